@@ -0,0 +1,211 @@
+use super::BitReader;
+
+use anyhow::{anyhow, ensure, Result};
+
+pub const SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// The AudioSpecificConfig, carried by the audio sequence header.
+#[allow(unused)]
+#[derive(Clone, Debug)]
+pub struct AacConfig {
+    pub object_type: u8,
+    pub sample_rate_index: u8,
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+impl AacConfig {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        let mut reader = BitReader::new(buf);
+        let mut object_type = reader.bits(5)?;
+        if object_type == 31 {
+            object_type = 32 + reader.bits(6)?;
+        }
+
+        ensure!(object_type > 0, "invalid aac object type");
+
+        let sample_rate_index = reader.bits(4)? as u8;
+        let sample_rate = if sample_rate_index == 15 {
+            let sample_rate = reader.bits(24)?;
+            ensure!(sample_rate > 0, "invalid aac sample rate");
+            sample_rate
+        } else {
+            *SAMPLE_RATES
+                .get(sample_rate_index as usize)
+                .ok_or_else(|| anyhow!("invalid aac sample rate index"))?
+        };
+
+        Ok(Self {
+            object_type: object_type as u8,
+            channels: reader.bits(4)? as u8,
+            sample_rate_index,
+            sample_rate,
+        })
+    }
+
+    /// Parse the header of an adts frame, returns the config and the size of
+    /// the header and of the whole frame.
+    pub fn parse_adts(buf: &[u8]) -> Result<(Self, usize, usize)> {
+        ensure!(buf.len() >= 7, "adts header is too short");
+        ensure!(
+            buf[0] == 0xff && buf[1] & 0xf0 == 0xf0,
+            "invalid adts sync word"
+        );
+
+        let header = if buf[1] & 0x01 == 1 { 7 } else { 9 };
+        let sample_rate_index = (buf[2] >> 2) & 0x0f;
+        let size =
+            ((buf[3] as usize & 0x03) << 11) | (buf[4] as usize) << 3 | (buf[5] as usize) >> 5;
+        ensure!(size >= header, "invalid adts frame size");
+
+        let config = Self {
+            object_type: (buf[2] >> 6) + 1,
+            sample_rate: *SAMPLE_RATES
+                .get(sample_rate_index as usize)
+                .ok_or_else(|| anyhow!("invalid aac sample rate index"))?,
+            channels: ((buf[2] & 0x01) << 2) | (buf[3] >> 6),
+            sample_rate_index,
+        };
+
+        Ok((config, header, size))
+    }
+
+    /// Write the audio specific config, two bytes unless the object type or
+    /// the sample rate are escaped. The object types from 32 follow the
+    /// escape value 31, which is not an object type itself.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut fields: Vec<(u64, usize)> = Vec::with_capacity(6);
+        if self.object_type >= 31 {
+            fields.extend([
+                (31, 5),
+                (self.object_type.saturating_sub(32) as u64 & 0x3f, 6),
+            ]);
+        } else {
+            fields.push((self.object_type as u64, 5));
+        }
+
+        if self.sample_rate_index == 15 {
+            fields.extend([(15, 4), (self.sample_rate as u64 & 0xffffff, 24)]);
+        } else {
+            fields.push((self.sample_rate_index as u64, 4));
+        }
+
+        // The frame length, core coder and extension flags of the
+        // GASpecificConfig are all zero.
+        fields.extend([(self.channels as u64 & 0x0f, 4), (0, 3)]);
+
+        let (value, width) = fields
+            .iter()
+            .fold((0u64, 0), |(value, width), (field, bits)| {
+                ((value << bits) | field, width + bits)
+            });
+        let bytes = width.div_ceil(8);
+        let value = value << (bytes * 8 - width);
+        (0..bytes)
+            .rev()
+            .map(|byte| (value >> (byte * 8)) as u8)
+            .collect()
+    }
+
+    /// Write the header of an adts frame holding `size` bytes of raw data.
+    /// The profile of adts only has two bits, sbr and ps are signaled as
+    /// aac-lc, their decoders find the extensions in the data. The other
+    /// object types, the sample rates outside of the table and the frames
+    /// over 8191 bytes can not be written.
+    pub fn adts_header(&self, size: usize) -> Result<[u8; 7]> {
+        let profile = match self.object_type {
+            1..=4 => self.object_type - 1,
+            5 | 29 => 1,
+            object_type => return Err(anyhow!("aac object type {} is not adts", object_type)),
+        };
+
+        let sample_rate_index = match self.sample_rate_index {
+            15 => SAMPLE_RATES
+                .iter()
+                .position(|sample_rate| *sample_rate == self.sample_rate)
+                .ok_or_else(|| anyhow!("aac sample rate {} is not adts", self.sample_rate))?
+                as u8,
+            sample_rate_index => sample_rate_index,
+        };
+
+        ensure!(
+            self.channels < 8,
+            "aac channels {} is not adts",
+            self.channels
+        );
+
+        let size = size + 7;
+        ensure!(size < 1 << 13, "aac frame of {} bytes is too large", size);
+        Ok([
+            0xff,
+            0xf1,
+            (profile << 6) | (sample_rate_index << 2) | (self.channels >> 2),
+            ((self.channels & 0x03) << 6) | (size >> 11) as u8,
+            (size >> 3) as u8,
+            ((size & 0x07) << 5) as u8 | 0x1f,
+            0xfc,
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(object_type: u8, sample_rate_index: u8, sample_rate: u32) -> AacConfig {
+        AacConfig {
+            object_type,
+            sample_rate_index,
+            sample_rate,
+            channels: 2,
+        }
+    }
+
+    #[test]
+    fn encodes_the_escaped_object_types_and_sample_rates() {
+        assert_eq!(config(2, 4, 44100).encode(), [0x12, 0x10]);
+
+        for (object_type, sample_rate_index, sample_rate) in [
+            (2, 15, 12345),
+            (42, 3, 48000),
+            (42, 15, 12345),
+            (32, 4, 44100),
+        ] {
+            let bytes = config(object_type, sample_rate_index, sample_rate).encode();
+            let parsed = AacConfig::parse(&bytes).unwrap();
+            assert_eq!(parsed.object_type, object_type);
+            assert_eq!(parsed.sample_rate_index, sample_rate_index);
+            assert_eq!(parsed.sample_rate, sample_rate);
+            assert_eq!(parsed.channels, 2);
+        }
+    }
+
+    #[test]
+    fn writes_adts_headers_that_parse_back() {
+        let header = config(2, 4, 44100).adts_header(100).unwrap();
+        let (parsed, header_size, size) = AacConfig::parse_adts(&header).unwrap();
+        assert_eq!(
+            (parsed.object_type, parsed.sample_rate, parsed.channels),
+            (2, 44100, 2)
+        );
+        assert_eq!((header_size, size), (7, 107));
+
+        // The sbr and ps streams are aac-lc to adts, an explicit sample rate
+        // of the table is written as its index.
+        for object_type in [5, 29] {
+            let header = config(object_type, 15, 24000).adts_header(8184).unwrap();
+            let (parsed, _, size) = AacConfig::parse_adts(&header).unwrap();
+            assert_eq!((parsed.object_type, parsed.sample_rate), (2, 24000));
+            assert_eq!(size, 8191);
+        }
+    }
+
+    #[test]
+    fn rejects_what_adts_can_not_carry() {
+        assert!(config(42, 4, 44100).adts_header(100).is_err());
+        assert!(config(2, 15, 12345).adts_header(100).is_err());
+        assert!(config(2, 4, 44100).adts_header(8185).is_err());
+    }
+}
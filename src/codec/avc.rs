@@ -0,0 +1,207 @@
+use super::{unescape, BitReader};
+
+use anyhow::{anyhow, ensure, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+pub const NALU_IDR: u8 = 5;
+pub const NALU_SPS: u8 = 7;
+pub const NALU_PPS: u8 = 8;
+pub const NALU_AUD: u8 = 9;
+
+/// The AVCDecoderConfigurationRecord, carried by the video sequence header.
+#[allow(unused)]
+#[derive(Clone, Debug)]
+pub struct AvcConfig {
+    pub profile: u8,
+    pub compatibility: u8,
+    pub level: u8,
+    pub nal_length_size: usize,
+    pub sps: Vec<Bytes>,
+    pub pps: Vec<Bytes>,
+}
+
+impl AvcConfig {
+    pub fn parse(mut buf: Bytes) -> Result<Self> {
+        ensure!(
+            buf.len() >= 7,
+            "avc decoder configuration record is too short"
+        );
+        ensure!(buf.get_u8() == 1, "unsupported avc configuration version");
+
+        let profile = buf.get_u8();
+        let compatibility = buf.get_u8();
+        let level = buf.get_u8();
+        let nal_length_size = (buf.get_u8() & 0x03) as usize + 1;
+
+        let mut sets = [Vec::new(), Vec::new()];
+        for (i, sets) in sets.iter_mut().enumerate() {
+            ensure!(
+                buf.has_remaining(),
+                "avc decoder configuration record is too short"
+            );
+            let count = buf.get_u8() & if i == 0 { 0x1f } else { 0xff };
+            for _ in 0..count {
+                ensure!(buf.remaining() >= 2, "avc parameter set is too short");
+                let size = buf.get_u16() as usize;
+                ensure!(buf.remaining() >= size, "avc parameter set is too short");
+                sets.push(buf.split_to(size));
+            }
+        }
+
+        let [sps, pps] = sets;
+        Ok(Self {
+            profile,
+            compatibility,
+            level,
+            nal_length_size,
+            sps,
+            pps,
+        })
+    }
+
+    /// Write a decoder configuration record holding a single sps and pps.
+    pub fn encode(sps: &[u8], pps: &[u8]) -> Bytes {
+        let mut buf = BytesMut::with_capacity(sps.len() + pps.len() + 11);
+        buf.put_u8(1);
+        buf.put_slice(&sps[1..4]);
+        buf.put_u8(0xff);
+        buf.put_u8(0xe1);
+        buf.put_u16(sps.len() as u16);
+        buf.put_slice(sps);
+        buf.put_u8(1);
+        buf.put_u16(pps.len() as u16);
+        buf.put_slice(pps);
+        buf.freeze()
+    }
+
+    /// The width and height of the picture, read from the first sps.
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        parse_sps_dimensions(self.sps.first()?).ok()
+    }
+}
+
+fn skip_scaling_list(reader: &mut BitReader, size: usize) -> Result<()> {
+    let (mut last, mut next) = (8, 8);
+    for _ in 0..size {
+        if next != 0 {
+            next = (last + reader.se()? + 256) % 256;
+        }
+
+        if next != 0 {
+            last = next;
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_sps_dimensions(sps: &[u8]) -> Result<(u32, u32)> {
+    let sps = unescape(sps);
+    let mut reader = BitReader::new(&sps);
+    reader.skip(8)?;
+
+    let profile = reader.bits(8)?;
+    reader.skip(16)?;
+    reader.ue()?;
+
+    let mut chroma_format = 1;
+    if matches!(
+        profile,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format = reader.ue()?;
+        if chroma_format == 3 {
+            reader.skip(1)?;
+        }
+
+        reader.ue()?;
+        reader.ue()?;
+        reader.skip(1)?;
+        if reader.bit()? == 1 {
+            for i in 0..if chroma_format == 3 { 12 } else { 8 } {
+                if reader.bit()? == 1 {
+                    skip_scaling_list(&mut reader, if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    reader.ue()?;
+    match reader.ue()? {
+        0 => {
+            reader.ue()?;
+        }
+        1 => {
+            reader.skip(1)?;
+            reader.se()?;
+            reader.se()?;
+            for _ in 0..reader.ue()? {
+                reader.se()?;
+            }
+        }
+        _ => (),
+    }
+
+    reader.ue()?;
+    reader.skip(1)?;
+
+    // The sizes come from the publisher, an overflow is an invalid sps.
+    let invalid = || anyhow!("invalid sps dimensions");
+    let width = reader
+        .ue()?
+        .checked_add(1)
+        .and_then(|width| width.checked_mul(16))
+        .ok_or_else(invalid)?;
+    let height = reader.ue()?.checked_add(1).ok_or_else(invalid)?;
+    let frame_mbs_only = reader.bit()?;
+    if frame_mbs_only == 0 {
+        reader.skip(1)?;
+    }
+
+    reader.skip(1)?;
+
+    let (mut crop_x, mut crop_y) = (0, 0);
+    if reader.bit()? == 1 {
+        let (left, right, top, bottom) = (reader.ue()?, reader.ue()?, reader.ue()?, reader.ue()?);
+        let unit_x = if matches!(chroma_format, 1 | 2) { 2 } else { 1 };
+        let unit_y = if chroma_format == 1 { 2 } else { 1 } * (2 - frame_mbs_only);
+        crop_x = left
+            .checked_add(right)
+            .and_then(|crop| crop.checked_mul(unit_x))
+            .ok_or_else(invalid)?;
+        crop_y = top
+            .checked_add(bottom)
+            .and_then(|crop| crop.checked_mul(unit_y))
+            .ok_or_else(invalid)?;
+    }
+
+    let height = height
+        .checked_mul(16 * (2 - frame_mbs_only))
+        .ok_or_else(invalid)?;
+    Ok((
+        width.checked_sub(crop_x).ok_or_else(invalid)?,
+        height.checked_sub(crop_y).ok_or_else(invalid)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sps_dimensions_of_a_stream() {
+        let sps = [
+            0x67, 0x42, 0xc0, 0x1e, 0xda, 0x02, 0x80, 0xbf, 0xe5, 0x84, 0x00, 0x00, 0x03, 0x00,
+            0x04, 0x00, 0x00, 0x03, 0x00, 0xca, 0x3c, 0x58, 0xba, 0x80,
+        ];
+
+        assert_eq!(parse_sps_dimensions(&sps).unwrap(), (640, 360));
+    }
+
+    #[test]
+    fn parse_sps_dimensions_rejects_a_crop_larger_than_the_frame() {
+        // A 16x16 baseline frame with 200 pixels cropped from the left.
+        let sps = [0x67, 0x42, 0x00, 0x1e, 0xdd, 0xf0, 0x32, 0xf8];
+        assert!(parse_sps_dimensions(&sps).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use super::{unescape, BitReader};
+
+use anyhow::{ensure, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+pub const NALU_VPS: u8 = 32;
+pub const NALU_SPS: u8 = 33;
+pub const NALU_PPS: u8 = 34;
+pub const NALU_AUD: u8 = 35;
+
+/// The HEVCDecoderConfigurationRecord, carried by the video sequence header.
+#[allow(unused)]
+#[derive(Clone, Debug)]
+pub struct HevcConfig {
+    pub profile_space: u8,
+    pub tier: u8,
+    pub profile: u8,
+    pub compatibility: u32,
+    pub constraints: [u8; 6],
+    pub level: u8,
+    pub nal_length_size: usize,
+    pub vps: Vec<Bytes>,
+    pub sps: Vec<Bytes>,
+    pub pps: Vec<Bytes>,
+}
+
+impl HevcConfig {
+    pub fn parse(mut buf: Bytes) -> Result<Self> {
+        ensure!(
+            buf.len() >= 23,
+            "hevc decoder configuration record is too short"
+        );
+        ensure!(buf.get_u8() == 1, "unsupported hevc configuration version");
+
+        let byte = buf.get_u8();
+        let compatibility = buf.get_u32();
+        let mut constraints = [0u8; 6];
+        buf.copy_to_slice(&mut constraints);
+        let level = buf.get_u8();
+        buf.advance(8);
+        let nal_length_size = (buf.get_u8() & 0x03) as usize + 1;
+
+        let (mut vps, mut sps, mut pps) = (Vec::new(), Vec::new(), Vec::new());
+        for _ in 0..buf.get_u8() {
+            ensure!(
+                buf.remaining() >= 3,
+                "hevc parameter set array is too short"
+            );
+            let kind = buf.get_u8() & 0x3f;
+            for _ in 0..buf.get_u16() {
+                ensure!(buf.remaining() >= 2, "hevc parameter set is too short");
+                let size = buf.get_u16() as usize;
+                ensure!(buf.remaining() >= size, "hevc parameter set is too short");
+                let nalu = buf.split_to(size);
+                match kind {
+                    NALU_VPS => vps.push(nalu),
+                    NALU_SPS => sps.push(nalu),
+                    NALU_PPS => pps.push(nalu),
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(Self {
+            profile_space: byte >> 6,
+            tier: (byte >> 5) & 1,
+            profile: byte & 0x1f,
+            compatibility,
+            constraints,
+            level,
+            nal_length_size,
+            vps,
+            sps,
+            pps,
+        })
+    }
+
+    /// Write a decoder configuration record holding a single vps, sps and
+    /// pps, the profile is read from the sps.
+    pub fn encode(vps: &[u8], sps: &[u8], pps: &[u8]) -> Result<Bytes> {
+        let unescaped = unescape(sps);
+        ensure!(unescaped.len() >= 15, "hevc sps is too short");
+
+        // The nal unit header is followed by the number of sub layers and the
+        // general profile, tier and level.
+        let max_sub_layers = (unescaped[2] >> 1) & 0x07;
+        let nesting = unescaped[2] & 0x01;
+        let profile = &unescaped[3..15];
+
+        let mut buf = BytesMut::with_capacity(vps.len() + sps.len() + pps.len() + 38);
+        buf.put_u8(1);
+        buf.put_slice(profile);
+        buf.put_u16(0xf000);
+        buf.put_u8(0xfc);
+        buf.put_u8(0xfd);
+        buf.put_u8(0xf8);
+        buf.put_u8(0xf8);
+        buf.put_u16(0);
+        buf.put_u8(((max_sub_layers + 1) << 3) | (nesting << 2) | 0x03);
+        buf.put_u8(3);
+        for (kind, nalu) in [(NALU_VPS, vps), (NALU_SPS, sps), (NALU_PPS, pps)] {
+            buf.put_u8(0x80 | kind);
+            buf.put_u16(1);
+            buf.put_u16(nalu.len() as u16);
+            buf.put_slice(nalu);
+        }
+
+        Ok(buf.freeze())
+    }
+
+    /// The width and height of the picture, read from the first sps.
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        parse_sps_dimensions(self.sps.first()?).ok()
+    }
+}
+
+fn parse_sps_dimensions(sps: &[u8]) -> Result<(u32, u32)> {
+    let sps = unescape(sps);
+    let mut reader = BitReader::new(&sps);
+    reader.skip(16 + 4)?;
+
+    let max_sub_layers = reader.bits(3)? as usize;
+    reader.skip(1)?;
+
+    // profile_tier_level, the general profile and level are followed by the
+    // presence flags of every sub layer.
+    reader.skip(96)?;
+    let mut present = Vec::with_capacity(max_sub_layers);
+    for _ in 0..max_sub_layers {
+        present.push((reader.bit()?, reader.bit()?));
+    }
+
+    if max_sub_layers > 0 {
+        reader.skip((8 - max_sub_layers) * 2)?;
+    }
+
+    for (profile, level) in present {
+        reader.skip(profile as usize * 88 + level as usize * 8)?;
+    }
+
+    reader.ue()?;
+    let chroma_format = reader.ue()?;
+    if chroma_format == 3 {
+        reader.skip(1)?;
+    }
+
+    let mut width = reader.ue()?;
+    let mut height = reader.ue()?;
+    if reader.bit()? == 1 {
+        let (left, right, top, bottom) = (reader.ue()?, reader.ue()?, reader.ue()?, reader.ue()?);
+        let unit_x = if matches!(chroma_format, 1 | 2) { 2 } else { 1 };
+        let unit_y = if chroma_format == 1 { 2 } else { 1 };
+        width -= unit_x * (left + right);
+        height -= unit_y * (top + bottom);
+    }
+
+    Ok((width, height))
+}
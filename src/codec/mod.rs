@@ -0,0 +1,140 @@
+pub mod aac;
+pub mod avc;
+pub mod hevc;
+
+use anyhow::{anyhow, Result};
+
+/// Remove the emulation prevention bytes of a nal unit, `00 00 03` becomes
+/// `00 00`.
+pub fn unescape(nalu: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(nalu.len());
+    let mut zeros = 0;
+    for byte in nalu {
+        if zeros >= 2 && *byte == 3 {
+            zeros = 0;
+            continue;
+        }
+
+        zeros = if *byte == 0 { zeros + 1 } else { 0 };
+        buf.push(*byte);
+    }
+
+    buf
+}
+
+/// Split an annex b byte stream into nal units, without the start codes.
+pub fn split_annexb(buf: &[u8]) -> Vec<&[u8]> {
+    let mut nalus = Vec::new();
+    let mut start = None;
+    let mut i = 0;
+
+    while i + 3 <= buf.len() {
+        if buf[i] == 0 && buf[i + 1] == 0 && buf[i + 2] == 1 {
+            if let Some(start) = start {
+                // A four byte start code leaves a zero at the end.
+                let mut end = i;
+                while end > start && buf[end - 1] == 0 {
+                    end -= 1;
+                }
+
+                nalus.push(&buf[start..end]);
+            }
+
+            i += 3;
+            start = Some(i);
+        } else {
+            i += 1;
+        }
+    }
+
+    if let Some(start) = start {
+        if start < buf.len() {
+            nalus.push(&buf[start..]);
+        }
+    }
+
+    nalus
+}
+
+/// Split the length prefixed nal units of an avc or hevc sample.
+pub fn split_length_prefixed(buf: &[u8], length_size: usize) -> Vec<&[u8]> {
+    let mut nalus = Vec::new();
+    let mut cursor = 0;
+
+    while cursor + length_size <= buf.len() {
+        let size = buf[cursor..cursor + length_size]
+            .iter()
+            .fold(0usize, |size, byte| (size << 8) | *byte as usize);
+        cursor += length_size;
+        if cursor + size > buf.len() {
+            break;
+        }
+
+        nalus.push(&buf[cursor..cursor + size]);
+        cursor += size;
+    }
+
+    nalus
+}
+
+/// Reads the bit fields of codec headers, including exp-golomb codes.
+pub struct BitReader<'a> {
+    buf: &'a [u8],
+    cursor: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, cursor: 0 }
+    }
+
+    pub fn bit(&mut self) -> Result<u32> {
+        let byte = self
+            .buf
+            .get(self.cursor / 8)
+            .ok_or_else(|| anyhow!("unexpected end of bits"))?;
+        let bit = (byte >> (7 - self.cursor % 8)) & 1;
+        self.cursor += 1;
+        Ok(bit as u32)
+    }
+
+    pub fn bits(&mut self, count: usize) -> Result<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.bit()?;
+        }
+
+        Ok(value)
+    }
+
+    pub fn skip(&mut self, count: usize) -> Result<()> {
+        for _ in 0..count {
+            self.bit()?;
+        }
+
+        Ok(())
+    }
+
+    /// Unsigned exp-golomb code.
+    pub fn ue(&mut self) -> Result<u32> {
+        let mut zeros = 0;
+        while self.bit()? == 0 {
+            zeros += 1;
+            if zeros > 31 {
+                return Err(anyhow!("invalid exp-golomb code"));
+            }
+        }
+
+        Ok((1 << zeros) - 1 + self.bits(zeros)?)
+    }
+
+    /// Signed exp-golomb code.
+    pub fn se(&mut self) -> Result<i32> {
+        let value = self.ue()? as i64;
+        Ok(if value % 2 == 1 {
+            ((value + 1) / 2) as i32
+        } else {
+            -(value / 2) as i32
+        })
+    }
+}
@@ -1,15 +1,94 @@
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
 
 use clap::Parser;
 use serde::Deserialize;
 use tokio_tungstenite::tungstenite::protocol::*;
 
+/// The timeouts and socket options of the connections of a tcp listener,
+/// the timeouts are in seconds and zero disables them.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Connection {
+    /// Close a connection that has not started publishing or playing this
+    /// many seconds after it was accepted, the handshakes included.
+    #[serde(default = "Connection::handshake_timeout")]
+    pub handshake_timeout: u64,
+
+    /// End a publish when nothing has been received for this many seconds.
+    #[serde(default = "Connection::idle_timeout")]
+    pub idle_timeout: u64,
+
+    /// Close a viewer when a write has not completed for this many seconds.
+    #[serde(default = "Connection::write_timeout")]
+    pub write_timeout: u64,
+
+    /// Disable nagle's algorithm on the sockets.
+    #[serde(default)]
+    pub nodelay: bool,
+
+    /// Send tcp keepalive probes after the connection has been idle for this
+    /// many seconds, disabled if not set.
+    #[serde(default)]
+    pub keepalive: Option<u64>,
+}
+
+impl Connection {
+    fn handshake_timeout() -> u64 {
+        10
+    }
+
+    fn idle_timeout() -> u64 {
+        30
+    }
+
+    fn write_timeout() -> u64 {
+        30
+    }
+
+    fn duration(secs: u64) -> Option<Duration> {
+        Some(Duration::from_secs(secs)).filter(|_| secs > 0)
+    }
+
+    pub fn handshake(&self) -> Option<Duration> {
+        Self::duration(self.handshake_timeout)
+    }
+
+    pub fn idle(&self) -> Option<Duration> {
+        Self::duration(self.idle_timeout)
+    }
+
+    pub fn write(&self) -> Option<Duration> {
+        Self::duration(self.write_timeout)
+    }
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Self::handshake_timeout(),
+            idle_timeout: Self::idle_timeout(),
+            write_timeout: Self::write_timeout(),
+            nodelay: false,
+            keepalive: None,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Rtmp {
     #[serde(default = "Rtmp::listen")]
     pub listen: SocketAddr,
+    /// Not read yet, the session always announces a window of 5000000.
+    #[allow(dead_code)]
     #[serde(default = "Rtmp::band_width")]
     pub band_width: usize,
+    #[serde(flatten)]
+    pub connection: Connection,
 }
 
 impl Rtmp {
@@ -22,11 +101,25 @@ impl Rtmp {
     }
 }
 
+/// The certificate chain and private key in pem files, they are reloaded
+/// when the files change.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct WebSocketFlv {
+    /// The listener of the `?name={app}&key={stream}` urls, it is not
+    /// started if the http flv server listens on the same address, which
+    /// serves the `/{app}/{stream}.flv` urls as websocket flv.
     #[serde(default = "WebSocketFlv::listen")]
     pub listen: SocketAddr,
 
+    /// Accept wss connections instead of ws if set.
+    pub tls: Option<Tls>,
+
     /// The size of the send queue. You can use it to turn on/off the
     /// backpressure features. None means here that the size of the queue is
     /// unlimited. The default value is the unlimited queue.
@@ -55,6 +148,9 @@ pub struct WebSocketFlv {
     /// 6455.
     #[serde(default)]
     pub accept_unmasked_frames: bool,
+
+    #[serde(flatten)]
+    pub connection: Connection,
 }
 
 impl WebSocketFlv {
@@ -74,15 +170,39 @@ impl WebSocketFlv {
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct HttpFlv {
+    /// The streams are played at `/{app}/{key}.flv`, `.mp4` and `.ts`, a
+    /// websocket upgrade request of `.flv` is served as websocket flv on the
+    /// same listener.
+    ///
+    /// The paths under `/_/` are reserved for the server, the number of
+    /// clients rejected by the access control is served at `/_/stats/access`.
     #[serde(default = "HttpFlv::listen")]
     pub listen: SocketAddr,
 
     /// Set the value of the Access-Control-Allow-Origin header.
     ///
     /// Access-Control-Allow-Origin is a header request that states whether the
-    /// response is shared with requesting code.
+    /// response is shared with requesting code. The header is only sent to
+    /// the pages allowed by the `origins` of the access rules of the stream,
+    /// and to this page only unless it is `*`.
     #[serde(default = "HttpFlv::allow_origin")]
     pub allow_origin: String,
+
+    /// Serve https instead of http if set, http/2 is negotiated with alpn.
+    pub tls: Option<Tls>,
+
+    /// The directory of the flv files served at `/_/vod/{path}`, usually the
+    /// directory of the recordings, vod is disabled if it is not set.
+    pub vod_root: Option<PathBuf>,
+
+    /// Who can read the stats, everyone unless it is set.
+    #[serde(default)]
+    pub stats: Rules,
+
+    /// The handshake timeout covers the tls handshake and the request
+    /// headers, the write timeout the bodies of the streams.
+    #[serde(flatten)]
+    pub connection: Connection,
 }
 
 impl HttpFlv {
@@ -95,29 +215,501 @@ impl HttpFlv {
     }
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct Srt {
+    #[serde(default = "Srt::listen")]
+    pub listen: SocketAddr,
+
+    /// The receive and send latency in milliseconds, the time packets are
+    /// buffered to recover the lost ones.
+    #[serde(default = "Srt::latency")]
+    pub latency: u64,
+
+    /// Encrypt the connections with this passphrase of 10 to 79 characters,
+    /// the peers must use the same one. Disabled if not set.
+    pub passphrase: Option<String>,
+
+    /// The size of the encryption key in bytes, 16, 24 or 32.
+    #[serde(default = "Srt::key_size")]
+    pub key_size: u16,
+}
+
+impl Srt {
+    fn listen() -> SocketAddr {
+        "127.0.0.1:10080".parse().unwrap()
+    }
+
+    fn latency() -> u64 {
+        120
+    }
+
+    fn key_size() -> u16 {
+        16
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Rtsp {
+    #[serde(default = "Rtsp::listen")]
+    pub listen: SocketAddr,
+
+    /// Accept streams pushed with ANNOUNCE and RECORD, for example by
+    /// cameras, only playing is allowed if not set.
+    #[serde(default)]
+    pub record: bool,
+}
+
+impl Rtsp {
+    fn listen() -> SocketAddr {
+        "127.0.0.1:8554".parse().unwrap()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Webrtc {
+    /// The http address of the whep and whip endpoints, `/whep/{name}` and
+    /// `/whip/{name}`.
+    #[serde(default = "Webrtc::listen")]
+    pub listen: SocketAddr,
+
+    /// The udp address the media of every session is sent on, it is the
+    /// only ice candidate of the server.
+    #[serde(default = "Webrtc::udp")]
+    pub udp: SocketAddr,
+
+    /// The ips advertised in the ice candidates instead of the local ones,
+    /// the ip of `udp` is used if not set and it is not unspecified.
+    #[serde(default)]
+    pub candidates: Vec<IpAddr>,
+}
+
+impl Webrtc {
+    fn listen() -> SocketAddr {
+        "127.0.0.1:8889".parse().unwrap()
+    }
+
+    fn udp() -> SocketAddr {
+        "127.0.0.1:8189".parse().unwrap()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Discontinuity {
+    /// Close the gap, the output continues as if nothing happened.
+    #[default]
+    Rebase,
+    /// Keep the gap of a forward jump, for example when the publisher was
+    /// paused. A backward jump is always rebased.
+    Preserve,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Timestamp {
+    /// The largest jump between two timestamps in milliseconds that is still
+    /// considered continuous, anything larger is a discontinuity.
+    #[serde(default = "Timestamp::max_jump")]
+    pub max_jump: u32,
+
+    /// How discontinuities are handled.
+    #[serde(default)]
+    pub discontinuity: Discontinuity,
+}
+
+impl Timestamp {
+    fn max_jump() -> u32 {
+        3000
+    }
+}
+
+impl Default for Timestamp {
+    fn default() -> Self {
+        Self {
+            max_jump: Self::max_jump(),
+            discontinuity: Discontinuity::default(),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Default)]
 pub struct Proto {
     pub rtmp: Option<Rtmp>,
     pub websocket_flv: Option<WebSocketFlv>,
     pub http_flv: Option<HttpFlv>,
+    pub srt: Option<Srt>,
+    pub rtsp: Option<Rtsp>,
+    pub webrtc: Option<Webrtc>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordMode {
+    /// Record every stream published to the app.
+    #[default]
+    Always,
+    /// Only record when the publisher asks for it, for rtmp that is the
+    /// `record` or `append` publish type.
+    Publish,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    #[default]
+    Flv,
+    Mp4,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Record {
+    #[serde(default)]
+    pub mode: RecordMode,
+
+    /// The container of the recorded files, `append` publishing is only
+    /// supported by flv.
+    #[serde(default)]
+    pub format: RecordFormat,
+
+    /// Write mp4 as fragments, the file stays playable if the process exits
+    /// before the recording is finished.
+    #[serde(default)]
+    pub fragmented: bool,
+
+    /// Move the index of a non fragmented mp4 file to the front of the file
+    /// when it is finished, so it can be played before being fully
+    /// downloaded.
+    #[serde(default)]
+    pub faststart: bool,
+
+    /// The path of the recorded files, `{app}`, `{name}` and `{date}` are
+    /// replaced with the app, the stream name and the start time of the file
+    /// in milliseconds. Streams whose app or name is not a plain file name
+    /// are not recorded.
+    #[serde(default = "Record::path")]
+    pub path: String,
+
+    /// Start a new file when the current one is longer than this many
+    /// seconds, zero means no limit.
+    #[serde(default)]
+    pub max_duration: u64,
+
+    /// Start a new file when the current one is larger than this many bytes,
+    /// zero means no limit.
+    #[serde(default)]
+    pub max_size: u64,
+}
+
+impl Record {
+    fn path() -> String {
+        "./records/{app}/{name}/{date}.flv".to_string()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Pull {
+    /// The rtmp, http flv or srt urls of the stream on the origins, the next
+    /// url is tried when an origin fails. `{stream}` is replaced with the name
+    /// of the requested stream.
+    pub urls: Vec<String>,
+
+    /// Stop pulling when the stream has had no subscribers for this many
+    /// seconds, the recorder counts as a subscriber and the push relays do
+    /// not.
+    #[serde(default = "Pull::idle_timeout")]
+    pub idle_timeout: u64,
+}
+
+impl Pull {
+    fn idle_timeout() -> u64 {
+        30
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct App {
+    /// Record the streams of the app to disk, disabled if not set.
+    pub record: Option<Record>,
+
+    /// Push the streams of the app to these rtmp urls, for example
+    /// `rtmp://live.example.com/app/{stream}`, where `{stream}` is replaced
+    /// with the name of the stream.
+    #[serde(default)]
+    pub push: Vec<String>,
+
+    /// Pull the stream of the app from an origin when the first viewer
+    /// arrives, disabled if not set.
+    pub pull: Option<Pull>,
+
+    /// Who can publish and play the streams of the app, it replaces the
+    /// global access rules.
+    pub access: Option<Access>,
+}
+
+/// A network of `address/prefix`, or a single address.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let prefix = self.prefix as u32;
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (addr, prefix) = value.split_once('/').unwrap_or((&value, ""));
+        let addr = IpAddr::from_str(addr)
+            .map_err(|e| format!("invalid network {}: {}", value, e))?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max,
+            prefix => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid network prefix: {}", value))?,
+        };
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Rules {
+    /// Only the clients of these networks are allowed, unless it is empty.
+    #[serde(default)]
+    pub allow: Vec<Cidr>,
+
+    /// The clients of these networks are rejected, even if they are allowed.
+    #[serde(default)]
+    pub deny: Vec<Cidr>,
+}
+
+impl Rules {
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr)))
+            && !self.deny.iter().any(|cidr| cidr.contains(addr))
+    }
+}
+
+/// The access rules of a protocol, the rules that are not set are those of
+/// the app.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ProtocolAccess {
+    pub publish: Option<Rules>,
+    pub play: Option<Rules>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Access {
+    /// Who can publish streams.
+    #[serde(default)]
+    pub publish: Rules,
+
+    /// Who can play streams.
+    #[serde(default)]
+    pub play: Rules,
+
+    /// The rules of a protocol replace `publish` and `play` for its clients,
+    /// by the name of the protocol, `rtmp`, `rtsp`, `srt`, `http`,
+    /// `websocket`, `whip` or `whep`.
+    #[serde(default)]
+    pub protocols: HashMap<String, ProtocolAccess>,
+
+    /// The maximum number of viewers of a stream, zero means no limit. The
+    /// recorder and the push relays count as viewers, and are refused like
+    /// them once the stream is full.
+    #[serde(default)]
+    pub max_viewers: usize,
+
+    /// The web pages that can play streams, matched with the host of the
+    /// `Origin` or `Referer` header of http flv requests and websocket
+    /// upgrades, `*.example.com` matches the subdomains of example.com. Any
+    /// page can play if it is empty. Requests without either header, which
+    /// players outside of browsers do not send, are not checked unless
+    /// `require_origin` is set.
+    #[serde(default)]
+    pub origins: Vec<String>,
+
+    /// Reject the http flv requests and websocket upgrades without an
+    /// `Origin` or `Referer` header.
+    #[serde(default)]
+    pub require_origin: bool,
+}
+
+impl Access {
+    /// The publish rules of the clients of the protocol.
+    pub fn publish_rules(&self, proto: &str) -> &Rules {
+        let rules = self.protocols.get(proto);
+        rules
+            .and_then(|rules| rules.publish.as_ref())
+            .unwrap_or(&self.publish)
+    }
+
+    /// The play rules of the clients of the protocol.
+    pub fn play_rules(&self, proto: &str) -> &Rules {
+        let rules = self.protocols.get(proto);
+        rules
+            .and_then(|rules| rules.play.as_ref())
+            .unwrap_or(&self.play)
+    }
+
+    /// Whether a page of the origin, or the url of a referer, can play.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        if self.origins.is_empty() {
+            return true;
+        }
+
+        // The host is between the scheme and the path, without the user
+        // info and the port.
+        let host = origin.split_once("://").map_or(origin, |(_, rest)| rest);
+        let host = host.split(['/', '?', '#']).next().unwrap_or_default();
+        let host = host.rsplit('@').next().unwrap_or_default();
+        let host = match host.strip_prefix('[') {
+            Some(host) => host.split(']').next().unwrap_or_default(),
+            None => host.split(':').next().unwrap_or_default(),
+        }
+        .to_ascii_lowercase();
+
+        self.origins.iter().any(|pattern| {
+            let pattern = pattern.to_ascii_lowercase();
+            match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => host == pattern,
+            }
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Limits {
+    /// The maximum number of connections of the rtmp, rtsp, srt, http flv
+    /// and websocket flv listeners together, zero means no limit.
+    #[serde(default)]
+    pub max_connections: usize,
+
+    /// The maximum number of connections of a single ip address, zero means
+    /// no limit.
+    #[serde(default)]
+    pub max_connections_per_ip: usize,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Vhost {
+    /// The apps of the virtual host, they replace the global apps of the same
+    /// name.
+    #[serde(default)]
+    pub apps: HashMap<String, App>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FileSource {
+    /// The name the stream is published under, `app[/stream]`.
+    pub name: String,
+
+    /// The flv files played one after another.
+    pub files: Vec<PathBuf>,
+
+    /// Start again at the first file after the last one has been played.
+    #[serde(default, rename = "loop")]
+    pub looped: bool,
+}
+
+impl FromStr for FileSource {
+    type Err = String;
+
+    /// Parse `name=file[,file...]`, as given on the command line.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, files) = value
+            .split_once('=')
+            .ok_or_else(|| "expected name=file[,file...]".to_string())?;
+
+        Ok(Self {
+            name: name.to_string(),
+            files: files.split(',').map(PathBuf::from).collect(),
+            looped: false,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PullSource {
+    /// The name the stream is published under, `app[/stream]`.
+    pub name: String,
+
+    /// The rtmp, http flv or srt urls the stream is pulled from, the next url
+    /// is tried when an origin fails.
+    pub urls: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UdpSource {
+    /// The name the stream is published under, `app[/stream]`.
+    pub name: String,
+
+    /// The address the mpeg-ts packets are received on, plain or in rtp, a
+    /// multicast address joins the group.
+    pub listen: SocketAddr,
+
+    /// The local interface the multicast group is joined on, any interface
+    /// by default.
+    #[serde(default = "UdpSource::interface")]
+    pub interface: Ipv4Addr,
+}
+
+impl UdpSource {
+    fn interface() -> Ipv4Addr {
+        Ipv4Addr::UNSPECIFIED
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Sources {
+    /// Publish local flv files as live streams.
+    #[serde(default)]
+    pub file: Vec<FileSource>,
+
+    /// Pull streams from origins for as long as the process runs.
+    #[serde(default)]
+    pub pull: Vec<PullSource>,
+
+    /// Receive mpeg-ts streams over udp, for example from broadcast
+    /// encoders.
+    #[serde(default)]
+    pub udp: Vec<UdpSource>,
+}
+
+#[derive(Deserialize, Debug, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum LogLevel {
     Error,
     Warn,
+    #[default]
     Info,
     Debug,
     Trace,
 }
 
-impl Default for LogLevel {
-    fn default() -> Self {
-        Self::Info
-    }
-}
-
 impl LogLevel {
     pub fn as_level(&self) -> log::Level {
         match *self {
@@ -144,7 +736,25 @@ pub struct Config {
     #[serde(default)]
     pub proto: Proto,
     #[serde(default)]
+    pub timestamp: Timestamp,
+    #[serde(default)]
+    pub apps: HashMap<String, App>,
+    /// The virtual hosts, the streams are separated by the host name the
+    /// clients connect to.
+    #[serde(default)]
+    pub vhosts: HashMap<String, Vhost>,
+    #[serde(default)]
+    pub sources: Sources,
+    /// The access rules are reloaded when the configuration file changes.
+    #[serde(default)]
+    pub access: Access,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
     pub log: Log,
+    /// The path of the configuration file, if one was given.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
 }
 
 #[derive(Parser)]
@@ -157,6 +767,14 @@ struct Cli {
     /// specify the configuration file path.
     #[arg(long)]
     config: Option<String>,
+
+    /// publish flv files as a live stream, can be repeated.
+    #[arg(long, value_name = "NAME=FILE[,FILE...]")]
+    file: Vec<FileSource>,
+
+    /// play the files given with `--file` in a loop.
+    #[arg(long = "loop")]
+    looped: bool,
 }
 
 impl Config {
@@ -164,12 +782,44 @@ impl Config {
     /// specified, the configuration is read from the configuration file,
     /// otherwise the default configuration is used.
     pub fn load() -> Self {
-        toml::from_str(
-            &Cli::parse()
-                .config
+        let cli = Cli::parse();
+        let mut cfg: Self = toml::from_str(
+            &cli.config
+                .as_ref()
                 .and_then(|path| std::fs::read_to_string(path).ok())
                 .unwrap_or("".to_string()),
         )
-        .unwrap()
+        .unwrap();
+
+        cfg.path = cli.config.map(PathBuf::from);
+        cfg.sources
+            .file
+            .extend(cli.file.into_iter().map(|source| FileSource {
+                looped: cli.looped,
+                ..source
+            }));
+
+        cfg
+    }
+
+    /// Read the configuration file again, the command line is not applied.
+    pub fn reload(&self) -> anyhow::Result<Option<Self>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let mut cfg: Self = toml::from_str(&std::fs::read_to_string(path)?)?;
+        cfg.path = Some(path.clone());
+        Ok(Some(cfg))
+    }
+
+    /// The configuration of an app, the apps of the virtual host come before
+    /// the global ones.
+    pub fn app(&self, vhost: &str, app: &str) -> Option<&App> {
+        self.vhosts
+            .get(vhost)
+            .and_then(|vhost| vhost.apps.get(app))
+            .or_else(|| self.apps.get(app))
     }
 }
@@ -1,4 +1,7 @@
-use bytes::{BufMut, BytesMut};
+use crate::codec::{aac::AacConfig, avc, avc::AvcConfig, hevc, hevc::HevcConfig};
+
+use anyhow::{anyhow, ensure, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 #[repr(u8)]
 #[allow(unused)]
@@ -10,6 +13,16 @@ pub enum FlvHeader {
 }
 
 impl FlvHeader {
+    /// Parse the type flags of the header, a file that declares neither audio
+    /// nor video is treated as containing both.
+    pub fn from_flags(flags: u8) -> Self {
+        match flags & 0x05 {
+            0x01 => Self::Video,
+            0x04 => Self::Audio,
+            _ => Self::Full,
+        }
+    }
+
     pub fn encode(&self, buf: &mut BytesMut) -> usize {
         buf.put_u8(0x46); // F
         buf.put_u8(0x4c); // L
@@ -31,11 +44,43 @@ pub enum FlvFrame {
     Script = 0x12,
 }
 
+impl TryFrom<u8> for FlvFrame {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0x08 => Self::Audio,
+            0x09 => Self::Video,
+            0x12 => Self::Script,
+            _ => return Err(anyhow!("unknown flv tag type: {}", value)),
+        })
+    }
+}
+
 impl FlvFrame {
+    /// Whether the tag data is a video keyframe.
+    pub fn is_keyframe(&self, data: &[u8]) -> bool {
+        *self == Self::Video && data.first().map(|b| b >> 4 == 1).unwrap_or(false)
+    }
+
+    /// Whether the tag data is a codec configuration, the AVC/HEVC decoder
+    /// configuration record or the AAC audio specific config.
+    pub fn is_sequence_header(&self, data: &[u8]) -> bool {
+        match self {
+            Self::Video => data.len() > 1 && matches!(data[0] & 0x0f, 7 | 12) && data[1] == 0,
+            Self::Audio => data.len() > 1 && data[0] >> 4 == 10 && data[1] == 0,
+            Self::Script => false,
+        }
+    }
+
+    /// Write a tag, the 32 bit timestamp is split into the lower 24 bits
+    /// followed by the extended byte holding the upper 8 bits, and the stream
+    /// id is always zero.
     pub fn encode(&self, src: &[u8], dst: &mut BytesMut, timestamp: u32) -> usize {
         dst.put_u8(*self as u8);
         dst.put_uint(src.len() as u64, 3);
-        dst.put_u32(timestamp_xor(timestamp));
+        dst.put_uint((timestamp & 0xffffff) as u64, 3);
+        dst.put_u8((timestamp >> 24) as u8);
         dst.put_uint(0, 3);
         dst.put(src);
         dst.put_u32((src.len() + 11) as u32);
@@ -44,20 +89,13 @@ impl FlvFrame {
     }
 }
 
-pub fn timestamp_xor(timestamp: u32) -> u32 {
-    u32::from_be_bytes([
-        ((timestamp >> 16) & 0xff) as u8,
-        ((timestamp >> 8) & 0xff) as u8,
-        (timestamp & 0xff) as u8,
-        ((timestamp >> 24) & 0xff) as u8,
-    ])
-}
-
+/// Writes flv tags into an internal buffer.
+///
+/// The timestamps are written as they are, use `Timestamper` to produce
+/// timestamps that are valid for a flv stream.
 pub struct FlvEncoer {
     header: FlvHeader,
     header_state: bool,
-    timestamp_cursor: u32,
-    timestamp: u32,
     bytes: BytesMut,
 }
 
@@ -66,28 +104,23 @@ impl FlvEncoer {
         Self {
             bytes: BytesMut::with_capacity(5000),
             header_state: false,
-            timestamp_cursor: 0,
-            timestamp: 0,
             header,
         }
     }
 
-    pub fn encode(&mut self, frame: FlvFrame, timestamp: u32, src: &[u8]) -> usize {
-        let mut size = 0;
-
-        self.timestamp += if !(self.timestamp_cursor > timestamp || self.timestamp_cursor == 0) {
-            timestamp - self.timestamp_cursor
-        } else {
-            0
-        };
-
-        if !self.header_state {
-            size += self.header.encode(&mut self.bytes);
-            self.header_state = true;
+    /// Write the flv header if it has not been written yet, this is done
+    /// automatically by the first `encode`.
+    pub fn encode_header(&mut self) -> usize {
+        if self.header_state {
+            return 0;
         }
 
-        self.timestamp_cursor = timestamp;
-        size + frame.encode(src, &mut self.bytes, self.timestamp)
+        self.header_state = true;
+        self.header.encode(&mut self.bytes)
+    }
+
+    pub fn encode(&mut self, frame: FlvFrame, timestamp: u32, src: &[u8]) -> usize {
+        self.encode_header() + frame.encode(src, &mut self.bytes, timestamp)
     }
 
     pub fn flush_to(&mut self) -> Vec<u8> {
@@ -96,3 +129,555 @@ impl FlvEncoer {
         bytes
     }
 }
+
+pub const VIDEO_CODEC_AVC: u8 = 7;
+pub const VIDEO_CODEC_HEVC: u8 = 12;
+pub const AUDIO_FORMAT_AAC: u8 = 10;
+/// The audio format of enhanced rtmp, the codec is a fourcc following the
+/// first byte.
+pub const AUDIO_FORMAT_EX: u8 = 9;
+pub const FOURCC_OPUS: [u8; 4] = *b"Opus";
+
+/// The body of an AVC or HEVC video tag.
+#[derive(Clone, Debug)]
+pub struct FlvVideo {
+    pub codec: u8,
+    pub keyframe: bool,
+    /// The data is the decoder configuration record instead of nal units.
+    pub header: bool,
+    /// Composition time offset in milliseconds.
+    pub cts: i32,
+    /// The length prefixed nal units, or the decoder configuration record.
+    pub data: Bytes,
+}
+
+impl FlvVideo {
+    pub fn parse(data: &Bytes) -> Option<Self> {
+        if data.len() < 5 {
+            return None;
+        }
+
+        let codec = data[0] & 0x0f;
+        if codec != VIDEO_CODEC_AVC && codec != VIDEO_CODEC_HEVC {
+            return None;
+        }
+
+        // A packet type of 2 is the end of sequence, which has no data.
+        if data[1] > 1 {
+            return None;
+        }
+
+        Some(Self {
+            cts: (i32::from_be_bytes([data[2], data[3], data[4], 0])) >> 8,
+            keyframe: data[0] >> 4 == 1,
+            header: data[1] == 0,
+            data: data.slice(5..),
+            codec,
+        })
+    }
+}
+
+/// The body of an AAC audio tag.
+#[derive(Clone, Debug)]
+pub struct FlvAudio {
+    /// The data is the audio specific config instead of a raw frame.
+    pub header: bool,
+    pub data: Bytes,
+}
+
+impl FlvAudio {
+    pub fn parse(data: &Bytes) -> Option<Self> {
+        if data.len() < 2 || data[0] >> 4 != AUDIO_FORMAT_AAC {
+            return None;
+        }
+
+        Some(Self {
+            header: data[1] == 0,
+            data: data.slice(2..),
+        })
+    }
+}
+
+/// The body of an Opus audio tag of enhanced rtmp.
+#[derive(Clone, Debug)]
+pub struct FlvOpus {
+    /// The data is the opus identification header instead of a packet.
+    pub header: bool,
+    pub data: Bytes,
+}
+
+impl FlvOpus {
+    pub fn parse(data: &Bytes) -> Option<Self> {
+        if data.len() < 5 || data[0] >> 4 != AUDIO_FORMAT_EX || data[1..5] != FOURCC_OPUS {
+            return None;
+        }
+
+        // The packet type is the low nibble, 0 is the sequence start.
+        Some(Self {
+            header: data[0] & 0x0f == 0,
+            data: data.slice(5..),
+        })
+    }
+}
+
+/// Builds the video tags of an avc or hevc stream from its nal units, the
+/// sequence header is built from the parameter sets found in the stream,
+/// and sent again whenever they change.
+pub struct FlvVideoBuilder {
+    codec: u8,
+    vps: Option<Bytes>,
+    sps: Option<Bytes>,
+    pps: Option<Bytes>,
+    header: Option<Bytes>,
+}
+
+impl FlvVideoBuilder {
+    pub fn new(codec: u8) -> Self {
+        Self {
+            vps: None,
+            sps: None,
+            pps: None,
+            header: None,
+            codec,
+        }
+    }
+
+    pub fn codec(&self) -> u8 {
+        self.codec
+    }
+
+    /// Build the tags of an access unit, `cts` is the offset of the
+    /// presentation time in milliseconds. The frames before the first
+    /// sequence header are dropped, they can not be decoded.
+    pub fn build<'a>(
+        &mut self,
+        nalus: impl IntoIterator<Item = &'a [u8]>,
+        timestamp: u32,
+        cts: i32,
+    ) -> Result<Vec<FlvTag>> {
+        let mut tags = Vec::with_capacity(2);
+        let mut keyframe = false;
+        let mut body = BytesMut::new();
+
+        for nalu in nalus {
+            if nalu.is_empty() {
+                continue;
+            }
+
+            let set = Some(Bytes::copy_from_slice(nalu));
+            if self.codec == VIDEO_CODEC_AVC {
+                match nalu[0] & 0x1f {
+                    avc::NALU_SPS => self.sps = set,
+                    avc::NALU_PPS => self.pps = set,
+                    avc::NALU_AUD => (),
+                    kind => {
+                        keyframe |= kind == avc::NALU_IDR;
+                        body.put_u32(nalu.len() as u32);
+                        body.put_slice(nalu);
+                    }
+                }
+            } else {
+                match (nalu[0] >> 1) & 0x3f {
+                    hevc::NALU_VPS => self.vps = set,
+                    hevc::NALU_SPS => self.sps = set,
+                    hevc::NALU_PPS => self.pps = set,
+                    hevc::NALU_AUD => (),
+                    kind => {
+                        // The irap pictures, bla, idr and cra.
+                        keyframe |= (16..=21).contains(&kind);
+                        body.put_u32(nalu.len() as u32);
+                        body.put_slice(nalu);
+                    }
+                }
+            }
+        }
+
+        let header = match (self.codec, &self.vps, &self.sps, &self.pps) {
+            (VIDEO_CODEC_AVC, _, Some(sps), Some(pps)) if sps.len() >= 4 => {
+                Some(AvcConfig::encode(sps, pps))
+            }
+            (VIDEO_CODEC_HEVC, Some(vps), Some(sps), Some(pps)) => {
+                Some(HevcConfig::encode(vps, sps, pps)?)
+            }
+            _ => None,
+        };
+
+        if let Some(config) = header.filter(|config| Some(config) != self.header.as_ref()) {
+            let mut data = BytesMut::with_capacity(config.len() + 5);
+            data.put_slice(&[0x10 | self.codec, 0, 0, 0, 0]);
+            data.put_slice(&config);
+
+            self.header = Some(config);
+            tags.push(FlvTag {
+                frame: FlvFrame::Video,
+                data: data.freeze(),
+                timestamp,
+            });
+        }
+
+        if self.header.is_none() || body.is_empty() {
+            return Ok(tags);
+        }
+
+        let mut data = BytesMut::with_capacity(body.len() + 5);
+        data.put_u8(if keyframe { 0x10 } else { 0x20 } | self.codec);
+        data.put_u8(1);
+        data.put_slice(&cts.to_be_bytes()[1..]);
+        data.put_slice(&body);
+
+        tags.push(FlvTag {
+            frame: FlvFrame::Video,
+            data: data.freeze(),
+            timestamp,
+        });
+
+        Ok(tags)
+    }
+}
+
+/// Builds the audio tags of an aac stream from its raw frames, the sequence
+/// header is sent again whenever the config changes.
+#[derive(Default)]
+pub struct FlvAudioBuilder {
+    header: Option<Vec<u8>>,
+}
+
+impl FlvAudioBuilder {
+    pub fn build(&mut self, config: &AacConfig, timestamp: u32, frame: &[u8]) -> Vec<FlvTag> {
+        let mut tags = Vec::with_capacity(2);
+        let specific = config.encode();
+        if self.header.as_ref() != Some(&specific) {
+            tags.push(FlvTag {
+                frame: FlvFrame::Audio,
+                data: Bytes::from([&[0xaf, 0][..], &specific].concat()),
+                timestamp,
+            });
+            self.header = Some(specific);
+        }
+
+        let mut data = BytesMut::with_capacity(frame.len() + 2);
+        data.put_slice(&[0xaf, 1]);
+        data.put_slice(frame);
+        tags.push(FlvTag {
+            frame: FlvFrame::Audio,
+            data: data.freeze(),
+            timestamp,
+        });
+
+        tags
+    }
+}
+
+/// The identification header of a 48 kHz stereo opus stream, RFC 7845.
+const OPUS_HEAD: [u8; 19] = [
+    b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', 1, 2, 0x38, 0x01, 0x80, 0xbb, 0, 0, 0, 0, 0,
+];
+
+/// Builds the enhanced rtmp audio tags of an opus stream, the sequence
+/// start comes before the first packet.
+#[derive(Default)]
+pub struct FlvOpusBuilder {
+    started: bool,
+}
+
+impl FlvOpusBuilder {
+    pub fn build(&mut self, timestamp: u32, packet: &[u8]) -> Vec<FlvTag> {
+        let mut tags = Vec::with_capacity(2);
+        let tag = |kind: u8, data: &[u8]| {
+            let mut buf = BytesMut::with_capacity(data.len() + 5);
+            buf.put_u8(AUDIO_FORMAT_EX << 4 | kind);
+            buf.put_slice(&FOURCC_OPUS);
+            buf.put_slice(data);
+            FlvTag {
+                frame: FlvFrame::Audio,
+                data: buf.freeze(),
+                timestamp,
+            }
+        };
+
+        if !self.started {
+            self.started = true;
+            tags.push(tag(0, &OPUS_HEAD));
+        }
+
+        tags.push(tag(1, packet));
+        tags
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FlvTag {
+    pub frame: FlvFrame,
+    pub timestamp: u32,
+    pub data: Bytes,
+}
+
+/// An incremental flv demuxer, the input can be split at any position. The
+/// input is copied into an internal buffer, and the tag data returned shares
+/// that buffer.
+pub struct FlvDecoder {
+    bytes: BytesMut,
+    header: Option<FlvHeader>,
+    consumed: u64,
+}
+
+impl Default for FlvDecoder {
+    fn default() -> Self {
+        Self {
+            bytes: BytesMut::with_capacity(5000),
+            header: None,
+            consumed: 0,
+        }
+    }
+}
+
+impl FlvDecoder {
+    /// The flv header, available after the header has been decoded.
+    #[allow(unused)]
+    pub fn header(&self) -> Option<FlvHeader> {
+        self.header
+    }
+
+    /// The number of bytes decoded so far, a tag ends at this position of
+    /// the stream when it is returned.
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    pub fn extend(&mut self, chunk: &[u8]) {
+        self.bytes.extend_from_slice(chunk);
+    }
+
+    /// Decode the next tag, returns `None` when more input is needed.
+    ///
+    /// Tags of unknown types are skipped, any error means the stream is
+    /// corrupted and the decoder should not be used anymore.
+    pub fn decode(&mut self) -> Result<Option<FlvTag>> {
+        if self.header.is_none() && !self.decode_header()? {
+            return Ok(None);
+        }
+
+        loop {
+            if self.bytes.len() < 11 {
+                return Ok(None);
+            }
+
+            let kind = self.bytes[0];
+            let size =
+                u32::from_be_bytes([0, self.bytes[1], self.bytes[2], self.bytes[3]]) as usize;
+            if self.bytes.len() < 15 + size {
+                return Ok(None);
+            }
+
+            ensure!(kind & 0x20 == 0, "encrypted flv tags are not supported");
+
+            // The timestamp is 24 bits, followed by an extended byte holding
+            // the upper 8 bits.
+            let timestamp =
+                u32::from_be_bytes([self.bytes[7], self.bytes[4], self.bytes[5], self.bytes[6]]);
+
+            let previous_tag_size = u32::from_be_bytes([
+                self.bytes[11 + size],
+                self.bytes[12 + size],
+                self.bytes[13 + size],
+                self.bytes[14 + size],
+            ]);
+
+            ensure!(
+                previous_tag_size as usize == size + 11,
+                "invalid previous tag size: {}, expected: {}",
+                previous_tag_size,
+                size + 11
+            );
+
+            self.bytes.advance(11);
+            let data = self.bytes.split_to(size).freeze();
+            self.bytes.advance(4);
+            self.consumed += 15 + size as u64;
+
+            if let Ok(frame) = FlvFrame::try_from(kind & 0x1f) {
+                return Ok(Some(FlvTag {
+                    frame,
+                    timestamp,
+                    data,
+                }));
+            }
+        }
+    }
+
+    fn decode_header(&mut self) -> Result<bool> {
+        if self.bytes.len() < 9 {
+            return Ok(false);
+        }
+
+        ensure!(&self.bytes[..3] == b"FLV", "invalid flv signature");
+        ensure!(
+            self.bytes[3] == 1,
+            "unsupported flv version: {}",
+            self.bytes[3]
+        );
+
+        let offset =
+            u32::from_be_bytes([self.bytes[5], self.bytes[6], self.bytes[7], self.bytes[8]])
+                as usize;
+
+        ensure!(offset >= 9, "invalid flv header size: {}", offset);
+        if self.bytes.len() < offset + 4 {
+            return Ok(false);
+        }
+
+        let header = FlvHeader::from_flags(self.bytes[4]);
+        self.bytes.advance(offset);
+        ensure!(self.bytes.get_u32() == 0, "invalid first previous tag size");
+        self.header = Some(header);
+        self.consumed = offset as u64 + 4;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A xorshift generator, the tests are reproducible without a dependency
+    /// on a random crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// Random tags, the timestamps use the whole 32 bits so that the extended
+    /// byte is covered.
+    fn tags(rng: &mut Rng, count: usize) -> Vec<FlvTag> {
+        let frames = [FlvFrame::Audio, FlvFrame::Video, FlvFrame::Script];
+        (0..count)
+            .map(|_| {
+                let size = [0, 1, 10, 300, 70000][rng.below(5)];
+                FlvTag {
+                    frame: frames[rng.below(frames.len())],
+                    timestamp: match rng.below(3) {
+                        0 => rng.next() as u32 & 0xffffff,
+                        1 => 0xffffff + rng.below(3) as u32,
+                        _ => rng.next() as u32,
+                    },
+                    data: (0..size).map(|_| rng.next() as u8).collect(),
+                }
+            })
+            .collect()
+    }
+
+    fn encode(tags: &[FlvTag]) -> Vec<u8> {
+        let mut encoder = FlvEncoer::new(FlvHeader::Full);
+        encoder.encode_header();
+        for tag in tags {
+            encoder.encode(tag.frame, tag.timestamp, &tag.data);
+        }
+
+        encoder.flush_to()
+    }
+
+    fn assert_tags(decoded: &[FlvTag], tags: &[FlvTag]) {
+        assert_eq!(decoded.len(), tags.len());
+        for (decoded, tag) in decoded.iter().zip(tags) {
+            assert_eq!(decoded.frame, tag.frame);
+            assert_eq!(decoded.timestamp, tag.timestamp);
+            assert_eq!(decoded.data, tag.data);
+        }
+    }
+
+    #[test]
+    fn decode_round_trips_tags_split_at_any_position() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for _ in 0..200 {
+            let count = 1 + rng.below(20);
+            let tags = tags(&mut rng, count);
+            let bytes = encode(&tags);
+
+            let mut decoder = FlvDecoder::default();
+            let mut decoded = Vec::new();
+            let mut rest = &bytes[..];
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(1 + rng.below(rest.len().min(1000)));
+                rest = tail;
+
+                decoder.extend(chunk);
+                while let Some(tag) = decoder.decode().unwrap() {
+                    decoded.push(tag);
+                }
+            }
+
+            assert_tags(&decoded, &tags);
+            assert_eq!(decoder.consumed(), bytes.len() as u64);
+            assert_eq!(decoder.header(), Some(FlvHeader::Full));
+        }
+    }
+
+    #[test]
+    fn decode_round_trips_tags_fed_byte_by_byte() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        let tags: Vec<_> = tags(&mut rng, 50)
+            .into_iter()
+            .filter(|tag| tag.data.len() < 1000)
+            .collect();
+
+        let mut decoder = FlvDecoder::default();
+        let mut decoded = Vec::new();
+        for byte in encode(&tags) {
+            decoder.extend(&[byte]);
+            while let Some(tag) = decoder.decode().unwrap() {
+                decoded.push(tag);
+            }
+        }
+
+        assert_tags(&decoded, &tags);
+    }
+
+    #[test]
+    fn decode_reads_the_extended_timestamp() {
+        let mut encoder = FlvEncoer::new(FlvHeader::Video);
+        encoder.encode(FlvFrame::Video, 0x12345678, &[0x17, 1]);
+        let bytes = encoder.flush_to();
+
+        // The lower 24 bits, then the upper 8 bits.
+        assert_eq!(&bytes[13 + 4..13 + 8], &[0x34, 0x56, 0x78, 0x12]);
+
+        let mut decoder = FlvDecoder::default();
+        decoder.extend(&bytes);
+        assert_eq!(decoder.decode().unwrap().unwrap().timestamp, 0x12345678);
+        assert!(decoder.decode().unwrap().is_none());
+    }
+
+    #[test]
+    fn decode_skips_unknown_tags_and_rejects_bad_sizes() {
+        let mut bytes = encode(&[]);
+        let mut unknown = BytesMut::new();
+        FlvFrame::Audio.encode(&[1, 2, 3], &mut unknown, 0);
+        unknown[0] = 0x0f;
+        bytes.extend_from_slice(&unknown);
+        let mut tag = BytesMut::new();
+        FlvFrame::Video.encode(&[4, 5], &mut tag, 40);
+        bytes.extend_from_slice(&tag);
+
+        let mut decoder = FlvDecoder::default();
+        decoder.extend(&bytes);
+        let decoded = decoder.decode().unwrap().unwrap();
+        assert_eq!((decoded.frame, decoded.timestamp), (FlvFrame::Video, 40));
+
+        let len = tag.len();
+        tag[len - 1] ^= 1;
+        let mut decoder = FlvDecoder::default();
+        decoder.extend(&encode(&[]));
+        decoder.extend(&tag);
+        assert!(decoder.decode().is_err());
+    }
+}
@@ -1,16 +1,30 @@
+mod codec;
 mod config;
 mod flv;
+mod mp4;
 mod proto;
+mod record;
+mod relay;
 mod router;
+mod rtp;
 mod server;
+mod source;
+mod timestamp;
+mod ts;
 
 use config::Config;
+use router::Router;
 use std::{future::pending, sync::Arc};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cfg = Arc::new(Config::load());
     simple_logger::init_with_level(cfg.log.level.as_level())?;
-    server::run(cfg);
+    let vhosts = cfg.vhosts.keys().cloned();
+    let router = Arc::new(Router::new(cfg.timestamp.clone(), vhosts));
+    record::run(cfg.clone(), router.clone());
+    relay::run(cfg.clone(), router.clone());
+    source::run(cfg.clone(), router.clone());
+    server::run(cfg, router);
     pending().await
 }
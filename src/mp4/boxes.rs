@@ -0,0 +1,344 @@
+use super::{Codec, Sample, Track, TIMESCALE};
+
+use bytes::{BufMut, BytesMut};
+
+const MATRIX: [u32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
+
+fn write_box(buf: &mut BytesMut, kind: &[u8; 4], body: impl FnOnce(&mut BytesMut)) {
+    let start = buf.len();
+    buf.put_u32(0);
+    buf.put_slice(kind);
+    body(buf);
+
+    let size = (buf.len() - start) as u32;
+    buf[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(
+    buf: &mut BytesMut,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut BytesMut),
+) {
+    write_box(buf, kind, |buf| {
+        buf.put_u32((version as u32) << 24 | flags);
+        body(buf);
+    });
+}
+
+pub fn ftyp(buf: &mut BytesMut, fragmented: bool) {
+    write_box(buf, b"ftyp", |buf| {
+        buf.put_slice(if fragmented { b"iso5" } else { b"isom" });
+        buf.put_u32(512);
+        for brand in [b"isom", b"iso2", b"iso5", b"avc1", b"mp41"] {
+            buf.put_slice(brand);
+        }
+    });
+}
+
+/// The sample table of a track in a progressive file.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub duration: u32,
+    pub cts: i32,
+    pub size: u32,
+    pub sync: bool,
+    pub offset: u64,
+}
+
+/// Write the movie box, a fragmented file has no samples in the movie box,
+/// the samples of a progressive file are described by the entries of each
+/// track, and the chunk offsets are moved by `shift` bytes.
+pub fn moov(buf: &mut BytesMut, tracks: &[(&Track, &[Entry])], fragmented: bool, shift: u64) {
+    let duration = |entries: &[Entry]| entries.iter().map(|e| e.duration as u64).sum::<u64>();
+    let max_duration = tracks.iter().map(|(_, e)| duration(e)).max().unwrap_or(0);
+
+    write_box(buf, b"moov", |buf| {
+        write_full_box(buf, b"mvhd", 0, 0, |buf| {
+            buf.put_u64(0);
+            buf.put_u32(TIMESCALE);
+            buf.put_u32(max_duration as u32);
+            buf.put_u32(0x00010000);
+            buf.put_u16(0x0100);
+            buf.put_bytes(0, 10);
+            MATRIX.iter().for_each(|v| buf.put_u32(*v));
+            buf.put_bytes(0, 24);
+            buf.put_u32(tracks.len() as u32 + 1);
+        });
+
+        for (track, entries) in tracks {
+            trak(buf, track, entries, duration(entries), shift);
+        }
+
+        if fragmented {
+            write_box(buf, b"mvex", |buf| {
+                for (track, _) in tracks {
+                    write_full_box(buf, b"trex", 0, 0, |buf| {
+                        buf.put_u32(track.id);
+                        buf.put_u32(1);
+                        buf.put_u32(0);
+                        buf.put_u32(0);
+                        buf.put_u32(0);
+                    });
+                }
+            });
+        }
+    });
+}
+
+fn trak(buf: &mut BytesMut, track: &Track, entries: &[Entry], duration: u64, shift: u64) {
+    write_box(buf, b"trak", |buf| {
+        write_full_box(buf, b"tkhd", 0, 3, |buf| {
+            buf.put_u64(0);
+            buf.put_u32(track.id);
+            buf.put_u32(0);
+            buf.put_u32(duration as u32);
+            buf.put_bytes(0, 12);
+            buf.put_u16(if track.is_video() { 0 } else { 0x0100 });
+            buf.put_u16(0);
+            MATRIX.iter().for_each(|v| buf.put_u32(*v));
+            buf.put_u32(track.width << 16);
+            buf.put_u32(track.height << 16);
+        });
+
+        write_box(buf, b"mdia", |buf| {
+            write_full_box(buf, b"mdhd", 0, 0, |buf| {
+                buf.put_u64(0);
+                buf.put_u32(TIMESCALE);
+                buf.put_u32(duration as u32);
+                buf.put_u16(0x55c4);
+                buf.put_u16(0);
+            });
+
+            write_full_box(buf, b"hdlr", 0, 0, |buf| {
+                buf.put_u32(0);
+                buf.put_slice(if track.is_video() { b"vide" } else { b"soun" });
+                buf.put_bytes(0, 12);
+                buf.put_slice(if track.is_video() {
+                    b"VideoHandler\0"
+                } else {
+                    b"SoundHandler\0"
+                });
+            });
+
+            write_box(buf, b"minf", |buf| {
+                if track.is_video() {
+                    write_full_box(buf, b"vmhd", 0, 1, |buf| buf.put_bytes(0, 8));
+                } else {
+                    write_full_box(buf, b"smhd", 0, 0, |buf| buf.put_u32(0));
+                }
+
+                write_box(buf, b"dinf", |buf| {
+                    write_full_box(buf, b"dref", 0, 0, |buf| {
+                        buf.put_u32(1);
+                        write_full_box(buf, b"url ", 0, 1, |_| ());
+                    });
+                });
+
+                stbl(buf, track, entries, shift);
+            });
+        });
+    });
+}
+
+fn stbl(buf: &mut BytesMut, track: &Track, entries: &[Entry], shift: u64) {
+    write_box(buf, b"stbl", |buf| {
+        write_full_box(buf, b"stsd", 0, 0, |buf| {
+            buf.put_u32(1);
+            sample_entry(buf, track);
+        });
+
+        // Consecutive samples with the same duration or offset share a
+        // single entry.
+        let runs = |values: Vec<i64>| {
+            let mut runs: Vec<(u32, i64)> = Vec::new();
+            for value in values {
+                match runs.last_mut() {
+                    Some((count, last)) if *last == value => *count += 1,
+                    _ => runs.push((1, value)),
+                }
+            }
+
+            runs
+        };
+
+        let durations = runs(entries.iter().map(|e| e.duration as i64).collect());
+        write_full_box(buf, b"stts", 0, 0, |buf| {
+            buf.put_u32(durations.len() as u32);
+            for (count, duration) in durations {
+                buf.put_u32(count);
+                buf.put_u32(duration as u32);
+            }
+        });
+
+        if entries.iter().any(|e| e.cts != 0) {
+            let offsets = runs(entries.iter().map(|e| e.cts as i64).collect());
+            write_full_box(buf, b"ctts", 1, 0, |buf| {
+                buf.put_u32(offsets.len() as u32);
+                for (count, offset) in offsets {
+                    buf.put_u32(count);
+                    buf.put_i32(offset as i32);
+                }
+            });
+        }
+
+        if track.is_video() && entries.iter().any(|e| !e.sync) {
+            let syncs = (1..=entries.len() as u32)
+                .filter(|i| entries[*i as usize - 1].sync)
+                .collect::<Vec<_>>();
+            write_full_box(buf, b"stss", 0, 0, |buf| {
+                buf.put_u32(syncs.len() as u32);
+                syncs.iter().for_each(|i| buf.put_u32(*i));
+            });
+        }
+
+        write_full_box(buf, b"stsc", 0, 0, |buf| {
+            if entries.is_empty() {
+                buf.put_u32(0);
+            } else {
+                buf.put_u32(1);
+                buf.put_u32(1);
+                buf.put_u32(1);
+                buf.put_u32(1);
+            }
+        });
+
+        write_full_box(buf, b"stsz", 0, 0, |buf| {
+            buf.put_u32(0);
+            buf.put_u32(entries.len() as u32);
+            entries.iter().for_each(|e| buf.put_u32(e.size));
+        });
+
+        write_full_box(buf, b"co64", 0, 0, |buf| {
+            buf.put_u32(entries.len() as u32);
+            entries.iter().for_each(|e| buf.put_u64(e.offset + shift));
+        });
+    });
+}
+
+fn sample_entry(buf: &mut BytesMut, track: &Track) {
+    let (kind, config_kind) = match track.codec {
+        Codec::Avc(_) => (b"avc1", b"avcC"),
+        Codec::Hevc(_) => (b"hvc1", b"hvcC"),
+        Codec::Aac(ref config) => {
+            return write_box(buf, b"mp4a", |buf| {
+                buf.put_bytes(0, 6);
+                buf.put_u16(1);
+                buf.put_bytes(0, 8);
+                buf.put_u16(config.channels as u16);
+                buf.put_u16(16);
+                buf.put_u32(0);
+                buf.put_u32(config.sample_rate.min(0xffff) << 16);
+                esds(buf, track);
+            });
+        }
+    };
+
+    write_box(buf, kind, |buf| {
+        buf.put_bytes(0, 6);
+        buf.put_u16(1);
+        buf.put_bytes(0, 16);
+        buf.put_u16(track.width as u16);
+        buf.put_u16(track.height as u16);
+        buf.put_u32(0x00480000);
+        buf.put_u32(0x00480000);
+        buf.put_u32(0);
+        buf.put_u16(1);
+        buf.put_bytes(0, 32);
+        buf.put_u16(0x0018);
+        buf.put_i16(-1);
+        write_box(buf, config_kind, |buf| buf.put_slice(&track.config));
+    });
+}
+
+fn esds(buf: &mut BytesMut, track: &Track) {
+    let config = &track.config;
+    write_full_box(buf, b"esds", 0, 0, |buf| {
+        // ES_Descriptor
+        buf.put_u8(0x03);
+        buf.put_u8(23 + config.len() as u8);
+        buf.put_u16(track.id as u16);
+        buf.put_u8(0);
+
+        // DecoderConfigDescriptor, mpeg-4 audio stream
+        buf.put_u8(0x04);
+        buf.put_u8(15 + config.len() as u8);
+        buf.put_u8(0x40);
+        buf.put_u8(0x15);
+        buf.put_uint(0, 3);
+        buf.put_u32(0);
+        buf.put_u32(0);
+
+        // DecoderSpecificInfo
+        buf.put_u8(0x05);
+        buf.put_u8(config.len() as u8);
+        buf.put_slice(config);
+
+        // SLConfigDescriptor
+        buf.put_u8(0x06);
+        buf.put_u8(1);
+        buf.put_u8(0x02);
+    });
+}
+
+/// The samples of a track in a movie fragment, the durations of the samples
+/// must be set.
+pub struct Fragment<'a> {
+    pub track: &'a Track,
+    pub samples: &'a [Sample],
+}
+
+/// Write a movie fragment box followed by the media data box holding the
+/// samples of all fragments.
+pub fn moof(buf: &mut BytesMut, sequence: u32, fragments: &[Fragment]) {
+    let start = buf.len();
+    let mut data_offsets = Vec::with_capacity(fragments.len());
+
+    write_box(buf, b"moof", |buf| {
+        write_full_box(buf, b"mfhd", 0, 0, |buf| buf.put_u32(sequence));
+        for fragment in fragments {
+            write_box(buf, b"traf", |buf| {
+                // default-base-is-moof
+                write_full_box(buf, b"tfhd", 0, 0x020000, |buf| {
+                    buf.put_u32(fragment.track.id)
+                });
+                write_full_box(buf, b"tfdt", 1, 0, |buf| {
+                    buf.put_u64(fragment.samples.first().map(|s| s.dts).unwrap_or(0));
+                });
+
+                // data-offset, duration, size, flags and composition time
+                // offset are present for every sample.
+                write_full_box(buf, b"trun", 1, 0x000f01, |buf| {
+                    buf.put_u32(fragment.samples.len() as u32);
+                    data_offsets.push(buf.len());
+                    buf.put_i32(0);
+                    for sample in fragment.samples {
+                        buf.put_u32(sample.duration);
+                        buf.put_u32(sample.data.len() as u32);
+                        buf.put_u32(if sample.sync { 0x02000000 } else { 0x01010000 });
+                        buf.put_i32(sample.cts);
+                    }
+                });
+            });
+        }
+    });
+
+    let size = fragments
+        .iter()
+        .flat_map(|f| f.samples.iter())
+        .map(|s| s.data.len())
+        .sum::<usize>();
+
+    let mut offset = buf.len() - start + 8;
+    for (fragment, position) in fragments.iter().zip(data_offsets) {
+        buf[position..position + 4].copy_from_slice(&(offset as i32).to_be_bytes());
+        offset += fragment.samples.iter().map(|s| s.data.len()).sum::<usize>();
+    }
+
+    buf.put_u32(size as u32 + 8);
+    buf.put_slice(b"mdat");
+    for sample in fragments.iter().flat_map(|f| f.samples.iter()) {
+        buf.put_slice(&sample.data);
+    }
+}
@@ -0,0 +1,325 @@
+use super::{ftyp, moof, moov, Fragment, Sample, Track};
+use crate::flv::FlvFrame;
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+
+/// Fragments of audio only streams are cut at this duration in milliseconds
+/// when grouping by gop.
+const FRAGMENT_DURATION: u64 = 1000;
+
+/// How the samples of a live stream are grouped into movie fragments.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Fragmentation {
+    /// One fragment per frame, the lowest latency.
+    #[default]
+    Frame,
+    /// One fragment per group of pictures, starting at every keyframe.
+    Gop,
+}
+
+impl Fragmentation {
+    pub fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some("gop") => Self::Gop,
+            _ => Self::Frame,
+        }
+    }
+}
+
+/// Converts flv tags carrying avc, hevc and aac into fragmented mp4 for
+/// media source extensions.
+///
+/// The init segment is written before the first keyframe, or before the
+/// first audio frame of a stream without video, the frames before it are
+/// dropped. The tracks are fixed by the init segment. Every sample waits
+/// for the next one of its track to know its duration.
+#[derive(Default)]
+pub struct Fmp4Encoder {
+    fragmentation: Fragmentation,
+    /// The video track is at index 0 and the audio track at index 1.
+    tracks: [Option<Track>; 2],
+    pending: [Option<Sample>; 2],
+    queue: [Vec<Sample>; 2],
+    /// The mime type of the init segment, set once it has been written.
+    mime: Option<String>,
+    sequence: u32,
+    buf: BytesMut,
+}
+
+impl Fmp4Encoder {
+    pub fn new(fragmentation: Fragmentation) -> Self {
+        Self {
+            fragmentation,
+            ..Self::default()
+        }
+    }
+
+    /// The mime type with the codecs of the tracks, for example
+    /// `video/mp4; codecs="avc1.64001f,mp4a.40.2"`, known once the init
+    /// segment has been written.
+    pub fn mime(&self) -> Option<&str> {
+        self.mime.as_deref()
+    }
+
+    /// Encode a tag, returns the segments written for it, which may be
+    /// empty.
+    pub fn encode(&mut self, frame: FlvFrame, timestamp: u32, data: &Bytes) -> Result<Bytes> {
+        let index = match frame {
+            FlvFrame::Video => 0,
+            FlvFrame::Audio => 1,
+            FlvFrame::Script => return Ok(Bytes::new()),
+        };
+
+        if frame.is_sequence_header(data) {
+            if self.mime.is_none() {
+                self.tracks[index] = Track::from_flv(index as u32 + 1, frame, data)?;
+            }
+
+            return Ok(Bytes::new());
+        }
+
+        let sample = match Sample::from_flv(frame, timestamp as u64, data) {
+            Some(sample) if self.tracks[index].is_some() => sample,
+            _ => return Ok(Bytes::new()),
+        };
+
+        if self.mime.is_none() {
+            if self.tracks[0].is_some() && !(index == 0 && sample.sync) {
+                return Ok(Bytes::new());
+            }
+
+            self.init();
+        }
+
+        if let Some(mut last) = self.pending[index].replace(sample) {
+            let dts = self.pending[index].as_ref().unwrap().dts;
+            last.duration = dts.saturating_sub(last.dts) as u32;
+            self.push(index, last);
+        }
+
+        Ok(self.buf.split().freeze())
+    }
+
+    fn init(&mut self) {
+        let tracks = self
+            .tracks
+            .iter()
+            .flatten()
+            .map(|track| (track, &[][..]))
+            .collect::<Vec<_>>();
+
+        ftyp(&mut self.buf, true);
+        moov(&mut self.buf, &tracks, true, 0);
+
+        let codecs = tracks
+            .iter()
+            .map(|(track, _)| track.codec_string())
+            .collect::<Vec<_>>();
+        let kind = if self.tracks[0].is_some() {
+            "video"
+        } else {
+            "audio"
+        };
+
+        self.mime = Some(format!("{}/mp4; codecs=\"{}\"", kind, codecs.join(",")));
+    }
+
+    fn push(&mut self, index: usize, sample: Sample) {
+        if self.fragmentation == Fragmentation::Frame {
+            self.queue[index].push(sample);
+            return self.flush();
+        }
+
+        // A new fragment starts at every video keyframe, or after a fixed
+        // duration if there is no video.
+        let flush = if self.tracks[0].is_some() {
+            index == 0 && sample.sync
+        } else {
+            self.queue[index]
+                .first()
+                .map(|first| sample.dts - first.dts >= FRAGMENT_DURATION)
+                .unwrap_or(false)
+        };
+
+        if flush {
+            self.flush();
+        }
+
+        self.queue[index].push(sample);
+    }
+
+    fn flush(&mut self) {
+        if self.queue.iter().all(|queue| queue.is_empty()) {
+            return;
+        }
+
+        self.sequence += 1;
+        let fragments = self
+            .tracks
+            .iter()
+            .zip(self.queue.iter())
+            .filter_map(|(track, samples)| Some((track.as_ref()?, samples)))
+            .filter(|(_, samples)| !samples.is_empty())
+            .map(|(track, samples)| Fragment { track, samples })
+            .collect::<Vec<_>>();
+
+        moof(&mut self.buf, self.sequence, &fragments);
+        self.queue.iter_mut().for_each(|queue| queue.clear());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::avc::AvcConfig;
+
+    use bytes::BufMut;
+
+    const SPS: [u8; 24] = [
+        0x67, 0x42, 0xc0, 0x1e, 0xda, 0x02, 0x80, 0xbf, 0xe5, 0x84, 0x00, 0x00, 0x03, 0x00, 0x04,
+        0x00, 0x00, 0x03, 0x00, 0xca, 0x3c, 0x58, 0xba, 0x80,
+    ];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+
+    fn video_header() -> Bytes {
+        let mut header = BytesMut::from(&[0x17, 0, 0, 0, 0][..]);
+        header.put_slice(&AvcConfig::encode(&SPS, &PPS));
+        header.freeze()
+    }
+
+    fn video(keyframe: bool) -> Bytes {
+        let mut frame = BytesMut::new();
+        frame.put_slice(&[if keyframe { 0x17 } else { 0x27 }, 1, 0, 0, 0]);
+        frame.put_u32(4);
+        frame.put_slice(&[if keyframe { 0x65 } else { 0x41 }, 0x88, 0x84, 0]);
+        frame.freeze()
+    }
+
+    fn audio_header() -> Bytes {
+        Bytes::from_static(&[0xaf, 0, 0x12, 0x10])
+    }
+
+    fn audio() -> Bytes {
+        Bytes::from_static(&[0xaf, 1, 0x21, 0x10, 0x04])
+    }
+
+    /// The types of the top level boxes of a segment.
+    fn boxes(buf: &[u8]) -> Vec<&str> {
+        let mut types = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= buf.len() {
+            let size = u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
+            types.push(std::str::from_utf8(&buf[offset + 4..offset + 8]).unwrap());
+            offset += size;
+        }
+
+        types
+    }
+
+    /// The sample counts of the track runs of a segment.
+    fn samples(buf: &[u8]) -> Vec<u32> {
+        buf.windows(4)
+            .enumerate()
+            .filter(|(_, window)| window == b"trun")
+            .map(|(offset, _)| u32::from_be_bytes(buf[offset + 8..offset + 12].try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn init_segment_is_written_at_the_first_keyframe() {
+        let mut encoder = Fmp4Encoder::new(Fragmentation::Frame);
+        encoder.encode(FlvFrame::Video, 0, &video_header()).unwrap();
+        encoder.encode(FlvFrame::Audio, 0, &audio_header()).unwrap();
+
+        assert!(encoder
+            .encode(FlvFrame::Video, 0, &video(false))
+            .unwrap()
+            .is_empty());
+        assert!(encoder
+            .encode(FlvFrame::Audio, 10, &audio())
+            .unwrap()
+            .is_empty());
+        assert!(encoder.mime().is_none());
+
+        let init = encoder.encode(FlvFrame::Video, 40, &video(true)).unwrap();
+        assert_eq!(boxes(&init), ["ftyp", "moov"]);
+        assert_eq!(
+            encoder.mime(),
+            Some("video/mp4; codecs=\"avc1.42c01e,mp4a.40.2\"")
+        );
+    }
+
+    #[test]
+    fn frame_fragmentation_writes_a_fragment_per_frame() {
+        let mut encoder = Fmp4Encoder::new(Fragmentation::Frame);
+        encoder.encode(FlvFrame::Video, 0, &video_header()).unwrap();
+        encoder.encode(FlvFrame::Video, 0, &video(true)).unwrap();
+
+        // Every frame completes the one before it.
+        for i in 1..5 {
+            let buf = encoder
+                .encode(FlvFrame::Video, i * 40, &video(false))
+                .unwrap();
+            assert_eq!(boxes(&buf), ["moof", "mdat"]);
+            assert_eq!(samples(&buf), [1]);
+        }
+    }
+
+    #[test]
+    fn gop_fragmentation_starts_a_fragment_at_every_keyframe() {
+        let mut encoder = Fmp4Encoder::new(Fragmentation::Gop);
+        encoder.encode(FlvFrame::Video, 0, &video_header()).unwrap();
+        encoder.encode(FlvFrame::Audio, 0, &audio_header()).unwrap();
+
+        let mut segments = Vec::new();
+        for gop in 0..3 {
+            for i in 0..4 {
+                let timestamp = (gop * 4 + i) * 40;
+                let buf = encoder
+                    .encode(FlvFrame::Video, timestamp, &video(i == 0))
+                    .unwrap();
+                segments.push(buf);
+                let buf = encoder
+                    .encode(FlvFrame::Audio, timestamp + 20, &audio())
+                    .unwrap();
+                segments.push(buf);
+            }
+        }
+
+        let segments = segments
+            .into_iter()
+            .filter(|buf| !buf.is_empty())
+            .collect::<Vec<_>>();
+
+        // The init segment, then a fragment when the keyframes of the second
+        // and the third gop complete the last frame of the gop before.
+        assert_eq!(segments.len(), 3);
+        assert_eq!(boxes(&segments[0]), ["ftyp", "moov"]);
+        for segment in &segments[1..] {
+            assert_eq!(boxes(segment), ["moof", "mdat"]);
+            assert_eq!(samples(segment), [4, 4]);
+        }
+    }
+
+    #[test]
+    fn gop_fragmentation_cuts_audio_only_streams_by_duration() {
+        let mut encoder = Fmp4Encoder::new(Fragmentation::Gop);
+        encoder.encode(FlvFrame::Audio, 0, &audio_header()).unwrap();
+
+        let mut segments = Vec::new();
+        for i in 0..50 {
+            let buf = encoder.encode(FlvFrame::Audio, i * 100, &audio()).unwrap();
+            if !buf.is_empty() {
+                segments.push(buf);
+            }
+        }
+
+        assert_eq!(encoder.mime(), Some("audio/mp4; codecs=\"mp4a.40.2\""));
+        assert_eq!(boxes(&segments[0]), ["ftyp", "moov"]);
+        for segment in &segments[1..] {
+            assert_eq!(samples(segment), [10]);
+        }
+        assert_eq!(segments.len(), 5);
+    }
+}
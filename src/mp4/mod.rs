@@ -0,0 +1,150 @@
+mod boxes;
+mod encoder;
+
+pub use self::boxes::{ftyp, moof, moov, Entry, Fragment};
+pub use self::encoder::{Fmp4Encoder, Fragmentation};
+
+use crate::{
+    codec::{aac::AacConfig, avc::AvcConfig, hevc::HevcConfig},
+    flv::{FlvAudio, FlvFrame, FlvVideo, VIDEO_CODEC_AVC},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+/// All tracks use milliseconds, the same unit as flv timestamps.
+pub const TIMESCALE: u32 = 1000;
+
+#[derive(Clone, Debug)]
+pub enum Codec {
+    Avc(AvcConfig),
+    Hevc(HevcConfig),
+    Aac(AacConfig),
+}
+
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub id: u32,
+    pub codec: Codec,
+    /// The decoder configuration record or the audio specific config.
+    pub config: Bytes,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Track {
+    /// Create a track from the sequence header of a flv stream, returns
+    /// `None` if the tag is not a supported sequence header.
+    pub fn from_flv(id: u32, frame: FlvFrame, data: &Bytes) -> Result<Option<Self>> {
+        let (codec, config) = match frame {
+            FlvFrame::Video => match FlvVideo::parse(data) {
+                Some(video) if video.header => {
+                    let codec = if video.codec == VIDEO_CODEC_AVC {
+                        Codec::Avc(AvcConfig::parse(video.data.clone())?)
+                    } else {
+                        Codec::Hevc(HevcConfig::parse(video.data.clone())?)
+                    };
+
+                    (codec, video.data)
+                }
+                _ => return Ok(None),
+            },
+            FlvFrame::Audio => match FlvAudio::parse(data) {
+                Some(audio) if audio.header => {
+                    (Codec::Aac(AacConfig::parse(&audio.data)?), audio.data)
+                }
+                _ => return Ok(None),
+            },
+            FlvFrame::Script => return Ok(None),
+        };
+
+        let (width, height) = match &codec {
+            Codec::Avc(config) => config.dimensions(),
+            Codec::Hevc(config) => config.dimensions(),
+            Codec::Aac(_) => None,
+        }
+        .unwrap_or_default();
+
+        Ok(Some(Self {
+            id,
+            codec,
+            config,
+            width,
+            height,
+        }))
+    }
+
+    /// The codecs parameter of the track, RFC 6381.
+    pub fn codec_string(&self) -> String {
+        match &self.codec {
+            Codec::Avc(config) => format!(
+                "avc1.{:02x}{:02x}{:02x}",
+                config.profile, config.compatibility, config.level
+            ),
+            Codec::Hevc(config) => {
+                // The compatibility flags are written in reverse bit order,
+                // and the trailing zero bytes of the constraints are omitted.
+                let space = ["", "A", "B", "C"][config.profile_space as usize & 0x03];
+                let tier = if config.tier == 0 { "L" } else { "H" };
+                let mut codec = format!(
+                    "hvc1.{}{}.{:x}.{}{}",
+                    space,
+                    config.profile,
+                    config.compatibility.reverse_bits(),
+                    tier,
+                    config.level
+                );
+
+                let end = config
+                    .constraints
+                    .iter()
+                    .rposition(|byte| *byte != 0)
+                    .map_or(0, |position| position + 1);
+                for byte in &config.constraints[..end] {
+                    codec.push_str(&format!(".{:x}", byte));
+                }
+
+                codec
+            }
+            Codec::Aac(config) => format!("mp4a.40.{}", config.object_type),
+        }
+    }
+
+    pub fn is_video(&self) -> bool {
+        !matches!(self.codec, Codec::Aac(_))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    /// Decode time in milliseconds.
+    pub dts: u64,
+    /// Composition time offset in milliseconds.
+    pub cts: i32,
+    pub duration: u32,
+    pub sync: bool,
+    pub data: Bytes,
+}
+
+impl Sample {
+    /// Create a sample from a flv media tag, the sequence headers and
+    /// unsupported codecs are ignored.
+    pub fn from_flv(frame: FlvFrame, timestamp: u64, data: &Bytes) -> Option<Self> {
+        let (cts, sync, data) = match frame {
+            FlvFrame::Video => {
+                let video = FlvVideo::parse(data).filter(|v| !v.header)?;
+                (video.cts, video.keyframe, video.data)
+            }
+            FlvFrame::Audio => (0, true, FlvAudio::parse(data).filter(|a| !a.header)?.data),
+            FlvFrame::Script => return None,
+        };
+
+        Some(Self {
+            dts: timestamp,
+            duration: 0,
+            cts,
+            sync,
+            data,
+        })
+    }
+}
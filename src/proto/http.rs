@@ -1,4 +1,4 @@
-use crate::router::RouterReceiver;
+use crate::router::{RouterFmp4Receiver, RouterReceiver, RouterTsReceiver};
 
 use std::{pin::Pin, task::Context, task::Poll};
 
@@ -26,7 +26,7 @@ impl Body for Stream {
     ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
         match self.as_mut().receiver.poll_read(cx) {
             Poll::Pending => Poll::Pending,
-            Poll::Ready(res) => Poll::Ready(res.and_then(|b| Some(Ok(Bytes::from(b))))),
+            Poll::Ready(res) => Poll::Ready(res.map(|b| Ok(Bytes::from(b)))),
         }
     }
 
@@ -37,3 +37,72 @@ impl Body for Stream {
         Poll::Ready(Ok(None))
     }
 }
+
+/// The body of a stream encoded as mpeg-ts.
+pub struct TsStream {
+    receiver: RouterTsReceiver,
+}
+
+impl TsStream {
+    pub fn new(receiver: RouterTsReceiver) -> Self {
+        Self { receiver }
+    }
+}
+
+impl Body for TsStream {
+    type Data = Bytes;
+    type Error = anyhow::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        self.as_mut().receiver.poll_read(cx).map(|res| res.map(Ok))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+}
+
+/// The body of a stream encoded as fragmented mp4, the init segment has
+/// been read to announce the codecs before the body starts.
+pub struct Fmp4Stream {
+    receiver: RouterFmp4Receiver,
+    init: Option<Bytes>,
+}
+
+impl Fmp4Stream {
+    pub fn new(receiver: RouterFmp4Receiver, init: Bytes) -> Self {
+        Self {
+            init: Some(init),
+            receiver,
+        }
+    }
+}
+
+impl Body for Fmp4Stream {
+    type Data = Bytes;
+    type Error = anyhow::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        if let Some(init) = self.init.take() {
+            return Poll::Ready(Some(Ok(init)));
+        }
+
+        self.as_mut().receiver.poll_read(cx).map(|res| res.map(Ok))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+}
@@ -1,3 +1,5 @@
 pub mod http;
 pub mod rtmp;
+pub mod rtsp;
+pub mod srt;
 pub mod websocket;
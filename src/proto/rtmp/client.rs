@@ -0,0 +1,308 @@
+use crate::flv::{FlvFrame, FlvTag};
+
+use std::{collections::HashMap, io::Cursor};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use bytes::Bytes;
+use rml_rtmp::{
+    chunk_io::*,
+    handshake::{HandshakeProcessResult::*, *},
+    messages::*,
+    rml_amf0::{deserialize, serialize, Amf0Value, Amf0Value::*},
+    time::RtmpTimestamp,
+};
+
+/// The chunk size used by the client, the default of 128 bytes splits every
+/// video frame into a lot of chunks.
+const CHUNK_SIZE: u32 = 4096;
+
+/// The parts of an rtmp url, `rtmp://host[:port]/app[/instance]/stream`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RtmpUrl {
+    /// The host and port of the server.
+    pub addr: String,
+    pub app: String,
+    pub stream: String,
+}
+
+impl RtmpUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("rtmp://")
+            .ok_or_else(|| anyhow!("not an rtmp url: {}", url))?;
+
+        let (host, path) = rest
+            .split_once('/')
+            .ok_or_else(|| anyhow!("rtmp url has no app: {}", url))?;
+        let (app, stream) = path
+            .rsplit_once('/')
+            .ok_or_else(|| anyhow!("rtmp url has no stream: {}", url))?;
+        ensure!(
+            !host.is_empty() && !app.is_empty() && !stream.is_empty(),
+            "invalid rtmp url: {}",
+            url
+        );
+
+        Ok(Self {
+            addr: if host.contains(':') {
+                host.to_string()
+            } else {
+                format!("{}:1935", host)
+            },
+            app: app.to_string(),
+            stream: stream.to_string(),
+        })
+    }
+
+    pub fn tc_url(&self) -> String {
+        format!("rtmp://{}/{}", self.addr, self.app)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientMode {
+    /// Send a stream to the server.
+    Publish,
+    /// Receive a stream from the server.
+    Play,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Handshake,
+    Connect,
+    CreateStream,
+    Start,
+    Ready,
+}
+
+/// The client side of an rtmp connection that publishes or plays a stream.
+///
+/// Like the server, it does no io by itself, the bytes read from the socket
+/// are passed to `process`, and the returned bytes are written back.
+pub struct RtmpClient {
+    url: RtmpUrl,
+    mode: ClientMode,
+    state: State,
+    handshake: Handshake,
+    encoder: ChunkSerializer,
+    decoder: ChunkDeserializer,
+    stream_id: u32,
+    /// The frames received by a playing client.
+    tags: Vec<FlvTag>,
+    /// The server expects an acknowledgement every `window` bytes.
+    window: u32,
+    received: u32,
+    acknowledged: u32,
+}
+
+impl RtmpClient {
+    pub fn new(url: RtmpUrl, mode: ClientMode) -> Self {
+        Self {
+            handshake: Handshake::new(PeerType::Client),
+            encoder: ChunkSerializer::new(),
+            decoder: ChunkDeserializer::new(),
+            state: State::Handshake,
+            tags: Vec::new(),
+            window: 0,
+            received: 0,
+            acknowledged: 0,
+            stream_id: 0,
+            mode,
+            url,
+        }
+    }
+
+    /// The bytes that start the handshake, sent once the socket connects.
+    pub fn start(&mut self) -> Result<Vec<u8>> {
+        Ok(self.handshake.generate_outbound_p0_and_p1()?)
+    }
+
+    /// The server has accepted the publish or the play request, media can be
+    /// sent or received.
+    pub fn is_ready(&self) -> bool {
+        self.state == State::Ready
+    }
+
+    /// Take the frames received since the last call.
+    pub fn tags(&mut self) -> Vec<FlvTag> {
+        std::mem::take(&mut self.tags)
+    }
+
+    fn encode(&mut self, stream_id: u32, timestamp: u32, msg: RtmpMessage) -> Result<Vec<u8>> {
+        let payload = msg.into_message_payload(RtmpTimestamp { value: timestamp }, stream_id)?;
+        Ok(self.encoder.serialize(&payload, false, false)?.bytes)
+    }
+
+    fn command(
+        &mut self,
+        name: &str,
+        id: f64,
+        obj: Amf0Value,
+        args: Vec<Amf0Value>,
+    ) -> Result<Vec<u8>> {
+        let msg = RtmpMessage::Amf0Command {
+            command_name: name.to_string(),
+            transaction_id: id,
+            command_object: obj,
+            additional_arguments: args,
+        };
+
+        let stream_id = match name {
+            "publish" | "play" => self.stream_id,
+            _ => 0,
+        };
+
+        self.encode(stream_id, 0, msg)
+    }
+
+    #[rustfmt::skip]
+    fn connect(&mut self) -> Result<Vec<u8>> {
+        let mut obj = HashMap::new();
+        obj.insert("app".to_string(), Utf8String(self.url.app.clone()));
+        obj.insert("type".to_string(), Utf8String("nonprivate".to_string()));
+        obj.insert("flashVer".to_string(), Utf8String("FMLE/3.0".to_string()));
+        obj.insert("tcUrl".to_string(), Utf8String(self.url.tc_url()));
+
+        let timestamp = RtmpTimestamp { value: 0 };
+        let mut bytes = self.encoder.set_max_chunk_size(CHUNK_SIZE, timestamp)?.bytes;
+        bytes.extend(self.command("connect", 1.0, Object(obj), vec![])?);
+        Ok(bytes)
+    }
+
+    fn on_command(&mut self, name: &str, args: Vec<Amf0Value>) -> Result<Vec<u8>> {
+        let status = |args: &[Amf0Value]| match args.first() {
+            Some(Object(info)) => info.get("code").cloned().and_then(|v| v.get_string()),
+            _ => None,
+        };
+
+        match (self.state, name) {
+            (_, "_error") => bail!("rtmp command failed: {:?}", status(&args)),
+            (State::Connect, "_result") => {
+                self.state = State::CreateStream;
+                self.command("createStream", 2.0, Null, vec![])
+            }
+            (State::CreateStream, "_result") => {
+                let id = args.iter().find_map(|v| v.clone().get_number());
+                self.stream_id = id.ok_or_else(|| anyhow!("createStream has no stream id"))? as u32;
+                self.state = State::Start;
+
+                let stream = Utf8String(self.url.stream.clone());
+                match self.mode {
+                    ClientMode::Publish => {
+                        let args = vec![stream, Utf8String("live".to_string())];
+                        self.command("publish", 3.0, Null, args)
+                    }
+                    ClientMode::Play => self.command("play", 3.0, Null, vec![stream]),
+                }
+            }
+            (State::Start, "onStatus") => match status(&args).as_deref() {
+                Some("NetStream.Publish.Start" | "NetStream.Play.Start") => {
+                    self.state = State::Ready;
+                    Ok(Vec::new())
+                }
+                // Some servers reset the stream before it starts.
+                Some("NetStream.Play.Reset") => Ok(Vec::new()),
+                code => bail!("rtmp {:?} failed: {:?}", self.mode, code),
+            },
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    pub fn process(&mut self, buf: &[u8]) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut buf = buf.to_vec();
+
+        if self.state == State::Handshake {
+            match self.handshake.process_bytes(&buf)? {
+                InProgress { response_bytes } => return Ok(response_bytes),
+                Completed {
+                    response_bytes,
+                    remaining_bytes,
+                } => {
+                    bytes.extend_from_slice(&response_bytes);
+                    bytes.extend(self.connect()?);
+                    buf = remaining_bytes;
+                    self.state = State::Connect;
+                }
+            }
+        }
+
+        let mut is_first = true;
+        loop {
+            let input = if is_first { &buf[..] } else { &[] };
+            is_first = false;
+
+            let payload = match self.decoder.get_next_message(input)? {
+                Some(payload) => payload,
+                None => break,
+            };
+
+            match payload.to_rtmp_message()? {
+                RtmpMessage::Amf0Command {
+                    command_name,
+                    additional_arguments,
+                    ..
+                } => bytes.extend(self.on_command(&command_name, additional_arguments)?),
+                RtmpMessage::SetChunkSize { size } => {
+                    self.decoder.set_max_chunk_size(size as usize)?;
+                }
+                RtmpMessage::WindowAcknowledgement { size } => self.window = size,
+                RtmpMessage::AudioData { data } => self.tags.push(FlvTag {
+                    frame: FlvFrame::Audio,
+                    timestamp: payload.timestamp.value,
+                    data,
+                }),
+                RtmpMessage::VideoData { data } => self.tags.push(FlvTag {
+                    frame: FlvFrame::Video,
+                    timestamp: payload.timestamp.value,
+                    data,
+                }),
+                RtmpMessage::Amf0Data { mut values } => {
+                    // The metadata may still be wrapped in `@setDataFrame`.
+                    if let Some(Utf8String(name)) = values.first() {
+                        if name == "@setDataFrame" {
+                            values.remove(0);
+                        }
+                    }
+
+                    self.tags.push(FlvTag {
+                        frame: FlvFrame::Script,
+                        timestamp: payload.timestamp.value,
+                        data: serialize(&values)?.into(),
+                    });
+                }
+                _ => (),
+            }
+        }
+
+        // Acknowledge the received bytes, or the server stops sending.
+        self.received = self.received.wrapping_add(buf.len() as u32);
+        if self.window > 0 && self.received.wrapping_sub(self.acknowledged) >= self.window {
+            self.acknowledged = self.received;
+            let msg = RtmpMessage::Acknowledgement {
+                sequence_number: self.received,
+            };
+            bytes.extend(self.encode(0, 0, msg)?);
+        }
+
+        Ok(bytes)
+    }
+
+    /// Encode a flv tag as an rtmp message of the published stream.
+    pub fn send(&mut self, frame: FlvFrame, timestamp: u32, data: Bytes) -> Result<Vec<u8>> {
+        let msg = match frame {
+            FlvFrame::Audio => RtmpMessage::AudioData { data },
+            FlvFrame::Video => RtmpMessage::VideoData { data },
+            FlvFrame::Script => {
+                // The metadata is sent with `@setDataFrame`, the server
+                // stores it and passes it on to its players.
+                let mut values = vec![Utf8String("@setDataFrame".to_string())];
+                values.extend(deserialize(&mut Cursor::new(&data[..]))?);
+                RtmpMessage::Amf0Data { values }
+            }
+        };
+
+        self.encode(self.stream_id, timestamp, msg)
+    }
+}
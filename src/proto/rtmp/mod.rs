@@ -1,15 +1,59 @@
+mod client;
 mod session;
 
+use crate::flv::FlvFrame;
+
 use async_trait::async_trait;
 use bytes::Bytes;
 use rml_rtmp::{handshake::HandshakeProcessResult::*, handshake::*};
 use std::borrow::Cow;
 
+pub use self::client::{ClientMode, RtmpClient, RtmpUrl};
+
 use self::session::Session;
 
+/// The stream of a publish or play command, with the app and the host of the
+/// connect command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RtmpStream {
+    /// The host of the `tcUrl`, it may have a port.
+    pub host: Option<String>,
+    pub app: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PublishType {
+    Live,
+    Record,
+    Append,
+}
+
+impl From<&str> for PublishType {
+    fn from(value: &str) -> Self {
+        match value {
+            "record" => Self::Record,
+            "append" => Self::Append,
+            _ => Self::Live,
+        }
+    }
+}
+
+impl PublishType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Live => "live",
+            Self::Record => "record",
+            Self::Append => "append",
+        }
+    }
+}
+
 #[async_trait]
 pub trait RtmpObserver: Send + Sync {
-    async fn guard(&mut self, app: &str, key: &str);
+    /// Called when the client starts publishing, returning `false` rejects the
+    /// publish and closes the connection.
+    async fn guard(&mut self, stream: &RtmpStream, kind: PublishType) -> bool;
     async fn data_frame(&mut self, buf: Bytes);
     async fn audio_data(&mut self, timestamp: u32, buf: Bytes);
     async fn video_data(&mut self, timestamp: u32, buf: Bytes);
@@ -33,6 +77,31 @@ impl Rtmp {
         }
     }
 
+    /// The stream a client asked to play, returned once, the request must be
+    /// answered with `play_status`.
+    pub fn play(&mut self) -> Option<RtmpStream> {
+        self.session.play()
+    }
+
+    /// Whether the client is publishing a stream.
+    pub fn is_publishing(&self) -> bool {
+        self.session.is_publishing()
+    }
+
+    pub fn play_status(&mut self, found: bool) -> anyhow::Result<Vec<u8>> {
+        self.session.play_status(found)
+    }
+
+    /// Encode a frame for a playing client.
+    pub fn send(
+        &mut self,
+        frame: FlvFrame,
+        timestamp: u32,
+        data: Bytes,
+    ) -> anyhow::Result<Vec<u8>> {
+        self.session.send(frame, timestamp, data)
+    }
+
     pub async fn process(&mut self, buf: &[u8]) -> anyhow::Result<Vec<u8>> {
         let mut buf: Cow<'_, [u8]> = buf.into();
         let mut bytes = Vec::new();
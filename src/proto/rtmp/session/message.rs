@@ -9,6 +9,9 @@ pub enum Msg {
     ConnectSuccess,
     PublishSuccess,
     CreateSreamSuccess,
+    StreamBegin(u32),
+    PlayStart,
+    PlayNotFound,
 }
 
 impl Msg {
@@ -41,6 +44,24 @@ impl Msg {
         }
     }
 
+    fn stream_begin(stream_id: u32) -> RtmpMessage {
+        RtmpMessage::UserControl {
+            event_type: UserControlEventType::StreamBegin,
+            stream_id: Some(stream_id),
+            buffer_length: None,
+            timestamp: None,
+        }
+    }
+
+    fn play_status(args: CommandArgs) -> RtmpMessage {
+        RtmpMessage::Amf0Command {
+            additional_arguments: vec![args.into()],
+            command_name: "onStatus".to_string(),
+            command_object: Null,
+            transaction_id: 0.0,
+        }
+    }
+
     fn create_sream_success() -> RtmpMessage {
         RtmpMessage::Amf0Command {
             additional_arguments: vec![Number(1.0)],
@@ -59,6 +80,9 @@ impl From<Msg> for RtmpMessage {
             Msg::ConnectSuccess => Msg::connect_success(),
             Msg::PublishSuccess => Msg::publish_success(),
             Msg::CreateSreamSuccess => Msg::create_sream_success(),
+            Msg::StreamBegin(stream_id) => Msg::stream_begin(stream_id),
+            Msg::PlayStart => Msg::play_status(CommandArgs::PlayStart),
+            Msg::PlayNotFound => Msg::play_status(CommandArgs::PlayNotFound),
         }
     }
 }
@@ -66,6 +90,8 @@ impl From<Msg> for RtmpMessage {
 pub enum CommandArgs {
     ConnectSuccess,
     PublishSuccess,
+    PlayStart,
+    PlayNotFound,
 }
 
 impl CommandArgs {
@@ -87,6 +113,24 @@ impl CommandArgs {
         args.insert("description".to_string(), Utf8String("Start publishing".to_string()));
         args
     }
+
+    #[rustfmt::skip]
+    fn play_start() -> HashMap<String, Amf0Value> {
+        let mut args = HashMap::new();
+        args.insert("level".to_string(), Utf8String("status".to_string()));
+        args.insert("code".to_string(), Utf8String("NetStream.Play.Start".to_string()));
+        args.insert("description".to_string(), Utf8String("Start live".to_string()));
+        args
+    }
+
+    #[rustfmt::skip]
+    fn play_not_found() -> HashMap<String, Amf0Value> {
+        let mut args = HashMap::new();
+        args.insert("level".to_string(), Utf8String("error".to_string()));
+        args.insert("code".to_string(), Utf8String("NetStream.Play.StreamNotFound".to_string()));
+        args.insert("description".to_string(), Utf8String("No such stream".to_string()));
+        args
+    }
 }
 
 impl From<CommandArgs> for Amf0Value {
@@ -94,6 +138,8 @@ impl From<CommandArgs> for Amf0Value {
         Object(match val {
             CommandArgs::ConnectSuccess => CommandArgs::connect_success(),
             CommandArgs::PublishSuccess => CommandArgs::publish_success(),
+            CommandArgs::PlayStart => CommandArgs::play_start(),
+            CommandArgs::PlayNotFound => CommandArgs::play_not_found(),
         })
     }
 }
@@ -1,13 +1,20 @@
 mod message;
 
-use super::RtmpObserver;
+use super::{PublishType, RtmpObserver, RtmpStream};
 
-use anyhow::Result;
+use crate::flv::FlvFrame;
+
+use std::io::Cursor;
+
+use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use message::Msg;
-use rml_rtmp::rml_amf0::{serialize, Amf0Value};
+use rml_rtmp::rml_amf0::{deserialize, serialize, Amf0Value};
 use rml_rtmp::{chunk_io::*, messages::MessagePayload, messages::*, time::RtmpTimestamp};
 
+/// The chunk size used for the media sent to players.
+const PLAY_CHUNK_SIZE: u32 = 4096;
+
 pub struct Command {
     encoder: ChunkSerializer,
 }
@@ -54,11 +61,58 @@ impl Command {
     pub fn create_stream(&mut self, id: u32) -> Result<Vec<u8>> {
         self.encode(id, vec![Msg::CreateSreamSuccess.into()])
     }
+
+    pub fn play(&mut self, id: u32, found: bool) -> Result<Vec<u8>> {
+        if !found {
+            return self.encode(id, vec![Msg::PlayNotFound.into()]);
+        }
+
+        let mut bytes = self.set_max_chunk_size(PLAY_CHUNK_SIZE)?;
+        bytes.extend(self.encode(id, vec![Msg::StreamBegin(id).into(), Msg::PlayStart.into()])?);
+        Ok(bytes)
+    }
+
+    pub fn media(
+        &mut self,
+        id: u32,
+        frame: FlvFrame,
+        timestamp: u32,
+        data: Bytes,
+    ) -> Result<Vec<u8>> {
+        let msg = match frame {
+            FlvFrame::Audio => RtmpMessage::AudioData { data },
+            FlvFrame::Video => RtmpMessage::VideoData { data },
+            FlvFrame::Script => RtmpMessage::Amf0Data {
+                values: deserialize(&mut Cursor::new(&data[..]))?,
+            },
+        };
+
+        let payload = msg.into_message_payload(RtmpTimestamp { value: timestamp }, id)?;
+        Ok(self.encoder.serialize(&payload, false, false)?.bytes)
+    }
+}
+
+/// The host of a `rtmp://host[:port]/app` url.
+fn host(tc_url: &str) -> Option<String> {
+    let (_, rest) = tc_url.split_once("://")?;
+    let host = rest.split('/').next()?;
+    Some(host.to_string()).filter(|host| !host.is_empty())
+}
+
+/// Drop the query of an app or a stream name, some clients pass parameters
+/// such as tokens there.
+fn strip_query(name: &str) -> &str {
+    name.split('?').next().unwrap_or_default()
 }
 
 pub struct Session {
     app: Option<String>,
-    key: Option<String>,
+    host: Option<String>,
+    /// The message stream id and the stream name of a play request, and
+    /// whether the request has been answered.
+    play: Option<(u32, String, bool)>,
+    /// A publish request has been accepted.
+    publishing: bool,
     decoder: ChunkDeserializer,
     observer: Box<dyn RtmpObserver>,
     command: Command,
@@ -71,13 +125,58 @@ impl Session {
     {
         Self {
             app: None,
-            key: None,
+            host: None,
+            play: None,
+            publishing: false,
             observer: Box::new(observer),
             decoder: ChunkDeserializer::new(),
             command: Command::new(),
         }
     }
 
+    fn stream(&self, name: &str) -> Option<RtmpStream> {
+        Some(RtmpStream {
+            host: self.host.clone(),
+            app: self.app.clone()?,
+            name: strip_query(name).to_string(),
+        })
+    }
+
+    /// The stream requested by a play command that has not been answered
+    /// yet.
+    pub fn play(&mut self) -> Option<RtmpStream> {
+        match &mut self.play {
+            Some((_, name, answered)) if !*answered => {
+                *answered = true;
+                let name = name.clone();
+                self.stream(&name)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn is_publishing(&self) -> bool {
+        self.publishing
+    }
+
+    /// Answer the play request, a stream that is not found ends the session.
+    pub fn play_status(&mut self, found: bool) -> Result<Vec<u8>> {
+        let (id, ..) = self
+            .play
+            .as_ref()
+            .ok_or_else(|| anyhow!("no play request"))?;
+        self.command.play(*id, found)
+    }
+
+    /// Encode a frame of the played stream.
+    pub fn send(&mut self, frame: FlvFrame, timestamp: u32, data: Bytes) -> Result<Vec<u8>> {
+        let (id, ..) = self
+            .play
+            .as_ref()
+            .ok_or_else(|| anyhow!("no play request"))?;
+        self.command.media(*id, frame, timestamp, data)
+    }
+
     pub fn set_max_chunk_size(&mut self, size: u32) -> Result<Vec<u8>> {
         self.decoder.set_max_chunk_size(size as usize)?;
         self.command.set_max_chunk_size(size)
@@ -92,24 +191,56 @@ impl Session {
     ) -> Result<Option<Vec<u8>>> {
         Ok(match name {
             "createStream" => Some(self.command.create_stream(id)?),
-            "publish" => Some(self.command.publish(id)?),
             "connect" => {
                 if let Amf0Value::Object(info) = obj {
                     if let Some(Amf0Value::Utf8String(app)) = info.get("app") {
-                        let _ = self.app.insert(app.to_string());
+                        let _ = self.app.insert(strip_query(app).to_string());
+                    }
+
+                    if let Some(Amf0Value::Utf8String(tc_url)) = info.get("tcUrl") {
+                        self.host = host(tc_url);
                     }
                 }
 
                 Some(self.command.connect(id)?)
             }
-            "releaseStream" => {
-                if let Some(Amf0Value::Utf8String(key)) = args.get(0) {
-                    let _ = self.key.insert(key.to_string());
-                    if let (Some(a), Some(k)) = (&self.app, &self.key) {
-                        self.observer.guard(a, k).await;
-                    }
+            "publish" => {
+                // The publish command carries the stream name and the publish
+                // type, which is one of live, record and append.
+                let kind = match args.get(1) {
+                    Some(Amf0Value::Utf8String(kind)) => PublishType::from(kind.as_str()),
+                    _ => PublishType::Live,
+                };
+
+                let stream = match args.first() {
+                    Some(Amf0Value::Utf8String(name)) => self.stream(name),
+                    _ => None,
+                };
+
+                // A publish before connect or without a stream name can not be
+                // checked, it is rejected like a publish the guard refuses.
+                let stream =
+                    stream.ok_or_else(|| anyhow!("publish rejected, no connect or stream name"))?;
+                if !self.observer.guard(&stream, kind).await {
+                    return Err(anyhow!(
+                        "publish rejected, app: {}, name: {}",
+                        stream.app,
+                        stream.name
+                    ));
                 }
 
+                self.publishing = true;
+                Some(self.command.publish(id)?)
+            }
+            "play" => {
+                // The play request is answered by the connection, once it
+                // knows whether the stream exists.
+                let name = match args.first() {
+                    Some(Amf0Value::Utf8String(name)) => name.clone(),
+                    _ => String::new(),
+                };
+
+                let _ = self.play.insert((id, name, false));
                 None
             }
             _ => None,
@@ -141,7 +272,7 @@ impl Session {
             }
             RtmpMessage::SetChunkSize { size } => Some(self.set_max_chunk_size(size)?),
             RtmpMessage::Amf0Data { values } => {
-                if let Some(Amf0Value::Utf8String(key)) = values.get(0) {
+                if let Some(Amf0Value::Utf8String(key)) = values.first() {
                     if key.as_str() == "@setDataFrame" {
                         let bytes = serialize(&values)?;
                         let bytes = Bytes::copy_from_slice(&bytes[16..]);
@@ -0,0 +1,247 @@
+pub mod sdp;
+
+use ahash::AHashMap;
+use anyhow::{anyhow, ensure, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// The largest request header accepted, a client that sends more is broken.
+const MAX_HEADER_SIZE: usize = 65536;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub uri: String,
+    /// The header names are lowercase.
+    pub headers: AHashMap<String, String>,
+    pub body: Bytes,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|value| value.as_str())
+    }
+
+    /// The host of the uri, with the port if it has one.
+    pub fn host(&self) -> Option<&str> {
+        let (_, rest) = self.uri.split_once("://")?;
+        rest.split('/').next().filter(|host| !host.is_empty())
+    }
+
+    /// The path of the uri, `rtsp://host:port/app/stream/trackID=0` becomes
+    /// `app/stream/trackID=0`.
+    pub fn path(&self) -> &str {
+        let path = match self.uri.split_once("://") {
+            Some((_, rest)) => rest.split_once('/').map(|(_, path)| path).unwrap_or(""),
+            None => self.uri.trim_start_matches('/'),
+        };
+
+        let path = path.split_once('?').map(|(path, _)| path).unwrap_or(path);
+        path.trim_end_matches('/')
+    }
+}
+
+#[derive(Debug)]
+pub enum Message {
+    Request(Request),
+    /// A packet interleaved in the connection, with its channel.
+    Interleaved(u8, Bytes),
+}
+
+/// An incremental decoder of the requests and interleaved packets sent by a
+/// client.
+#[derive(Default)]
+pub struct RtspDecoder {
+    bytes: BytesMut,
+}
+
+impl RtspDecoder {
+    pub fn extend(&mut self, chunk: &[u8]) {
+        self.bytes.extend_from_slice(chunk);
+    }
+
+    /// Decode the next message, returns `None` when more input is needed.
+    pub fn decode(&mut self) -> Result<Option<Message>> {
+        if self.bytes.first() == Some(&b'$') {
+            if self.bytes.len() < 4 {
+                return Ok(None);
+            }
+
+            let size = u16::from_be_bytes([self.bytes[2], self.bytes[3]]) as usize;
+            if self.bytes.len() < 4 + size {
+                return Ok(None);
+            }
+
+            let channel = self.bytes[1];
+            self.bytes.advance(4);
+            return Ok(Some(Message::Interleaved(
+                channel,
+                self.bytes.split_to(size).freeze(),
+            )));
+        }
+
+        let end = match self.bytes.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end,
+            None => {
+                ensure!(
+                    self.bytes.len() < MAX_HEADER_SIZE,
+                    "rtsp request header is too large"
+                );
+                return Ok(None);
+            }
+        };
+
+        let head = std::str::from_utf8(&self.bytes[..end])?;
+        let mut lines = head.split("\r\n");
+        let mut parts = lines
+            .next()
+            .ok_or_else(|| anyhow!("rtsp request line is missing"))?
+            .split(' ');
+
+        let (method, uri) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(uri), Some(version)) if version.starts_with("RTSP/") => {
+                (method.to_string(), uri.to_string())
+            }
+            _ => return Err(anyhow!("invalid rtsp request line")),
+        };
+
+        let headers: AHashMap<String, String> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+            .collect();
+
+        let size = match headers.get("content-length") {
+            Some(size) => size.parse::<usize>()?,
+            None => 0,
+        };
+
+        if self.bytes.len() < end + 4 + size {
+            return Ok(None);
+        }
+
+        self.bytes.advance(end + 4);
+        let body = self.bytes.split_to(size).freeze();
+        Ok(Some(Message::Request(Request {
+            method,
+            uri,
+            headers,
+            body,
+        })))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Option<(&'static str, String)>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            headers: Vec::new(),
+            body: None,
+            status,
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn body(mut self, content_type: &'static str, body: String) -> Self {
+        self.body = Some((content_type, body));
+        self
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            415 => "Unsupported Media Type",
+            454 => "Session Not Found",
+            455 => "Method Not Valid in This State",
+            459 => "Aggregate Operation Not Allowed",
+            461 => "Unsupported Transport",
+            _ => "Internal Server Error",
+        }
+    }
+
+    /// Write the response to the request with the sequence number.
+    pub fn encode(&self, cseq: Option<&str>) -> Vec<u8> {
+        let mut buf = format!("RTSP/1.0 {} {}\r\n", self.status, self.reason());
+        if let Some(cseq) = cseq {
+            buf.push_str(&format!("CSeq: {}\r\n", cseq));
+        }
+
+        for (name, value) in &self.headers {
+            buf.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        match &self.body {
+            Some((content_type, body)) => {
+                buf.push_str(&format!("Content-Type: {}\r\n", content_type));
+                buf.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+                buf.push_str(body);
+            }
+            None => buf.push_str("\r\n"),
+        }
+
+        buf.into_bytes()
+    }
+}
+
+/// Frame a packet interleaved in the connection.
+pub fn interleaved(channel: u8, packet: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(packet.len() + 4);
+    buf.put_u8(b'$');
+    buf.put_u8(channel);
+    buf.put_u16(packet.len() as u16);
+    buf.put_slice(packet);
+    buf
+}
+
+/// The transport requested by the SETUP of a track.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transport {
+    /// Interleaved in the connection, with the rtp and rtcp channels.
+    Tcp(u8, u8),
+    /// Sent over udp, with the rtp and rtcp ports of the client.
+    Udp(u16, u16),
+}
+
+impl Transport {
+    /// Parse the first supported transport of the `Transport` header, the
+    /// channels of a tcp transport default to the ones of the track.
+    pub fn parse(header: &str, track: usize) -> Option<Self> {
+        header.split(',').find_map(|spec| {
+            let mut params = spec.split(';');
+            let profile = params.next()?.trim();
+            let pair = |value: &str| -> Option<(u16, u16)> {
+                let (first, second) = value.split_once('-').unwrap_or((value, ""));
+                let first = first.parse().ok()?;
+                Some((first, second.parse().unwrap_or(first + 1)))
+            };
+
+            match profile {
+                "RTP/AVP/TCP" => {
+                    let channels = params
+                        .find_map(|param| param.strip_prefix("interleaved="))
+                        .and_then(pair)
+                        .unwrap_or((track as u16 * 2, track as u16 * 2 + 1));
+                    Some(Self::Tcp(channels.0 as u8, channels.1 as u8))
+                }
+                "RTP/AVP" | "RTP/AVP/UDP" => {
+                    let ports = params.find_map(|param| param.strip_prefix("client_port="))?;
+                    let (rtp, rtcp) = pair(ports)?;
+                    Some(Self::Udp(rtp, rtcp))
+                }
+                _ => None,
+            }
+        })
+    }
+}
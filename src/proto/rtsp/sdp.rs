@@ -0,0 +1,115 @@
+/// A media description of a session description.
+#[derive(Debug, Clone, Default)]
+pub struct Media {
+    /// `video` or `audio`.
+    pub kind: String,
+    pub payload_type: u8,
+    /// The encoding name of the rtpmap, uppercase.
+    pub encoding: String,
+    pub clock_rate: u32,
+    pub channels: Option<u8>,
+    /// The format parameters, in order.
+    pub fmtp: Vec<(String, String)>,
+    /// The url of the media, relative to the base url of the session.
+    pub control: String,
+}
+
+impl Media {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.fmtp
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn encode(&self, buf: &mut String) {
+        buf.push_str(&format!(
+            "m={} 0 RTP/AVP {}\r\n",
+            self.kind, self.payload_type
+        ));
+
+        let channels = self
+            .channels
+            .map(|channels| format!("/{}", channels))
+            .unwrap_or_default();
+        buf.push_str(&format!(
+            "a=rtpmap:{} {}/{}{}\r\n",
+            self.payload_type, self.encoding, self.clock_rate, channels
+        ));
+
+        if !self.fmtp.is_empty() {
+            let params: Vec<_> = self
+                .fmtp
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            buf.push_str(&format!(
+                "a=fmtp:{} {}\r\n",
+                self.payload_type,
+                params.join(";")
+            ));
+        }
+
+        buf.push_str(&format!("a=control:{}\r\n", self.control));
+    }
+}
+
+/// Write the session description of a stream.
+pub fn encode(name: &str, medias: &[Media]) -> String {
+    let mut buf = String::with_capacity(512);
+    buf.push_str("v=0\r\n");
+    buf.push_str("o=- 0 0 IN IP4 127.0.0.1\r\n");
+    buf.push_str(&format!("s={}\r\n", name));
+    buf.push_str("c=IN IP4 0.0.0.0\r\n");
+    buf.push_str("t=0 0\r\n");
+    buf.push_str("a=control:*\r\n");
+    for media in medias {
+        media.encode(&mut buf);
+    }
+
+    buf
+}
+
+/// Read the media descriptions of a session description, the attributes of
+/// the session are ignored.
+pub fn parse(sdp: &str) -> Vec<Media> {
+    let mut medias: Vec<Media> = Vec::new();
+    for line in sdp.lines().map(|line| line.trim()) {
+        if let Some(value) = line.strip_prefix("m=") {
+            let mut parts = value.split_whitespace();
+            medias.push(Media {
+                kind: parts.next().unwrap_or_default().to_string(),
+                payload_type: parts.nth(2).and_then(|pt| pt.parse().ok()).unwrap_or(0),
+                ..Media::default()
+            });
+
+            continue;
+        }
+
+        let media = match medias.last_mut() {
+            Some(media) => media,
+            None => continue,
+        };
+
+        if let Some(value) = line.strip_prefix("a=rtpmap:") {
+            let (_, map) = value.split_once(' ').unwrap_or_default();
+            let mut parts = map.split('/');
+            media.encoding = parts.next().unwrap_or_default().to_uppercase();
+            media.clock_rate = parts.next().and_then(|rate| rate.parse().ok()).unwrap_or(0);
+            media.channels = parts.next().and_then(|channels| channels.parse().ok());
+        } else if let Some(value) = line.strip_prefix("a=fmtp:") {
+            let (_, params) = value.split_once(' ').unwrap_or_default();
+            for param in params.split(';') {
+                if let Some((name, value)) = param.split_once('=') {
+                    media
+                        .fmtp
+                        .push((name.trim().to_lowercase(), value.trim().to_string()));
+                }
+            }
+        } else if let Some(value) = line.strip_prefix("a=control:") {
+            media.control = value.to_string();
+        }
+    }
+
+    medias
+}
@@ -0,0 +1,96 @@
+pub use super::websocket::Mode;
+
+use super::websocket::parse;
+
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Result};
+
+/// The stream of an srt connection, read from the stream id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamId {
+    /// The host name of the `h` key, which selects the virtual host.
+    pub host: Option<String>,
+    pub name: String,
+    pub key: String,
+    pub mode: Mode,
+}
+
+impl StreamId {
+    /// Parse the access control syntax `#!::h=host,r=app/stream,m=publish`,
+    /// or a plain `app/stream`. The mode defaults to `request`, which plays
+    /// the stream.
+    pub fn parse(id: &str) -> Result<Self> {
+        let (host, resource, mode) = match id.strip_prefix("#!::") {
+            Some(entries) => {
+                let (mut host, mut resource, mut mode) = (None, None, None);
+                for entry in entries.split(',') {
+                    match entry.split_once('=') {
+                        Some(("h", value)) => host = Some(value.to_string()),
+                        Some(("r", value)) => resource = Some(value),
+                        Some(("m", value)) => mode = Some(value),
+                        _ => (),
+                    }
+                }
+
+                (
+                    host,
+                    resource.ok_or_else(|| anyhow!("stream id has no resource: {}", id))?,
+                    mode,
+                )
+            }
+            None => (None, id, None),
+        };
+
+        let mode = match mode {
+            Some("publish") => Mode::Publish,
+            None | Some("request") => Mode::Play,
+            Some(mode) => return Err(anyhow!("unsupported srt mode: {}", mode)),
+        };
+
+        let (name, key) = resource.split_once('/').unwrap_or((resource, ""));
+        ensure!(!name.is_empty(), "stream id has no app: {}", id);
+
+        Ok(Self {
+            host,
+            name: name.to_string(),
+            key: key.to_string(),
+            mode,
+        })
+    }
+}
+
+/// The parts of an srt url of caller mode,
+/// `srt://host:port?streamid=...&passphrase=...&latency=ms`.
+#[derive(Debug, Clone)]
+pub struct SrtUrl {
+    /// The host and port of the listener.
+    pub addr: String,
+    pub stream_id: Option<String>,
+    pub passphrase: Option<String>,
+    pub latency: Option<Duration>,
+}
+
+impl SrtUrl {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("srt://")
+            .ok_or_else(|| anyhow!("not an srt url: {}", url))?;
+
+        let (addr, query) = rest.split_once('?').unwrap_or((rest, ""));
+        ensure!(addr.contains(':'), "srt url has no port: {}", url);
+
+        let mut querys = parse(query);
+        let latency = match querys.get("latency") {
+            Some(latency) => Some(Duration::from_millis(latency.parse()?)),
+            None => None,
+        };
+
+        Ok(Self {
+            addr: addr.trim_end_matches('/').to_string(),
+            stream_id: querys.remove("streamid"),
+            passphrase: querys.remove("passphrase"),
+            latency,
+        })
+    }
+}
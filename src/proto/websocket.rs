@@ -1,28 +1,71 @@
+use crate::mp4::Fragmentation;
+
 use ahash::AHashMap;
 use anyhow::{anyhow, Result};
-use tokio::net::TcpStream;
-use tokio_tungstenite::tungstenite::{handshake::server::*, protocol::WebSocketConfig};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::*, http::StatusCode, protocol::WebSocketConfig,
+};
 use tokio_tungstenite::{accept_hdr_async_with_config, WebSocketStream};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Play,
+    Publish,
+}
+
 #[derive(Debug, Clone)]
 pub struct Query {
+    /// The host header of the request, which selects the virtual host.
+    pub host: Option<String>,
+    /// The origin header of the request, the web page that opened the
+    /// connection.
+    pub origin: Option<String>,
+    /// The app of the stream.
     pub name: String,
+    /// The name of the stream in the app.
     pub key: String,
+    /// `mode=publish` sends a stream to the server, the default is to play.
+    pub mode: Mode,
+    /// `format=mp4` plays the stream as fragmented mp4 instead of flv, the
+    /// fragments are grouped by `fragment=frame|gop`.
+    pub fragmentation: Option<Fragmentation>,
 }
 
 impl Query {
     pub fn from_str(src: &str) -> Result<Self> {
         let querys = parse(src);
-        Ok(Self {
-            name: querys
-                .get("name")
-                .ok_or_else(|| anyhow!("name is not found!"))?
-                .to_string(),
-            key: querys
-                .get("key")
-                .ok_or_else(|| anyhow!("key is not found!"))?
-                .to_string(),
-        })
+        let name = querys
+            .get("name")
+            .ok_or_else(|| anyhow!("name is not found!"))?
+            .to_string();
+        let key = querys
+            .get("key")
+            .ok_or_else(|| anyhow!("key is not found!"))?
+            .to_string();
+
+        Ok(Self::new(name, key, &querys))
+    }
+
+    /// The stream is given by the caller, the other options are read from the
+    /// query parameters.
+    pub fn new(name: String, key: String, querys: &AHashMap<String, String>) -> Self {
+        Self {
+            host: None,
+            origin: None,
+            name,
+            key,
+            mode: match querys.get("mode").map(|m| m.as_str()) {
+                Some("publish") => Mode::Publish,
+                _ => Mode::Play,
+            },
+            fragmentation: match querys.get("format").map(|f| f.as_str()) {
+                Some("mp4") => Some(Fragmentation::parse(
+                    querys.get("fragment").map(|f| f.as_str()),
+                )),
+                _ => None,
+            },
+        }
     }
 }
 
@@ -37,26 +80,65 @@ pub fn parse(src: &str) -> AHashMap<String, String> {
     querys
 }
 
-struct Guard<'a> {
-    query: &'a mut String,
+/// Reads the query of the handshake request, the request is rejected if the
+/// query is invalid or the check fails.
+struct Guard<'a, F> {
+    query: &'a mut Option<Query>,
+    check: F,
+}
+
+fn reject(status: StatusCode, reason: String) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(reason));
+    *response.status_mut() = status;
+    response
 }
 
-impl Callback for Guard<'_> {
+impl<F: FnOnce(&Query) -> bool + Unpin> Callback for Guard<'_, F> {
     fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
-        if let Some(query) = request.uri().query() {
-            self.query.push_str(query);
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+
+        let query = match Query::from_str(request.uri().query().unwrap_or_default()) {
+            Ok(query) => Query {
+                host: header("host"),
+                origin: header("origin"),
+                ..query
+            },
+            Err(e) => return Err(reject(StatusCode::BAD_REQUEST, e.to_string())),
+        };
+
+        if !(self.check)(&query) {
+            return Err(reject(StatusCode::FORBIDDEN, "forbidden".to_string()));
         }
 
+        *self.query = Some(query);
         Ok(response)
     }
 }
 
-pub async fn accept(
-    socket: TcpStream,
+/// Accept a websocket connection, `check` decides whether the request is
+/// allowed before the handshake is answered.
+pub async fn accept<S, F>(
+    socket: S,
     cfg: Option<WebSocketConfig>,
-) -> Result<(WebSocketStream<TcpStream>, Query)> {
-    let mut query = String::new();
-    let stream = accept_hdr_async_with_config(socket, Guard { query: &mut query }, cfg).await?;
-    let query = Query::from_str(&query)?;
+    check: F,
+) -> Result<(WebSocketStream<S>, Query)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnOnce(&Query) -> bool + Unpin,
+{
+    let mut query = None;
+    let guard = Guard {
+        query: &mut query,
+        check,
+    };
+
+    let stream = accept_hdr_async_with_config(socket, guard, cfg).await?;
+    let query = query.ok_or_else(|| anyhow!("query is not found!"))?;
     Ok((stream, query))
 }
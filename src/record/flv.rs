@@ -0,0 +1,213 @@
+use super::RecordFile;
+use crate::{
+    config::Timestamp,
+    flv::{FlvDecoder, FlvEncoer, FlvFrame, FlvHeader},
+    timestamp::Timestamper,
+};
+
+use std::{collections::HashMap, io::Cursor, io::SeekFrom, path::PathBuf};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use rml_rtmp::rml_amf0::{deserialize, serialize, Amf0Value};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+
+/// Get the properties of an `onMetaData` script tag.
+pub fn parse_metadata(bytes: &[u8]) -> Option<HashMap<String, Amf0Value>> {
+    deserialize(&mut Cursor::new(bytes))
+        .ok()?
+        .into_iter()
+        .find_map(|value| match value {
+            Amf0Value::Object(properties) => Some(properties),
+            _ => None,
+        })
+}
+
+/// A single flv file being recorded.
+///
+/// The tags are written to a `.part` file next to the target path, which is
+/// a playable flv file without metadata, so it survives a crash of the
+/// process. When the file is closed the target is written with an
+/// `onMetaData` tag that has the duration and the keyframe index, followed by
+/// the tags of the part file.
+pub struct FlvFile {
+    path: PathBuf,
+    part: PathBuf,
+    writer: BufWriter<File>,
+    encoder: FlvEncoer,
+    timestamper: Timestamper,
+    metadata: HashMap<String, Amf0Value>,
+    keyframes: Vec<(u32, u64)>,
+    duration: u32,
+    base: u32,
+    size: u64,
+}
+
+impl FlvFile {
+    pub async fn create(path: PathBuf, timestamp: Timestamp, append: bool) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+
+        let mut file = Self {
+            writer: BufWriter::new(File::create(&part).await?),
+            encoder: FlvEncoer::new(FlvHeader::Full),
+            timestamper: Timestamper::new(timestamp),
+            metadata: HashMap::new(),
+            keyframes: Vec::new(),
+            part: part.into(),
+            duration: 0,
+            base: 0,
+            size: 0,
+            path,
+        };
+
+        file.size = file.encoder.encode_header() as u64;
+        file.writer.write_all(&file.encoder.flush_to()).await?;
+        if append && fs::try_exists(&file.path).await? {
+            file.load().await?;
+        }
+
+        Ok(file)
+    }
+
+    /// Copy the tags of the existing file, the new frames continue after the
+    /// last timestamp of the file.
+    async fn load(&mut self) -> Result<()> {
+        let mut source = File::open(&self.path).await?;
+        let mut decoder = FlvDecoder::default();
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let size = source.read(&mut buf).await?;
+            if size == 0 {
+                break;
+            }
+
+            decoder.extend(&buf[..size]);
+            while let Some(tag) = decoder.decode()? {
+                if tag.frame == FlvFrame::Script {
+                    if let Some(metadata) = parse_metadata(&tag.data) {
+                        self.metadata = metadata;
+                    }
+                } else {
+                    self.put(tag.frame, tag.timestamp, &tag.data).await?;
+                }
+            }
+        }
+
+        if self.size > 13 {
+            self.base = self.duration + 1;
+        }
+
+        Ok(())
+    }
+
+    async fn put(&mut self, frame: FlvFrame, timestamp: u32, bytes: &[u8]) -> Result<()> {
+        if frame.is_keyframe(bytes) && !frame.is_sequence_header(bytes) {
+            self.keyframes.push((timestamp, self.size));
+        }
+
+        self.size += self.encoder.encode(frame, timestamp, bytes) as u64;
+        self.duration = self.duration.max(timestamp);
+        self.writer.write_all(&self.encoder.flush_to()).await?;
+        Ok(())
+    }
+
+    fn encode_metadata(&self, shift: u64) -> Result<Vec<u8>> {
+        let mut properties = self.metadata.clone();
+        let (times, positions) = self
+            .keyframes
+            .iter()
+            .map(|(time, offset)| {
+                (
+                    Amf0Value::Number(*time as f64 / 1000.0),
+                    Amf0Value::Number((offset + shift) as f64),
+                )
+            })
+            .unzip();
+
+        let mut keyframes = HashMap::new();
+        keyframes.insert("times".to_string(), Amf0Value::StrictArray(times));
+        keyframes.insert(
+            "filepositions".to_string(),
+            Amf0Value::StrictArray(positions),
+        );
+
+        let mut set = |key: &str, value| properties.insert(key.to_string(), value);
+        set("duration", Amf0Value::Number(self.duration as f64 / 1000.0));
+        set("filesize", Amf0Value::Number((self.size + shift) as f64));
+        set(
+            "hasKeyframes",
+            Amf0Value::Boolean(!self.keyframes.is_empty()),
+        );
+        set("hasMetadata", Amf0Value::Boolean(true));
+        set("keyframes", Amf0Value::Object(keyframes));
+
+        Ok(serialize(&vec![
+            Amf0Value::Utf8String("onMetaData".to_string()),
+            Amf0Value::Object(properties),
+        ])?)
+    }
+}
+
+#[async_trait]
+impl RecordFile for FlvFile {
+    fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    fn duration(&self) -> u64 {
+        (self.duration - self.base) as u64
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn set_metadata(&mut self, bytes: &Bytes) {
+        if let Some(metadata) = parse_metadata(bytes) {
+            self.metadata.extend(metadata);
+        }
+    }
+
+    async fn write_header(&mut self, frame: FlvFrame, bytes: &Bytes) -> Result<()> {
+        self.put(frame, self.base, bytes).await
+    }
+
+    async fn write(&mut self, frame: FlvFrame, timestamp: u32, bytes: &Bytes) -> Result<()> {
+        let timestamp = self.base + self.timestamper.normalize(frame, timestamp);
+        self.put(frame, timestamp, bytes).await
+    }
+
+    async fn close(mut self: Box<Self>) -> Result<()> {
+        self.writer.flush().await?;
+
+        // Numbers have a fixed size in amf0, so the size of the metadata does
+        // not depend on the offsets written into it.
+        let shift = self.encode_metadata(0)?.len() as u64 + 15;
+        let metadata = self.encode_metadata(shift)?;
+
+        let mut head = BytesMut::with_capacity(metadata.len() + 28);
+        FlvHeader::Full.encode(&mut head);
+        FlvFrame::Script.encode(&metadata, &mut head, 0);
+
+        let mut source = File::open(&self.part).await?;
+        source.seek(SeekFrom::Start(13)).await?;
+
+        let mut target = BufWriter::new(File::create(&self.path).await?);
+        target.write_all(&head).await?;
+        io::copy(&mut source, &mut target).await?;
+        target.flush().await?;
+
+        fs::remove_file(&self.part).await?;
+        log::info!("record file closed: {:?}", self.path);
+        Ok(())
+    }
+}
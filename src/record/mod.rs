@@ -0,0 +1,336 @@
+mod flv;
+mod mp4;
+
+use crate::{
+    config::{Config, Record, RecordFormat, RecordMode, Timestamp},
+    flv::FlvFrame,
+    router::{Metadata, Payload, Router, RouterEvent, StreamId},
+};
+
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::sync::broadcast::error::RecvError;
+
+pub use self::flv::parse_metadata;
+
+use self::{flv::FlvFile, mp4::Mp4File};
+
+/// A file of a recording in one of the supported formats.
+#[async_trait]
+pub trait RecordFile: Send {
+    fn path(&self) -> &PathBuf;
+    /// The duration of the file in milliseconds.
+    fn duration(&self) -> u64;
+    /// The number of bytes written to the file.
+    fn size(&self) -> u64;
+    /// The `onMetaData` script data of the publisher.
+    fn set_metadata(&mut self, bytes: &Bytes);
+    /// Write a sequence header at the start of the file.
+    async fn write_header(&mut self, frame: FlvFrame, bytes: &Bytes) -> Result<()>;
+    async fn write(&mut self, frame: FlvFrame, timestamp: u32, bytes: &Bytes) -> Result<()>;
+    async fn close(self: Box<Self>) -> Result<()>;
+}
+
+/// Where a stream is recorded, replaces the variables of the path template.
+pub struct Target {
+    pub app: String,
+    pub name: String,
+}
+
+/// Whether a name of the client can be a single component of a path, it
+/// can not name a parent directory or contain a separator.
+fn is_component(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+        && !name.contains(['/', '\\', '\0'])
+}
+
+impl Target {
+    /// The path of a new file, the app and the name come from the client, so
+    /// the path must stay in the directory of the template.
+    pub fn path(&self, template: &str) -> Result<PathBuf> {
+        for name in [&self.app, &self.name] {
+            if !is_component(name) {
+                return Err(anyhow!("invalid record name: {:?}", name));
+            }
+        }
+
+        // The milliseconds keep apart the files split within a second.
+        let date = chrono::Local::now().format("%Y%m%d-%H%M%S-%3f").to_string();
+        let path: PathBuf = template
+            .replace("{app}", &self.app)
+            .replace("{name}", &self.name)
+            .replace("{date}", &date)
+            .into();
+
+        // The directory of the template is the part before the first
+        // variable.
+        let prefix = &template[..template.find('{').unwrap_or(template.len())];
+        let root = match prefix.rfind('/') {
+            Some(index) => Path::new(&prefix[..index]),
+            None => Path::new(""),
+        };
+
+        let inside = path.strip_prefix(root).is_ok_and(|rest| {
+            rest.components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        });
+
+        if !inside {
+            return Err(anyhow!("record path is outside of {:?}: {:?}", root, path));
+        }
+
+        Ok(path)
+    }
+}
+
+/// Records a stream into files, starting a new file when the current one
+/// reaches the configured limits.
+pub struct Recorder {
+    cfg: Record,
+    target: Target,
+    timestamp: Timestamp,
+    append: bool,
+    metadata: Option<Bytes>,
+    headers: [Option<(FlvFrame, Bytes)>; 2],
+    file: Option<Box<dyn RecordFile>>,
+}
+
+impl Recorder {
+    pub fn new(cfg: Record, target: Target, timestamp: Timestamp, append: bool) -> Self {
+        Self {
+            headers: [None, None],
+            metadata: None,
+            file: None,
+            timestamp,
+            append,
+            target,
+            cfg,
+        }
+    }
+
+    fn is_full(&self, frame: FlvFrame, bytes: &[u8]) -> bool {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return false,
+        };
+
+        let full = (self.cfg.max_duration > 0 && file.duration() >= self.cfg.max_duration * 1000)
+            || (self.cfg.max_size > 0 && file.size() >= self.cfg.max_size);
+
+        // Split only at keyframes so every file can be decoded on its own,
+        // unless there is no video at all.
+        full && (frame.is_keyframe(bytes) || self.headers[1].is_none())
+    }
+
+    async fn open(&mut self) -> Result<()> {
+        let path = self.target.path(&self.cfg.path)?;
+        let append = std::mem::take(&mut self.append);
+        let timestamp = self.timestamp.clone();
+        let mut file: Box<dyn RecordFile> = match self.cfg.format {
+            RecordFormat::Flv => Box::new(FlvFile::create(path, timestamp, append).await?),
+            RecordFormat::Mp4 => Box::new(Mp4File::create(path, timestamp, &self.cfg).await?),
+        };
+
+        log::info!("record file opened: {:?}", file.path());
+
+        if let Some(metadata) = &self.metadata {
+            file.set_metadata(metadata);
+        }
+
+        for (frame, bytes) in self.headers.iter().flatten() {
+            file.write_header(*frame, bytes).await?;
+        }
+
+        self.file = Some(file);
+        Ok(())
+    }
+
+    pub async fn write(&mut self, payload: Payload) -> Result<()> {
+        let Payload {
+            frame,
+            timestamp,
+            bytes,
+        } = payload;
+
+        // The metadata of the publisher is merged into the metadata written
+        // when the file is closed.
+        if frame == FlvFrame::Script {
+            if let Some(file) = &mut self.file {
+                file.set_metadata(&bytes);
+            }
+
+            self.metadata = Some(bytes);
+            return Ok(());
+        }
+
+        let header = frame.is_sequence_header(&bytes);
+        if header {
+            let index = if frame == FlvFrame::Audio { 0 } else { 1 };
+            self.headers[index] = Some((frame, bytes.clone()));
+        }
+
+        if self.is_full(frame, &bytes) {
+            if let Some(file) = self.file.take() {
+                file.close().await?;
+            }
+        }
+
+        if self.file.is_none() {
+            self.open().await?;
+            if header {
+                return Ok(());
+            }
+        }
+
+        if let Some(file) = &mut self.file {
+            file.write(frame, timestamp, &bytes).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn close(mut self) -> Result<()> {
+        if let Some(file) = self.file.take() {
+            file.close().await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn fork_record(
+    cfg: Arc<Config>,
+    record: Record,
+    router: Arc<Router>,
+    name: StreamId,
+    metadata: Arc<Metadata>,
+) -> Result<()> {
+    let mut subscriber = router
+        .subscribe(&name)
+        .await
+        .ok_or_else(|| anyhow!("stream is not found"))?;
+
+    // A stream published under the app alone is recorded under the app name.
+    let stream = if name.stream.is_empty() {
+        &name.app
+    } else {
+        &name.stream
+    };
+
+    let target = Target {
+        app: name.app.clone(),
+        name: stream.clone(),
+    };
+
+    let append = metadata.params.get("type").map(|t| t.as_str()) == Some("append");
+    let mut recorder = Recorder::new(record, target, cfg.timestamp.clone(), append);
+    let result = loop {
+        match subscriber.recv().await {
+            Some(RouterEvent::Frame(payload)) => {
+                if let Err(e) = recorder.write(payload).await {
+                    break Err(e);
+                }
+            }
+            Some(RouterEvent::Start(_)) => (),
+            Some(RouterEvent::End) | None => break Ok(()),
+        }
+    };
+
+    // The file is finalized even if a write failed, so that it is not left
+    // as a partial file.
+    let closed = recorder.close().await;
+    result.and(closed)
+}
+
+/// Record the streams of every app that has recording enabled.
+pub fn run(cfg: Arc<Config>, router: Arc<Router>) {
+    tokio::spawn(async move {
+        let mut watcher = router.watch();
+        loop {
+            let (name, metadata) = match watcher.recv().await {
+                Ok(stream) => stream,
+                Err(RecvError::Lagged(count)) => {
+                    log::warn!("recorder missed {} published streams", count);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let app = cfg.app(&name.vhost, &name.app);
+            let record = match app.and_then(|app| app.record.clone()) {
+                Some(record) => record,
+                None => continue,
+            };
+
+            // In publish mode only the publisher can ask for the recording.
+            let kind = metadata.params.get("type").map(|t| t.as_str());
+            if record.mode == RecordMode::Publish && !matches!(kind, Some("record" | "append")) {
+                continue;
+            }
+
+            log::info!("record stream start, name: {}", name);
+
+            let (cfg, router) = (cfg.clone(), router.clone());
+            tokio::spawn(async move {
+                if let Err(e) = fork_record(cfg, record, router, name.clone(), metadata).await {
+                    log::error!("record stream failed, name: {}, err: {}", name, e);
+                } else {
+                    log::info!("record stream stop, name: {}", name);
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(app: &str, name: &str) -> Target {
+        Target {
+            app: app.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn path_replaces_the_variables() {
+        let path = target("live", "cam").path("./records/{app}/{name}.flv");
+        assert_eq!(path.unwrap(), PathBuf::from("./records/live/cam.flv"));
+    }
+
+    #[test]
+    fn path_rejects_names_outside_of_the_directory() {
+        let template = "./records/{app}/{name}/{date}.flv";
+        for (app, name) in [
+            ("live", "../../etc/x"),
+            ("live", ".."),
+            ("..", "cam"),
+            ("live", "a/b"),
+            ("live", "a\\b"),
+            ("live", "/etc"),
+            ("", "cam"),
+        ] {
+            assert!(
+                target(app, name).path(template).is_err(),
+                "{}/{}",
+                app,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn path_dates_are_in_milliseconds() {
+        let path = target("live", "cam").path("{date}").unwrap();
+        assert_eq!(path.to_str().unwrap().len(), "20240101-000000-000".len());
+    }
+}
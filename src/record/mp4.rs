@@ -0,0 +1,314 @@
+use super::RecordFile;
+use crate::{
+    config::{Record, Timestamp},
+    flv::FlvFrame,
+    mp4::{self, Entry, Fragment, Sample, Track},
+    timestamp::Timestamper,
+};
+
+use std::{io::SeekFrom, path::PathBuf};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+
+/// Fragments of audio only streams are cut at this duration in milliseconds.
+const FRAGMENT_DURATION: u64 = 1000;
+
+/// A single mp4 file being recorded.
+///
+/// A progressive file writes the samples into `mdat` of a `.part` file next
+/// to the target path, and the `moov` box is written when the file is
+/// closed, either after `mdat`, or in front of it for faststart. A
+/// fragmented file is written directly to the target path, the init segment
+/// is followed by one `moof` and `mdat` per group of pictures.
+pub struct Mp4File {
+    path: PathBuf,
+    part: PathBuf,
+    writer: BufWriter<File>,
+    timestamper: Timestamper,
+    fragmented: bool,
+    faststart: bool,
+    /// The video track is at index 0 and the audio track at index 1.
+    tracks: [Option<Track>; 2],
+    entries: [Vec<Entry>; 2],
+    /// The last sample of every track waits for the next sample to know its
+    /// duration.
+    pending: [Option<Sample>; 2],
+    queue: [Vec<Sample>; 2],
+    started: bool,
+    initialized: bool,
+    mdat: u64,
+    sequence: u32,
+    duration: u64,
+    size: u64,
+}
+
+impl Mp4File {
+    pub async fn create(path: PathBuf, timestamp: Timestamp, cfg: &Record) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+        let part = PathBuf::from(part);
+
+        let writer =
+            BufWriter::new(File::create(if cfg.fragmented { &path } else { &part }).await?);
+        let mut file = Self {
+            timestamper: Timestamper::new(timestamp),
+            fragmented: cfg.fragmented,
+            faststart: cfg.faststart,
+            tracks: [None, None],
+            entries: [Vec::new(), Vec::new()],
+            pending: [None, None],
+            queue: [Vec::new(), Vec::new()],
+            started: false,
+            initialized: false,
+            mdat: 0,
+            sequence: 0,
+            duration: 0,
+            size: 0,
+            writer,
+            part,
+            path,
+        };
+
+        // A progressive file starts with a 64 bit `mdat` header, the size is
+        // written when the file is closed.
+        if !file.fragmented {
+            let mut buf = BytesMut::new();
+            mp4::ftyp(&mut buf, false);
+            file.mdat = buf.len() as u64;
+            buf.extend_from_slice(&1u32.to_be_bytes());
+            buf.extend_from_slice(b"mdat");
+            buf.extend_from_slice(&0u64.to_be_bytes());
+            file.put(&buf).await?;
+        }
+
+        Ok(file)
+    }
+
+    async fn put(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer.write_all(buf).await?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    fn index(frame: FlvFrame) -> usize {
+        if frame == FlvFrame::Video {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn set_track(&mut self, frame: FlvFrame, bytes: &Bytes) -> Result<()> {
+        let index = Self::index(frame);
+        if self.tracks[index].is_some() {
+            return Ok(());
+        }
+
+        // The tracks of a fragmented file are fixed by the init segment.
+        if self.initialized {
+            log::warn!("record mp4 track added after the start: {:?}", self.path);
+            return Ok(());
+        }
+
+        self.tracks[index] = Track::from_flv(index as u32 + 1, frame, bytes)?;
+        Ok(())
+    }
+
+    async fn push(&mut self, index: usize, sample: Sample) -> Result<()> {
+        if !self.fragmented {
+            self.entries[index].push(Entry {
+                duration: sample.duration,
+                size: sample.data.len() as u32,
+                sync: sample.sync,
+                cts: sample.cts,
+                offset: self.size,
+            });
+
+            return self.put(&sample.data).await;
+        }
+
+        // A new fragment starts at every video keyframe, or after a fixed
+        // duration if there is no video.
+        let flush = if self.tracks[0].is_some() {
+            index == 0 && sample.sync
+        } else {
+            self.queue[index]
+                .first()
+                .map(|first| sample.dts - first.dts >= FRAGMENT_DURATION)
+                .unwrap_or(false)
+        };
+
+        if flush {
+            self.flush().await?;
+        }
+
+        self.queue[index].push(sample);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if self.queue.iter().all(|q| q.is_empty()) {
+            return Ok(());
+        }
+
+        let mut buf = BytesMut::new();
+        if !self.initialized {
+            self.initialized = true;
+            let tracks = self
+                .tracks
+                .iter()
+                .flatten()
+                .map(|t| (t, &[][..]))
+                .collect::<Vec<_>>();
+            mp4::ftyp(&mut buf, true);
+            mp4::moov(&mut buf, &tracks, true, 0);
+        }
+
+        self.sequence += 1;
+        let fragments = self
+            .tracks
+            .iter()
+            .zip(self.queue.iter())
+            .filter_map(|(track, samples)| Some((track.as_ref()?, samples)))
+            .filter(|(_, samples)| !samples.is_empty())
+            .map(|(track, samples)| Fragment { track, samples })
+            .collect::<Vec<_>>();
+
+        mp4::moof(&mut buf, self.sequence, &fragments);
+        self.queue.iter_mut().for_each(|q| q.clear());
+        self.put(&buf).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RecordFile for Mp4File {
+    fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    fn duration(&self) -> u64 {
+        self.duration
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn set_metadata(&mut self, _: &Bytes) {}
+
+    async fn write_header(&mut self, frame: FlvFrame, bytes: &Bytes) -> Result<()> {
+        self.set_track(frame, bytes)
+    }
+
+    async fn write(&mut self, frame: FlvFrame, timestamp: u32, bytes: &Bytes) -> Result<()> {
+        if frame.is_sequence_header(bytes) {
+            return self.set_track(frame, bytes);
+        }
+
+        let index = Self::index(frame);
+        if self.tracks[index].is_none() {
+            return Ok(());
+        }
+
+        let timestamp = self.timestamper.normalize(frame, timestamp) as u64;
+        let sample = match Sample::from_flv(frame, timestamp, bytes) {
+            Some(sample) => sample,
+            None => return Ok(()),
+        };
+
+        // The file starts at a keyframe, unless there is no video.
+        if !self.started {
+            if self.tracks[0].is_some() && !(frame == FlvFrame::Video && sample.sync) {
+                return Ok(());
+            }
+
+            self.started = true;
+        }
+
+        self.duration = self.duration.max(timestamp);
+        if let Some(mut last) = self.pending[index].replace(sample) {
+            last.duration = (timestamp - last.dts) as u32;
+            self.push(index, last).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn close(mut self: Box<Self>) -> Result<()> {
+        // The last sample of a track gets the duration of the sample before.
+        for index in 0..2 {
+            if let Some(mut last) = self.pending[index].take() {
+                last.duration = match self.fragmented {
+                    true => self.queue[index].last().map(|s| s.duration),
+                    false => self.entries[index].last().map(|e| e.duration),
+                }
+                .unwrap_or(0);
+                self.push(index, last).await?;
+            }
+        }
+
+        if self.fragmented {
+            self.flush().await?;
+            log::info!("record file closed: {:?}", self.path);
+            return Ok(());
+        }
+
+        self.writer.flush().await?;
+
+        let mdat = self.mdat;
+        let file = self.writer.get_mut();
+        file.seek(SeekFrom::Start(mdat + 8)).await?;
+        file.write_all(&(self.size - mdat).to_be_bytes()).await?;
+        file.seek(SeekFrom::End(0)).await?;
+
+        let tracks = self
+            .tracks
+            .iter()
+            .zip(self.entries.iter())
+            .filter_map(|(track, entries)| Some((track.as_ref()?, &entries[..])))
+            .collect::<Vec<_>>();
+
+        // The size of the `moov` box does not depend on the chunk offsets,
+        // they are always 64 bits.
+        let mut moov = BytesMut::new();
+        if !self.faststart {
+            mp4::moov(&mut moov, &tracks, false, 0);
+            file.write_all(&moov).await?;
+            file.flush().await?;
+            fs::rename(&self.part, &self.path).await?;
+        } else {
+            mp4::moov(&mut moov, &tracks, false, 0);
+            let shift = moov.len() as u64;
+            moov.clear();
+            mp4::moov(&mut moov, &tracks, false, shift);
+
+            let mut head = BytesMut::new();
+            mp4::ftyp(&mut head, false);
+            head.extend_from_slice(&moov);
+
+            let mut source = File::open(&self.part).await?;
+            source.seek(SeekFrom::Start(mdat)).await?;
+
+            let mut target = BufWriter::new(File::create(&self.path).await?);
+            target.write_all(&head).await?;
+            io::copy(&mut source, &mut target).await?;
+            target.flush().await?;
+            fs::remove_file(&self.part).await?;
+        }
+
+        log::info!("record file closed: {:?}", self.path);
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+mod pull;
+mod push;
+
+pub use self::pull::fork_pull;
+
+use crate::{config::Config, router::Router};
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::broadcast::error::RecvError;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Push the streams of every app that has push urls configured, and pull
+/// the streams of the apps that have a pull url on demand.
+pub fn run(cfg: Arc<Config>, router: Arc<Router>) {
+    router.set_source(Arc::new(pull::Puller::new(
+        cfg.clone(),
+        Arc::downgrade(&router),
+    )));
+
+    tokio::spawn(async move {
+        let mut watcher = router.watch();
+        loop {
+            let (name, _) = match watcher.recv().await {
+                Ok(stream) => stream,
+                Err(RecvError::Lagged(count)) => {
+                    log::warn!("relay missed {} published streams", count);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+
+            let urls = match cfg.app(&name.vhost, &name.app) {
+                Some(app) => app.push.clone(),
+                None => continue,
+            };
+
+            for url in urls {
+                let url = url.replace("{stream}", &name.stream);
+                log::info!("rtmp push start, name: {}, url: {}", name, url);
+
+                let (name, router) = (name.clone(), router.clone());
+                let timestamp = cfg.timestamp.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = push::fork_push(&url, &name, router, timestamp).await {
+                        log::error!("rtmp push failed, name: {}, url: {}, err: {}", name, url, e);
+                    } else {
+                        log::info!("rtmp push stop, name: {}, url: {}", name, url);
+                    }
+                });
+            }
+        }
+    });
+}
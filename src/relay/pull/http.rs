@@ -0,0 +1,50 @@
+use super::{Publisher, CONNECT_TIMEOUT};
+use crate::flv::FlvDecoder;
+
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Result};
+use hyper::{body::HttpBody, Client, StatusCode, Uri};
+use tokio::time::{interval, timeout};
+
+/// Play the stream from an http flv server, returns when the stream has been
+/// idle, or has been removed from the router.
+pub async fn pull(url: &str, publisher: &mut Publisher<'_>) -> Result<()> {
+    let uri: Uri = url.parse()?;
+    let response = timeout(CONNECT_TIMEOUT, Client::new().get(uri))
+        .await
+        .map_err(|_| anyhow!("connect timeout"))??;
+
+    ensure!(
+        response.status() == StatusCode::OK,
+        "unexpected http status: {}",
+        response.status()
+    );
+
+    log::info!("http flv pull playing, url: {}", url);
+    publisher.start(url).await?;
+
+    let mut body = response.into_body();
+    let mut decoder = FlvDecoder::default();
+    let mut ticker = interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            chunk = body.data() => {
+                let chunk = chunk.ok_or_else(|| anyhow!("connection closed by the server"))??;
+                decoder.extend(&chunk);
+
+                while let Some(tag) = decoder.decode()? {
+                    if !publisher.send(tag).await {
+                        return Ok(());
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                if publisher.is_idle().await {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
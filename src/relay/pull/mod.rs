@@ -0,0 +1,450 @@
+mod http;
+mod rtmp;
+mod srt;
+
+use super::{CONNECT_TIMEOUT, MAX_BACKOFF, MIN_BACKOFF};
+use crate::{
+    config::Config,
+    flv::FlvTag,
+    router::{Metadata, Router, RouterSender, RouterSource, StreamId},
+};
+
+use std::{
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use ahash::AHashSet;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{sleep, timeout, Instant},
+};
+
+/// The gap between two origins when the last frame duration is unknown.
+const FRAME_DURATION: u64 = 40;
+
+/// Publishes a pulled stream to the router once the first origin starts
+/// sending it, the stream stays published when the puller fails over to
+/// another origin.
+///
+/// The timestamps continue across origins, so the subscribers see no
+/// discontinuity, and the headers of a new origin replace the cached ones.
+pub struct Publisher<'a> {
+    name: &'a StreamId,
+    router: &'a Router,
+    sender: Option<RouterSender>,
+    idle: Option<Duration>,
+    idle_since: Option<Instant>,
+    /// The stream time in milliseconds at which the current origin starts.
+    base: u64,
+    /// The first timestamp of the current origin.
+    first: Option<u32>,
+    last: u64,
+    delta: u64,
+}
+
+impl<'a> Publisher<'a> {
+    fn new(name: &'a StreamId, router: &'a Router, idle: Option<Duration>) -> Self {
+        Self {
+            sender: None,
+            idle_since: None,
+            base: 0,
+            first: None,
+            last: 0,
+            delta: FRAME_DURATION,
+            router,
+            name,
+            idle,
+        }
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Publish the stream if it has not been published yet, or continue it
+    /// with the frames of another origin.
+    pub async fn start(&mut self, source: &str) -> Result<()> {
+        if let Some(sender) = &mut self.sender {
+            log::info!(
+                "pull switch origin, name: {}, source: {}",
+                self.name,
+                source
+            );
+            sender.reset();
+            self.base = self.last + self.delta;
+            self.first = None;
+            return Ok(());
+        }
+
+        let mut metadata = Metadata::new(source);
+        metadata
+            .params
+            .insert("type".to_string(), "live".to_string());
+
+        let sender = self.router.publish(self.name, metadata);
+        self.sender = Some(sender.ok_or_else(|| anyhow!("stream is already published"))?);
+        Ok(())
+    }
+
+    /// Send a tag of the pulled stream, returns `false` if the stream has been
+    /// removed from the router.
+    pub async fn send(&mut self, tag: FlvTag) -> bool {
+        let sender = match &mut self.sender {
+            Some(sender) => sender,
+            None => return true,
+        };
+
+        // The stream time of a tag is its offset from the first tag of the
+        // origin.
+        let first = *self.first.get_or_insert(tag.timestamp);
+        let position = self.base + tag.timestamp.saturating_sub(first) as u64;
+        if position > self.last {
+            self.delta = position - self.last;
+            self.last = position;
+        }
+
+        sender
+            .send(tag.frame, position as u32, tag.data)
+            .await
+            .is_some()
+    }
+
+    /// Whether the stream has had no subscribers for the idle timeout, this
+    /// is checked periodically, a stream pulled for as long as the process
+    /// runs is never idle.
+    pub async fn is_idle(&mut self) -> bool {
+        let idle = match self.idle {
+            Some(idle) if self.sender.is_some() => idle,
+            _ => return false,
+        };
+
+        if self.router.subscribers(self.name) != Some(0) {
+            self.idle_since = None;
+            return false;
+        }
+
+        self.idle_since.get_or_insert_with(Instant::now).elapsed() >= idle
+    }
+}
+
+async fn pull(url: &str, publisher: &mut Publisher<'_>) -> Result<()> {
+    if url.starts_with("rtmp://") {
+        rtmp::pull(url, publisher).await
+    } else if url.starts_with("http://") {
+        http::pull(url, publisher).await
+    } else if url.starts_with("srt://") {
+        srt::pull(url, publisher).await
+    } else {
+        Err(anyhow!("unsupported pull url: {}", url))
+    }
+}
+
+/// Pull the stream from the first origin that works, and fail over to the
+/// next one when it fails.
+///
+/// A stream pulled on demand stops when it has been idle, or when none of
+/// the origins could be reached before it was published. Otherwise the
+/// origins are retried with an exponential backoff.
+async fn pull_origins(
+    urls: &[String],
+    name: &StreamId,
+    router: &Router,
+    idle: Option<Duration>,
+) -> Result<()> {
+    let mut publisher = Publisher::new(name, router, idle);
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        for url in urls {
+            let start = Instant::now();
+            match pull(url, &mut publisher).await {
+                Ok(()) => return Ok(()),
+                Err(e) => log::warn!("pull failed, name: {}, url: {}, err: {}", name, url, e),
+            }
+
+            // A connection that stayed up for a while resets the backoff.
+            if start.elapsed() > MAX_BACKOFF {
+                backoff = MIN_BACKOFF;
+            }
+        }
+
+        if idle.is_some() && !publisher.is_started() {
+            return Err(anyhow!("no origin is available"));
+        }
+
+        if publisher.is_idle().await {
+            return Ok(());
+        }
+
+        log::warn!("pull retry in {:?}, name: {}", backoff, name);
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Pull the stream for as long as the process runs.
+pub async fn fork_pull(urls: &[String], name: &StreamId, router: Arc<Router>) -> Result<()> {
+    pull_origins(urls, name, &router, None).await
+}
+
+/// Pulls the streams of the apps that have origins configured when they are
+/// first subscribed to.
+pub struct Puller {
+    cfg: Arc<Config>,
+    router: Weak<Router>,
+    pulling: Arc<Mutex<AHashSet<StreamId>>>,
+}
+
+impl Puller {
+    pub fn new(cfg: Arc<Config>, router: Weak<Router>) -> Self {
+        Self {
+            pulling: Arc::default(),
+            router,
+            cfg,
+        }
+    }
+}
+
+#[async_trait]
+impl RouterSource for Puller {
+    async fn request(&self, name: &StreamId) -> bool {
+        let app = self.cfg.app(&name.vhost, &name.app);
+        let (pull, router) = match (app, self.router.upgrade()) {
+            (Some(app), Some(router)) => match &app.pull {
+                Some(pull) => (pull.clone(), router),
+                None => return false,
+            },
+            _ => return false,
+        };
+
+        // Watch before starting, so the publish of the pulled stream is not
+        // missed.
+        let mut watcher = router.watch();
+        if router.subscribers(name).is_some() {
+            return true;
+        }
+
+        // Concurrent requests for the same stream share a single pull.
+        if self.pulling.lock().unwrap().insert(name.clone()) {
+            let urls = pull
+                .urls
+                .iter()
+                .map(|url| url.replace("{stream}", &name.stream))
+                .collect::<Vec<_>>();
+            log::info!("pull start, name: {}, urls: {:?}", name, urls);
+
+            let (name, pulling) = (name.clone(), self.pulling.clone());
+            let router = router.clone();
+            tokio::spawn(async move {
+                let idle = Some(Duration::from_secs(pull.idle_timeout));
+                let result = pull_origins(&urls, &name, &router, idle).await;
+
+                pulling.lock().unwrap().remove(&name);
+                if let Err(e) = result {
+                    log::error!("pull failed, name: {}, err: {}", name, e);
+                } else {
+                    log::info!("pull stop, name: {}", name);
+                }
+            });
+        }
+
+        let published = async {
+            loop {
+                match watcher.recv().await {
+                    Ok((published, _)) if &published == name => return true,
+                    Err(RecvError::Closed) => return false,
+                    _ => (),
+                }
+            }
+        };
+
+        timeout(CONNECT_TIMEOUT, published).await.unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::avc::AvcConfig,
+        config::Timestamp,
+        flv::FlvFrame,
+        router::{RouterEvent, RouterSubscriber},
+        server,
+    };
+
+    use bytes::{BufMut, Bytes, BytesMut};
+    use tokio::task::JoinHandle;
+
+    const SPS: [u8; 24] = [
+        0x67, 0x42, 0xc0, 0x1e, 0xda, 0x02, 0x80, 0xbf, 0xe5, 0x84, 0x00, 0x00, 0x03, 0x00, 0x04,
+        0x00, 0x00, 0x03, 0x00, 0xca, 0x3c, 0x58, 0xba, 0x80,
+    ];
+
+    /// A video sequence header, the origins are told apart by the last byte
+    /// of the pps.
+    fn header(pps: u8) -> Bytes {
+        let mut header = BytesMut::from(&[0x17, 0, 0, 0, 0][..]);
+        header.put_slice(&AvcConfig::encode(&SPS, &[0x68, 0xce, 0x3c, pps]));
+        header.freeze()
+    }
+
+    fn keyframe(index: u32) -> Bytes {
+        let mut frame = BytesMut::from(&[0x17, 1, 0, 0, 0][..]);
+        frame.put_u32(4);
+        frame.put_slice(&[0x65, 0x88, 0x84, index as u8]);
+        frame.freeze()
+    }
+
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    /// Start a second instance with an rtmp and an http flv listener, as the
+    /// origin of the pulled streams. Returns its router and the ports.
+    async fn origin() -> (Arc<Router>, u16, u16) {
+        let (rtmp, http) = (free_port(), free_port());
+        let cfg = format!(
+            "[proto.rtmp]\nlisten = \"127.0.0.1:{}\"\n[proto.http_flv]\nlisten = \"127.0.0.1:{}\"\n",
+            rtmp, http
+        );
+
+        let router = Arc::new(Router::new(Timestamp::default(), []));
+        server::run(Arc::new(toml::from_str(&cfg).unwrap()), router.clone());
+        sleep(Duration::from_millis(100)).await;
+        (router, rtmp, http)
+    }
+
+    /// Publish `live/test` on an origin, a keyframe every 40 ms from the
+    /// timestamp, until the task is aborted.
+    fn publish(router: Arc<Router>, pps: u8, start: u32) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let name = StreamId::new("", "live", "test");
+            let mut sender = router.publish(&name, Metadata::default()).unwrap();
+            sender.send(FlvFrame::Video, start, header(pps)).await;
+            for index in 1.. {
+                let timestamp = start + index * 40;
+                sender
+                    .send(FlvFrame::Video, timestamp, keyframe(index))
+                    .await;
+                sleep(Duration::from_millis(40)).await;
+            }
+        })
+    }
+
+    /// Receive the next frame of the stream, the header or a keyframe.
+    async fn frame(subscriber: &mut RouterSubscriber) -> (u32, Bytes) {
+        loop {
+            match subscriber.recv().await {
+                Some(RouterEvent::Frame(payload)) => return (payload.timestamp, payload.bytes),
+                Some(RouterEvent::Start(_)) => (),
+                Some(RouterEvent::End) | None => panic!("the pulled stream ended"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn pulls_on_demand_and_stops_when_idle() {
+        let (origin, port, _) = origin().await;
+        let _publisher = publish(origin, 0x80, 0);
+
+        let cfg = format!(
+            "[apps.edge.pull]\nurls = [\"rtmp://127.0.0.1:{}/live/{{stream}}\"]\nidle_timeout = 1\n",
+            port
+        );
+        let edge = Arc::new(Router::new(Timestamp::default(), []));
+        let puller = Puller::new(
+            Arc::new(toml::from_str(&cfg).unwrap()),
+            Arc::downgrade(&edge),
+        );
+        edge.set_source(Arc::new(puller));
+
+        let name = StreamId::new("", "edge", "test");
+        let receive = async {
+            let mut subscriber = edge
+                .subscribe(&name)
+                .await
+                .expect("the stream is not pulled");
+            loop {
+                let (_, bytes) = frame(&mut subscriber).await;
+                if bytes[1] == 1 {
+                    return;
+                }
+            }
+        };
+
+        let received = timeout(Duration::from_secs(5), receive).await;
+        assert!(received.is_ok(), "no keyframe is pulled");
+
+        // The subscriber is gone, the pull stops after the idle timeout.
+        let idle = async {
+            while edge.subscribers(&name).is_some() {
+                sleep(Duration::from_millis(100)).await;
+            }
+        };
+
+        assert!(timeout(Duration::from_secs(5), idle).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn fails_over_with_continuous_timestamps_and_new_headers() {
+        let (first, _, first_port) = origin().await;
+        let (second, _, second_port) = origin().await;
+        let first = publish(first, 0x80, 5000);
+        let _second = publish(second, 0x81, 0);
+
+        let edge = Arc::new(Router::new(Timestamp::default(), []));
+        let name = StreamId::new("", "edge", "test");
+        let urls = [first_port, second_port]
+            .map(|port| format!("http://127.0.0.1:{}/live/test.flv", port))
+            .to_vec();
+        tokio::spawn({
+            let (edge, name) = (edge.clone(), name.clone());
+            async move { pull_origins(&urls, &name, &edge, None).await }
+        });
+
+        let receive = async {
+            let mut subscriber = loop {
+                match edge.subscribe(&name).await {
+                    Some(subscriber) => break subscriber,
+                    None => sleep(Duration::from_millis(10)).await,
+                }
+            };
+
+            let (_, bytes) = frame(&mut subscriber).await;
+            assert_eq!(bytes, header(0x80));
+
+            let mut last = 0;
+            for _ in 0..5 {
+                last = frame(&mut subscriber).await.0;
+            }
+
+            // The first origin fails, the stream goes on with the second one.
+            first.abort();
+            loop {
+                let (timestamp, bytes) = frame(&mut subscriber).await;
+                assert!(timestamp >= last, "{} is before {}", timestamp, last);
+                assert!(timestamp - last < 1000, "{} jumps from {}", timestamp, last);
+                last = timestamp;
+                if bytes == header(0x81) {
+                    break;
+                }
+            }
+
+            let (timestamp, _) = frame(&mut subscriber).await;
+            assert!(timestamp >= last && timestamp - last < 1000);
+
+            // A new subscriber gets the headers of the second origin.
+            let mut subscriber = edge.subscribe(&name).await.unwrap();
+            let (_, bytes) = frame(&mut subscriber).await;
+            assert_eq!(bytes, header(0x81));
+        };
+
+        let received = timeout(Duration::from_secs(10), receive).await;
+        assert!(received.is_ok(), "the pull does not fail over");
+    }
+}
@@ -0,0 +1,58 @@
+use super::{Publisher, CONNECT_TIMEOUT};
+use crate::proto::rtmp::{ClientMode, RtmpClient, RtmpUrl};
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{interval, timeout},
+};
+
+/// Play the stream from an rtmp server, returns when the stream has been
+/// idle, or has been removed from the router.
+pub async fn pull(url: &str, publisher: &mut Publisher<'_>) -> Result<()> {
+    let url = RtmpUrl::parse(url)?;
+    let mut socket = timeout(CONNECT_TIMEOUT, TcpStream::connect(&url.addr))
+        .await
+        .map_err(|_| anyhow!("connect timeout"))??;
+
+    let mut client = RtmpClient::new(url.clone(), ClientMode::Play);
+    socket.write_all(&client.start()?).await?;
+
+    let mut ticker = interval(Duration::from_secs(1));
+    let mut buf = [0u8; 5120];
+
+    loop {
+        tokio::select! {
+            size = socket.read(&mut buf) => {
+                let size = size?;
+                if size == 0 {
+                    return Err(anyhow!("connection closed by the server"));
+                }
+
+                let ready = client.is_ready();
+                let bytes = client.process(&buf[..size])?;
+                socket.write_all(&bytes).await?;
+
+                if !ready && client.is_ready() {
+                    log::info!("rtmp pull playing, url: {}", url.tc_url());
+                    let source = format!("{}/{}", url.tc_url(), url.stream);
+                    publisher.start(&source).await?;
+                }
+
+                for tag in client.tags() {
+                    if !publisher.send(tag).await {
+                        return Ok(());
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                if publisher.is_idle().await {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use super::{Publisher, CONNECT_TIMEOUT};
+use crate::{proto::srt::SrtUrl, ts::TsDecoder};
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures_util::TryStreamExt;
+use srt_tokio::SrtSocket;
+use tokio::time::{interval, timeout};
+
+/// Play the stream from an srt listener in caller mode, returns when the
+/// stream has been idle, or has been removed from the router.
+pub async fn pull(url: &str, publisher: &mut Publisher<'_>) -> Result<()> {
+    let srt = SrtUrl::parse(url)?;
+    let mut builder = SrtSocket::builder();
+    if let Some(latency) = srt.latency {
+        builder = builder.latency(latency);
+    }
+
+    if let Some(passphrase) = &srt.passphrase {
+        builder = builder.encryption(16, passphrase.as_str());
+    }
+
+    let mut socket = timeout(
+        CONNECT_TIMEOUT,
+        builder.call(srt.addr.as_str(), srt.stream_id.as_deref()),
+    )
+    .await
+    .map_err(|_| anyhow!("connect timeout"))??;
+
+    log::info!("srt pull playing, url: {}", url);
+    publisher.start(url).await?;
+
+    let mut decoder = TsDecoder::default();
+    let mut ticker = interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            message = socket.try_next() => {
+                let (_, buf) = message?.ok_or_else(|| anyhow!("connection closed by the server"))?;
+                decoder.extend(&buf);
+
+                for tag in decoder.decode()? {
+                    if !publisher.send(tag).await {
+                        return Ok(());
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                if publisher.is_idle().await {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
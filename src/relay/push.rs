@@ -0,0 +1,237 @@
+use super::{CONNECT_TIMEOUT, MAX_BACKOFF, MIN_BACKOFF};
+use crate::{
+    config,
+    proto::rtmp::{ClientMode, RtmpClient, RtmpUrl},
+    router::{Router, RouterEvent, StreamId},
+    timestamp::Timestamper,
+};
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{sleep, timeout, Instant},
+};
+
+/// Connect to the url and publish, returns once the server has accepted the
+/// publish.
+async fn connect(url: &RtmpUrl) -> Result<(TcpStream, RtmpClient)> {
+    let mut socket = TcpStream::connect(&url.addr).await?;
+    let mut client = RtmpClient::new(url.clone(), ClientMode::Publish);
+    socket.write_all(&client.start()?).await?;
+
+    let mut buf = [0u8; 5120];
+    while !client.is_ready() {
+        let size = socket.read(&mut buf).await?;
+        if size == 0 {
+            return Err(anyhow!("connection closed by the server"));
+        }
+
+        let bytes = client.process(&buf[..size])?;
+        socket.write_all(&bytes).await?;
+    }
+
+    Ok((socket, client))
+}
+
+/// Push the stream to the url, returns when the stream ends. The stream is
+/// only subscribed once the server is publishing, so that a slow server does
+/// not hold up the stream.
+async fn push(
+    url: &RtmpUrl,
+    router: &Router,
+    name: &StreamId,
+    timestamp: config::Timestamp,
+) -> Result<()> {
+    let (mut socket, mut client) = timeout(CONNECT_TIMEOUT, connect(url))
+        .await
+        .map_err(|_| anyhow!("connect timeout"))??;
+
+    log::info!("rtmp push publishing, url: {}", url.tc_url());
+    let mut subscriber = match router.subscribe_relay(name) {
+        Some(subscriber) => subscriber,
+        None => return Ok(()),
+    };
+
+    let mut timestamper = Timestamper::new(timestamp);
+    let mut buf = [0u8; 5120];
+    loop {
+        tokio::select! {
+            size = socket.read(&mut buf) => {
+                let size = size?;
+                if size == 0 {
+                    return Err(anyhow!("connection closed by the server"));
+                }
+
+                let bytes = client.process(&buf[..size])?;
+                socket.write_all(&bytes).await?;
+            }
+            event = subscriber.recv() => match event {
+                Some(RouterEvent::Frame(payload)) => {
+                    let timestamp = timestamper.normalize(payload.frame, payload.timestamp);
+                    let bytes = client.send(payload.frame, timestamp, payload.bytes)?;
+                    socket.write_all(&bytes).await?;
+                }
+                Some(RouterEvent::Start(metadata)) => log::info!(
+                    "rtmp push stream start, url: {}, source: {}",
+                    url.tc_url(),
+                    metadata.source
+                ),
+                Some(RouterEvent::End) | None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Push the stream to the url until the stream ends, the connection is
+/// retried with an exponential backoff.
+pub async fn fork_push(
+    url: &str,
+    name: &StreamId,
+    router: Arc<Router>,
+    timestamp: config::Timestamp,
+) -> Result<()> {
+    let url = RtmpUrl::parse(url)?;
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        if router.subscribers(name).is_none() {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+        match push(&url, &router, name, timestamp.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) => log::warn!(
+                "rtmp push disconnected, url: {}, err: {}, retry in {:?}",
+                url.tc_url(),
+                e,
+                backoff
+            ),
+        }
+
+        // A connection that stayed up for a while resets the backoff.
+        if start.elapsed() > MAX_BACKOFF {
+            backoff = MIN_BACKOFF;
+        }
+
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::avc::AvcConfig,
+        config::Config,
+        flv::FlvFrame,
+        router::{Metadata, RouterSubscriber},
+        server,
+    };
+
+    use std::time::Duration;
+
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    const SPS: [u8; 24] = [
+        0x67, 0x42, 0xc0, 0x1e, 0xda, 0x02, 0x80, 0xbf, 0xe5, 0x84, 0x00, 0x00, 0x03, 0x00, 0x04,
+        0x00, 0x00, 0x03, 0x00, 0xca, 0x3c, 0x58, 0xba, 0x80,
+    ];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+
+    fn header() -> Bytes {
+        let mut header = BytesMut::from(&[0x17, 0, 0, 0, 0][..]);
+        header.put_slice(&AvcConfig::encode(&SPS, &PPS));
+        header.freeze()
+    }
+
+    fn keyframe(index: u32) -> Bytes {
+        let mut frame = BytesMut::from(&[0x17, 1, 0, 0, 0][..]);
+        frame.put_u32(4);
+        frame.put_slice(&[0x65, 0x88, 0x84, index as u8]);
+        frame.freeze()
+    }
+
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    /// Start a server with only an rtmp listener on the port.
+    async fn upstream(port: u16, router: Arc<Router>) {
+        let cfg = format!("[proto.rtmp]\nlisten = \"127.0.0.1:{}\"\n", port);
+        let cfg: Config = toml::from_str(&cfg).unwrap();
+        server::run(Arc::new(cfg), router);
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    async fn subscribe(router: &Router, name: &StreamId) -> RouterSubscriber {
+        loop {
+            if let Some(subscriber) = router.subscribe(name).await {
+                return subscriber;
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn fork_push_reconnects_and_publishes_to_the_upstream() {
+        let remote = Arc::new(Router::new(config::Timestamp::default(), []));
+        let port = free_port();
+
+        let router = Arc::new(Router::new(config::Timestamp::default(), []));
+        let name = StreamId::new("", "live", "test");
+        let mut sender = router.publish(&name, Metadata::default()).unwrap();
+        sender.send(FlvFrame::Video, 0, header()).await.unwrap();
+
+        let url = format!("rtmp://127.0.0.1:{}/live/test", port);
+        let push = tokio::spawn({
+            let (router, name) = (router.clone(), name.clone());
+            async move { fork_push(&url, &name, router, config::Timestamp::default()).await }
+        });
+
+        // The upstream is not listening yet, the push retries after the
+        // backoff.
+        sleep(Duration::from_millis(200)).await;
+        assert!(!push.is_finished());
+        upstream(port, remote.clone()).await;
+
+        let receive = async {
+            let mut subscriber = subscribe(&remote, &name).await;
+
+            // The push is a relay, it is not a viewer of the local stream.
+            assert_eq!(router.subscribers(&name), Some(0));
+
+            let mut index = 0;
+            loop {
+                index += 1;
+                sender
+                    .send(FlvFrame::Video, index * 40, keyframe(index))
+                    .await
+                    .unwrap();
+
+                let event = timeout(Duration::from_millis(100), subscriber.recv()).await;
+                if let Ok(Some(RouterEvent::Frame(payload))) = event {
+                    if payload.bytes[1] == 1 {
+                        return payload.bytes;
+                    }
+                }
+            }
+        };
+
+        let frame = timeout(Duration::from_secs(5), receive).await;
+        let frame = frame.expect("no frame is pushed to the upstream");
+        assert_eq!(&frame[..5], &[0x17, 1, 0, 0, 0]);
+        assert_eq!(frame.len(), 13);
+
+        // The push ends with the stream.
+        drop(sender);
+        let ended = timeout(Duration::from_secs(5), push).await;
+        assert!(ended.unwrap().unwrap().is_ok());
+    }
+}
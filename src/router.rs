@@ -1,59 +1,338 @@
-use crate::flv::{FlvEncoer, FlvFrame, FlvHeader};
+use crate::{
+    config,
+    flv::{FlvEncoer, FlvFrame, FlvHeader},
+    mp4::{Fmp4Encoder, Fragmentation},
+    timestamp::Timestamper,
+    ts::TsEncoder,
+};
 
 use std::{
-    net::SocketAddr,
-    sync::Arc,
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock, RwLock,
+    },
     task::{Context, Poll},
 };
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
+use async_trait::async_trait;
 use bytes::Bytes;
-use tokio::sync::{mpsc::{channel, Receiver, Sender}, RwLock};
+use tokio::sync::{
+    broadcast,
+    mpsc::{channel, error::TrySendError, Receiver, Sender},
+};
+
+/// The number of frames queued for a subscriber, a subscriber that falls
+/// further behind skips frames until the next keyframe.
+const SUBSCRIBER_QUEUE: usize = 1024;
+
+/// The identity of a stream, the streams of different virtual hosts and apps
+/// are separate even if they have the same name.
+///
+/// The default virtual host is empty, it has the streams of every host that
+/// is not configured as a virtual host.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct StreamId {
+    pub vhost: String,
+    pub app: String,
+    pub stream: String,
+}
+
+impl StreamId {
+    pub fn new(vhost: impl Into<String>, app: &str, stream: &str) -> Self {
+        Self {
+            vhost: vhost.into(),
+            app: app.to_string(),
+            stream: stream.to_string(),
+        }
+    }
+
+    /// Parse a path of `app[/stream]`, the stream name may contain slashes.
+    pub fn from_path(vhost: impl Into<String>, path: &str) -> Self {
+        let path = path.trim_start_matches('/');
+        let (app, stream) = path.split_once('/').unwrap_or((path, ""));
+        Self::new(vhost, app, stream)
+    }
+}
+
+impl fmt::Display for StreamId {
+    /// Formats as `[vhost/]app[/stream]`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.vhost.is_empty() {
+            write!(f, "{}/", self.vhost)?;
+        }
+
+        f.write_str(&self.app)?;
+        if !self.stream.is_empty() {
+            write!(f, "/{}", self.stream)?;
+        }
+
+        Ok(())
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Payload {
-    timestamp: u32,
-    bytes: Bytes,
-    frame: FlvFrame,
+    pub timestamp: u32,
+    pub bytes: Bytes,
+    pub frame: FlvFrame,
+}
+
+/// Information about the publisher of a stream, every subscriber receives it
+/// at the start of the stream.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    /// Where the stream comes from, for example `rtmp://127.0.0.1:50000`.
+    pub source: String,
+    /// Free form parameters attached by the publisher.
+    pub params: AHashMap<String, String>,
+}
+
+impl Metadata {
+    pub fn new(source: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            params: AHashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum RouterEvent {
+    Start(Arc<Metadata>),
+    Frame(Payload),
+    End,
+}
+
+/// A stream has the maximum number of subscribers.
+struct Full;
+
+struct Channel {
+    publisher: u64,
+    metadata: Arc<Metadata>,
+    keyframes: Vec<Payload>,
+    subscribers: AHashMap<u64, Sender<Payload>>,
+    /// The subscribers of the server itself, they are not viewers.
+    relays: AHashSet<u64>,
+}
+
+impl Channel {
+    /// The number of open subscribers that are viewers.
+    fn viewers(&self) -> usize {
+        self.subscribers
+            .iter()
+            .filter(|(id, tx)| !tx.is_closed() && !self.relays.contains(id))
+            .count()
+    }
+}
+
+/// The lock is never held across an await, so the channel of a stream can be
+/// removed synchronously when its publisher is dropped.
+type Channels = Arc<RwLock<AHashMap<StreamId, Channel>>>;
+
+/// Provides the streams that are not published locally, for example by
+/// pulling them from an origin server.
+#[async_trait]
+pub trait RouterSource: Send + Sync {
+    /// Start providing the stream, returns `true` once it has been published
+    /// to the router, or `false` if the stream is not available.
+    async fn request(&self, name: &StreamId) -> bool;
+}
+
+/// Limits the number of subscribers of the streams.
+pub trait RouterLimits: Send + Sync {
+    /// The maximum number of subscribers of the stream, zero means no limit.
+    fn max_subscribers(&self, name: &StreamId) -> usize;
+
+    /// Called when a subscriber is refused because the stream is full.
+    fn refused(&self, name: &StreamId);
 }
 
-#[derive(Default)]
 pub struct Router {
-    senders: Arc<RwLock<AHashMap<String, AHashMap<SocketAddr, Sender<Payload>>>>>,
-    keyframes: Arc<RwLock<AHashMap<String, Vec<Payload>>>>,
-    addrs: RwLock<AHashMap<SocketAddr, String>>,
+    channels: Channels,
+    ids: AtomicU64,
+    timestamp: config::Timestamp,
+    vhosts: AHashSet<String>,
+    published: broadcast::Sender<(StreamId, Arc<Metadata>)>,
+    source: OnceLock<Arc<dyn RouterSource>>,
+    limits: OnceLock<Arc<dyn RouterLimits>>,
 }
 
 impl Router {
-    pub async fn get_receiver(&self, addr: &SocketAddr, name: &str) -> Option<RouterReceiver> {
-        let keyframes = self.keyframes.read().await;
-        let keyframes = keyframes.get(name)?.as_slice();
-        let (tx, rx) = channel(1);
+    pub fn new(timestamp: config::Timestamp, vhosts: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            published: broadcast::channel(100).0,
+            channels: Channels::default(),
+            ids: AtomicU64::new(0),
+            source: OnceLock::new(),
+            limits: OnceLock::new(),
+            vhosts: vhosts.into_iter().map(|v| v.to_lowercase()).collect(),
+            timestamp,
+        }
+    }
+
+    /// The virtual host of a host name given by a client, with or without a
+    /// port, a host that is not configured is the default virtual host.
+    pub fn vhost(&self, host: Option<&str>) -> String {
+        let host = host.unwrap_or_default().to_lowercase();
+        let name = match host.strip_prefix('[') {
+            Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+            None => host.split(':').next().unwrap_or_default(),
+        };
 
-        self.senders
-            .write()
-            .await
-            .entry(name.to_string())
-            .or_insert_with(AHashMap::new)
-            .insert(*addr, tx);
-        self.addrs.write().await.insert(*addr, name.to_string());
-        Some(RouterReceiver::new(keyframes, rx))
+        if self.vhosts.contains(name) {
+            name.to_string()
+        } else {
+            String::new()
+        }
     }
 
-    pub async fn get_sender(&self, addr: &SocketAddr, name: &str) -> RouterSender {
-        self.addrs.write().await.insert(*addr, name.to_string());
-        self.keyframes
-            .write()
-            .await
-            .insert(name.to_string(), Vec::with_capacity(3));
-        RouterSender::new(name, self.keyframes.clone(), self.senders.clone())
+    /// Set the source of the streams that are not published locally, only
+    /// the first source is kept.
+    pub fn set_source(&self, source: Arc<dyn RouterSource>) {
+        let _ = self.source.set(source);
     }
 
-    pub async fn remove(&self, addr: &SocketAddr) {
-        if let Some(name) = self.addrs.write().await.remove(addr) {
-            self.senders.write().await.remove(&name);
-            self.keyframes.write().await.remove(&name);
+    /// Set the limits of the subscribers of the streams, only the first
+    /// limits are kept.
+    pub fn set_limits(&self, limits: Arc<dyn RouterLimits>) {
+        let _ = self.limits.set(limits);
+    }
+
+    /// The number of viewers of a stream, `None` if it is not published. The
+    /// relays are not counted.
+    pub fn subscribers(&self, name: &StreamId) -> Option<usize> {
+        Some(self.channels.read().unwrap().get(name)?.viewers())
+    }
+
+    /// Get notified of the name and metadata of every stream published from
+    /// now on.
+    pub fn watch(&self) -> broadcast::Receiver<(StreamId, Arc<Metadata>)> {
+        self.published.subscribe()
+    }
+
+    /// Start publishing a stream under the name, returns `None` if the name is
+    /// already being published by someone else.
+    ///
+    /// The stream is removed from the router when the returned sender is
+    /// dropped.
+    pub fn publish(&self, name: &StreamId, metadata: Metadata) -> Option<RouterSender> {
+        let mut channels = self.channels.write().unwrap();
+        if channels.contains_key(name) {
+            return None;
         }
+
+        let id = self.ids.fetch_add(1, Ordering::Relaxed);
+        let metadata = Arc::new(metadata);
+        channels.insert(
+            name.clone(),
+            Channel {
+                metadata: metadata.clone(),
+                keyframes: Vec::with_capacity(3),
+                subscribers: AHashMap::new(),
+                relays: AHashSet::new(),
+                publisher: id,
+            },
+        );
+
+        // The watchers are notified once the stream can be subscribed to.
+        drop(channels);
+        let _ = self.published.send((name.clone(), metadata));
+        Some(RouterSender::new(id, name, self.channels.clone()))
+    }
+
+    /// Subscribe to the raw frames of a stream, a stream that is not
+    /// published is requested from the source of the router. Returns `None`
+    /// if the stream is not available or already has the maximum number of
+    /// subscribers.
+    pub async fn subscribe(&self, name: &StreamId) -> Option<RouterSubscriber> {
+        match self.attach(name, false) {
+            Ok(Some(subscriber)) => return Some(subscriber),
+            Ok(None) => (),
+            Err(Full) => return None,
+        }
+
+        if !self.source.get()?.request(name).await {
+            return None;
+        }
+
+        self.attach(name, false).ok()?
+    }
+
+    /// Subscribe to the raw frames of a stream for a relay of the server, it
+    /// is not limited or counted as a viewer, and the stream is not requested
+    /// from the source.
+    pub fn subscribe_relay(&self, name: &StreamId) -> Option<RouterSubscriber> {
+        self.attach(name, true).ok()?
+    }
+
+    /// The subscribers are counted under the write lock, so concurrent
+    /// subscribers can not exceed the limit.
+    fn attach(&self, name: &StreamId, relay: bool) -> Result<Option<RouterSubscriber>, Full> {
+        let limits = self.limits.get().filter(|_| !relay);
+        let max = limits.map_or(0, |limits| limits.max_subscribers(name));
+        let mut channels = self.channels.write().unwrap();
+        let stream = match channels.get_mut(name) {
+            Some(stream) => stream,
+            None => return Ok(None),
+        };
+
+        if max > 0 && stream.viewers() >= max {
+            if let Some(limits) = limits {
+                limits.refused(name);
+            }
+
+            return Err(Full);
+        }
+
+        let (tx, rx) = channel(SUBSCRIBER_QUEUE);
+
+        let id = self.ids.fetch_add(1, Ordering::Relaxed);
+        stream.subscribers.insert(id, tx);
+        if relay {
+            stream.relays.insert(id);
+        }
+
+        Ok(Some(RouterSubscriber::new(
+            stream.metadata.clone(),
+            &stream.keyframes,
+            rx,
+        )))
+    }
+
+    /// Subscribe to a stream encoded as flv.
+    pub async fn subscribe_flv(&self, name: &StreamId) -> Option<RouterReceiver> {
+        let timestamper = Timestamper::new(self.timestamp.clone());
+        Some(RouterReceiver::new(
+            self.subscribe(name).await?,
+            timestamper,
+        ))
+    }
+
+    /// Subscribe to a stream encoded as mpeg-ts.
+    pub async fn subscribe_ts(&self, name: &StreamId) -> Option<RouterTsReceiver> {
+        let timestamper = Timestamper::new(self.timestamp.clone());
+        Some(RouterTsReceiver {
+            subscriber: self.subscribe(name).await?,
+            encoder: TsEncoder::default(),
+            timestamper,
+        })
+    }
+
+    /// Subscribe to a stream encoded as fragmented mp4.
+    pub async fn subscribe_fmp4(
+        &self,
+        name: &StreamId,
+        fragmentation: Fragmentation,
+    ) -> Option<RouterFmp4Receiver> {
+        let timestamper = Timestamper::new(self.timestamp.clone());
+        Some(RouterFmp4Receiver {
+            subscriber: self.subscribe(name).await?,
+            encoder: Fmp4Encoder::new(fragmentation),
+            timestamper,
+        })
     }
 }
 
@@ -88,29 +367,45 @@ impl RouterSenderState {
 }
 
 pub struct RouterSender {
-    failed_txs: Vec<SocketAddr>,
-    keyframes: Arc<RwLock<AHashMap<String, Vec<Payload>>>>,
-    senders: Arc<RwLock<AHashMap<String, AHashMap<SocketAddr, Sender<Payload>>>>>,
+    id: u64,
+    failed_txs: Vec<u64>,
+    /// The subscribers whose queue was full, they skip frames until the next
+    /// keyframe.
+    lagging: AHashSet<u64>,
+    channels: Channels,
     state: RouterSenderState,
-    name: String,
+    name: StreamId,
 }
 
 impl RouterSender {
-    fn new(
-        name: &str,
-        keyframes: Arc<RwLock<AHashMap<String, Vec<Payload>>>>,
-        senders: Arc<RwLock<AHashMap<String, AHashMap<SocketAddr, Sender<Payload>>>>>,
-    ) -> Self {
+    fn new(id: u64, name: &StreamId, channels: Channels) -> Self {
         Self {
             failed_txs: Vec::with_capacity(10),
+            lagging: AHashSet::new(),
             state: RouterSenderState::default(),
-            name: name.to_string(),
-            keyframes,
-            senders,
+            name: name.clone(),
+            channels,
+            id,
+        }
+    }
+
+    /// Forget the cached header frames, the next ones sent replace them. This
+    /// is for a publisher whose source has changed, so that new subscribers do
+    /// not get the headers of the previous source.
+    pub fn reset(&mut self) {
+        self.state = RouterSenderState::default();
+        if let Some(channel) = self.channels.write().unwrap().get_mut(&self.name) {
+            channel.keyframes.clear();
         }
     }
 
+    /// Pass a frame to the subscribers, the publisher is never held up by a
+    /// slow subscriber. The frames of a subscriber whose queue is full are
+    /// dropped until the next keyframe, or until there is room again for a
+    /// stream without video, the headers are always passed on.
     pub async fn send(&mut self, frame: FlvFrame, timestamp: u32, bytes: Bytes) -> Option<()> {
+        let header = frame == FlvFrame::Script || frame.is_sequence_header(&bytes);
+        let keyframe = frame.is_keyframe(&bytes) && !header;
         let payload = Payload {
             timestamp,
             frame,
@@ -120,74 +415,371 @@ impl RouterSender {
         // If these key frames are not passed to the channel, they are recorded
         // in the internal cache Can.
         if self.state.in_keyframe(frame) {
-            self.keyframes
+            self.channels
                 .write()
-                .await
+                .unwrap()
                 .get_mut(&self.name)?
-                .push(payload);
-        } else {
-            {
-                for (addr, sender) in self.senders.read().await.get(&self.name)? {
-                    if sender.send(payload.clone()).await.is_err() {
-                        self.failed_txs.push(*addr);
-                    }
+                .keyframes
+                .push(payload.clone());
+        }
+
+        let resume = keyframe || !self.state.video;
+        {
+            let channels = self.channels.read().unwrap();
+            for (id, sender) in &channels.get(&self.name)?.subscribers {
+                let lagging = self.lagging.contains(id);
+                if lagging && !resume && !header {
+                    continue;
                 }
-            }
 
-            if !self.failed_txs.is_empty() {
-                let mut senders = self.senders.write().await;
-                let senders = senders.get_mut(&self.name)?;
-                for addr in &self.failed_txs {
-                    senders.remove(addr);
+                match sender.try_send(payload.clone()) {
+                    Ok(()) if lagging && resume => {
+                        self.lagging.remove(id);
+                    }
+                    Ok(()) => (),
+                    Err(TrySendError::Full(_)) => {
+                        if self.lagging.insert(*id) {
+                            log::warn!("router subscriber is lagging, name: {}", self.name);
+                        }
+                    }
+                    Err(TrySendError::Closed(_)) => self.failed_txs.push(*id),
                 }
+            }
+        }
 
-                self.failed_txs.clear();
+        if !self.failed_txs.is_empty() {
+            let mut channels = self.channels.write().unwrap();
+            let channel = channels.get_mut(&self.name)?;
+            for id in &self.failed_txs {
+                channel.subscribers.remove(id);
+                channel.relays.remove(id);
+                self.lagging.remove(id);
             }
+
+            self.failed_txs.clear();
         }
 
         Some(())
     }
 }
 
-pub struct RouterReceiver {
+impl Drop for RouterSender {
+    /// Removing the channel closes all subscribers, the name can be published
+    /// again as soon as the sender is dropped.
+    fn drop(&mut self) {
+        let mut channels = self.channels.write().unwrap();
+        if channels.get(&self.name).map(|c| c.publisher) == Some(self.id) {
+            channels.remove(&self.name);
+        }
+    }
+}
+
+pub struct RouterSubscriber {
+    metadata: Option<Arc<Metadata>>,
+    keyframes: VecDeque<Payload>,
     receiver: Receiver<Payload>,
+    ended: bool,
+}
+
+impl RouterSubscriber {
+    fn new(metadata: Arc<Metadata>, keyframes: &[Payload], receiver: Receiver<Payload>) -> Self {
+        Self {
+            keyframes: keyframes.iter().cloned().collect(),
+            metadata: Some(metadata),
+            ended: false,
+            receiver,
+        }
+    }
+
+    /// Receive the next event of the stream, the first event is always
+    /// `RouterEvent::Start` followed by the cached header frames, and the last
+    /// event is `RouterEvent::End`.
+    pub async fn recv(&mut self) -> Option<RouterEvent> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<RouterEvent>> {
+        if let Some(metadata) = self.metadata.take() {
+            return Poll::Ready(Some(RouterEvent::Start(metadata)));
+        }
+
+        if let Some(payload) = self.keyframes.pop_front() {
+            return Poll::Ready(Some(RouterEvent::Frame(payload)));
+        }
+
+        if self.ended {
+            return Poll::Ready(None);
+        }
+
+        Poll::Ready(Some(match self.receiver.poll_recv(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Some(payload)) => RouterEvent::Frame(payload),
+            Poll::Ready(None) => {
+                self.ended = true;
+                RouterEvent::End
+            }
+        }))
+    }
+}
+
+pub struct RouterReceiver {
+    subscriber: RouterSubscriber,
+    timestamper: Timestamper,
     encoder: FlvEncoer,
 }
 
 impl RouterReceiver {
-    fn new(keyframes: &[Payload], receiver: Receiver<Payload>) -> Self {
+    fn new(mut subscriber: RouterSubscriber, timestamper: Timestamper) -> Self {
+        // The cached header frames are written at the start of the stream,
+        // they are not part of the timeline of the live frames.
         let mut encoder = FlvEncoer::new(FlvHeader::Full);
-        for payload in keyframes {
+        for payload in subscriber.keyframes.drain(..) {
             encoder.encode(payload.frame, 0, &payload.bytes);
         }
 
-        Self { receiver, encoder }
+        Self {
+            subscriber,
+            timestamper,
+            encoder,
+        }
     }
 
     pub async fn read(&mut self) -> Option<Vec<u8>> {
-        let Payload {
-            bytes,
-            frame,
-            timestamp,
-        } = self.receiver.recv().await?;
-        self.encoder.encode(frame, timestamp, &bytes);
-        Some(self.encoder.flush_to())
+        std::future::poll_fn(|cx| self.poll_read(cx)).await
     }
 
     pub fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Option<Vec<u8>>> {
-        let Payload {
-            bytes,
-            frame,
+        loop {
+            match self.subscriber.poll_recv(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(RouterEvent::Start(_))) => (),
+                Poll::Ready(Some(RouterEvent::Frame(payload))) => {
+                    let timestamp = self.timestamper.normalize(payload.frame, payload.timestamp);
+                    self.encoder
+                        .encode(payload.frame, timestamp, &payload.bytes);
+                    return Poll::Ready(Some(self.encoder.flush_to()));
+                }
+                Poll::Ready(Some(RouterEvent::End) | None) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+pub struct RouterTsReceiver {
+    subscriber: RouterSubscriber,
+    timestamper: Timestamper,
+    encoder: TsEncoder,
+}
+
+impl RouterTsReceiver {
+    /// Read the packets of the next frames, the stream ends when it can not
+    /// be encoded as mpeg-ts.
+    pub fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        loop {
+            match self.subscriber.poll_recv(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(RouterEvent::Start(_))) => (),
+                Poll::Ready(Some(RouterEvent::Frame(payload))) => {
+                    let timestamp = self.timestamper.normalize(payload.frame, payload.timestamp);
+                    match self
+                        .encoder
+                        .encode(payload.frame, timestamp, &payload.bytes)
+                    {
+                        Ok(bytes) if bytes.is_empty() => (),
+                        Ok(bytes) => return Poll::Ready(Some(bytes)),
+                        Err(e) => {
+                            log::warn!("mpeg-ts encode failed, err: {}", e);
+                            return Poll::Ready(None);
+                        }
+                    }
+                }
+                Poll::Ready(Some(RouterEvent::End) | None) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+pub struct RouterFmp4Receiver {
+    subscriber: RouterSubscriber,
+    timestamper: Timestamper,
+    encoder: Fmp4Encoder,
+}
+
+impl RouterFmp4Receiver {
+    /// The mime type with the codecs of the stream, known once the init
+    /// segment has been read.
+    pub fn mime(&self) -> Option<&str> {
+        self.encoder.mime()
+    }
+
+    pub async fn read(&mut self) -> Option<Bytes> {
+        std::future::poll_fn(|cx| self.poll_read(cx)).await
+    }
+
+    /// Read the segments of the next frames, the first one starts with the
+    /// init segment, the stream ends when it can not be encoded as mp4.
+    pub fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        loop {
+            match self.subscriber.poll_recv(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(RouterEvent::Start(_))) => (),
+                Poll::Ready(Some(RouterEvent::Frame(payload))) => {
+                    let timestamp = self.timestamper.normalize(payload.frame, payload.timestamp);
+                    match self
+                        .encoder
+                        .encode(payload.frame, timestamp, &payload.bytes)
+                    {
+                        Ok(bytes) if bytes.is_empty() => (),
+                        Ok(bytes) => return Poll::Ready(Some(bytes)),
+                        Err(e) => {
+                            log::warn!("mp4 encode failed, err: {}", e);
+                            return Poll::Ready(None);
+                        }
+                    }
+                }
+                Poll::Ready(Some(RouterEvent::End) | None) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn router() -> Router {
+        Router::new(config::Timestamp::default(), [])
+    }
+
+    fn video(keyframe: bool, timestamp: u32) -> (FlvFrame, u32, Bytes) {
+        let kind = if keyframe { 0x17 } else { 0x27 };
+        (
+            FlvFrame::Video,
             timestamp,
-        } = match self.receiver.poll_recv(cx) {
-            Poll::Pending => return Poll::Pending,
-            Poll::Ready(res) => match res {
-                Some(payload) => payload,
-                None => return Poll::Ready(None),
-            },
-        };
+            Bytes::from(vec![kind, 1, 0, 0, 0]),
+        )
+    }
+
+    /// The timestamps of the frames received until the subscriber is idle.
+    async fn timestamps(subscriber: &mut RouterSubscriber) -> Vec<u32> {
+        let mut timestamps = Vec::new();
+        while let Ok(Some(event)) =
+            tokio::time::timeout(Duration::from_millis(10), subscriber.recv()).await
+        {
+            if let RouterEvent::Frame(payload) = event {
+                timestamps.push(payload.timestamp);
+            }
+        }
+
+        timestamps
+    }
+
+    #[tokio::test]
+    async fn send_skips_a_lagging_subscriber_until_the_next_keyframe() {
+        let router = router();
+        let name = StreamId::new("", "live", "test");
+        let mut sender = router.publish(&name, Metadata::default()).unwrap();
+        let mut subscriber = router.subscribe(&name).await.unwrap();
+
+        // The subscriber does not read, the publisher is not held up.
+        for i in 0..SUBSCRIBER_QUEUE as u32 + 10 {
+            let (frame, timestamp, bytes) = video(i == 0, i);
+            sender.send(frame, timestamp, bytes).await.unwrap();
+        }
+
+        let queued = (0..SUBSCRIBER_QUEUE as u32).collect::<Vec<_>>();
+        assert_eq!(timestamps(&mut subscriber).await, queued);
+
+        // There is room again, the frames up to the next keyframe are still
+        // skipped.
+        for (keyframe, timestamp) in [(false, 5000), (true, 6000), (false, 6040)] {
+            let (frame, timestamp, bytes) = video(keyframe, timestamp);
+            sender.send(frame, timestamp, bytes).await.unwrap();
+        }
+
+        assert_eq!(timestamps(&mut subscriber).await, [6000, 6040]);
+    }
+
+    struct Limits(AtomicU64);
+
+    impl RouterLimits for Limits {
+        fn max_subscribers(&self, _: &StreamId) -> usize {
+            2
+        }
+
+        fn refused(&self, _: &StreamId) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_refuses_subscribers_over_the_limit() {
+        let router = router();
+        let limits = Arc::new(Limits(AtomicU64::new(0)));
+        router.set_limits(limits.clone());
+
+        let name = StreamId::new("", "live", "test");
+        let _sender = router.publish(&name, Metadata::default()).unwrap();
+        let first = router.subscribe(&name).await.unwrap();
+        let _second = router.subscribe(&name).await.unwrap();
+        assert!(router.subscribe(&name).await.is_none());
+        assert_eq!(limits.0.load(Ordering::Relaxed), 1);
+
+        // A closed subscriber makes room for another.
+        drop(first);
+        assert!(router.subscribe(&name).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn relays_are_not_limited_or_counted() {
+        let router = router();
+        router.set_limits(Arc::new(Limits(AtomicU64::new(0))));
+
+        let name = StreamId::new("", "live", "test");
+        let _sender = router.publish(&name, Metadata::default()).unwrap();
+        let _relay = router.subscribe_relay(&name).unwrap();
+        let _first = router.subscribe(&name).await.unwrap();
+        let _second = router.subscribe(&name).await.unwrap();
+        let _other = router.subscribe_relay(&name).unwrap();
+
+        assert_eq!(router.subscribers(&name), Some(2));
+        assert!(router.subscribe(&name).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn drop_removes_the_stream_at_once() {
+        let router = router();
+        let name = StreamId::new("", "live", "test");
+        let sender = router.publish(&name, Metadata::default()).unwrap();
+        let mut subscriber = router.subscribe(&name).await.unwrap();
+        drop(sender);
+
+        assert!(router.subscribers(&name).is_none());
+        assert!(router.publish(&name, Metadata::default()).is_some());
+        assert!(matches!(
+            subscriber.recv().await,
+            Some(RouterEvent::Start(_))
+        ));
+        assert!(matches!(subscriber.recv().await, Some(RouterEvent::End)));
+    }
+
+    #[tokio::test]
+    async fn watchers_can_subscribe_to_a_published_stream() {
+        let router = Arc::new(router());
+        let name = StreamId::new("", "live", "test");
+        let mut watcher = router.watch();
+
+        let watch = tokio::spawn({
+            let router = router.clone();
+            async move {
+                let (name, _) = watcher.recv().await.unwrap();
+                router.subscribe(&name).await.is_some()
+            }
+        });
 
-        self.encoder.encode(frame, timestamp, &bytes);
-        Poll::Ready(Some(self.encoder.flush_to()))
+        let _sender = router.publish(&name, Metadata::default()).unwrap();
+        assert!(watch.await.unwrap());
     }
 }
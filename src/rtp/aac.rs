@@ -0,0 +1,89 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// The fmtp parameters of the RFC 3640 AAC-hbr mode written by the
+/// packetizer, without the config.
+pub const FMTP: [(&str, &str); 6] = [
+    ("streamtype", "5"),
+    ("profile-level-id", "1"),
+    ("mode", "AAC-hbr"),
+    ("sizelength", "13"),
+    ("indexlength", "3"),
+    ("indexdeltalength", "3"),
+];
+
+/// Write a raw aac frame as an RFC 3640 AAC-hbr payload with a single access
+/// unit.
+pub fn packetize(frame: &[u8]) -> Bytes {
+    let mut payload = BytesMut::with_capacity(frame.len() + 4);
+    payload.put_u16(16);
+    payload.put_u16((frame.len() as u16) << 3);
+    payload.put_slice(frame);
+    payload.freeze()
+}
+
+/// Read the access units of an RFC 3640 AAC-hbr payload, the sizes are 13
+/// bits followed by a 3 bits index.
+pub fn depacketize(payload: &Bytes) -> Vec<Bytes> {
+    let mut frames = Vec::new();
+    if payload.len() < 2 {
+        return frames;
+    }
+
+    let headers = (u16::from_be_bytes([payload[0], payload[1]]) as usize).div_ceil(8);
+    let mut offset = 2 + headers;
+    for header in payload[2..(2 + headers).min(payload.len())].chunks_exact(2) {
+        let size = (u16::from_be_bytes([header[0], header[1]]) >> 3) as usize;
+        if offset + size > payload.len() {
+            break;
+        }
+
+        frames.push(payload.slice(offset..offset + size));
+        offset += size;
+    }
+
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packetize_round_trip() {
+        let frame = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+        let payload = packetize(&frame);
+
+        assert_eq!(&payload[..4], &[0, 16, 0x09, 0x60]);
+        assert_eq!(depacketize(&payload), vec![Bytes::from(frame)]);
+    }
+
+    #[test]
+    fn depacketize_reads_every_access_unit() {
+        let mut payload = BytesMut::new();
+        payload.put_u16(32);
+        payload.put_u16(3 << 3);
+        payload.put_u16((2 << 3) | 1);
+        payload.put_slice(&[1, 2, 3, 4, 5]);
+
+        let frames = depacketize(&payload.freeze());
+        assert_eq!(
+            frames,
+            vec![Bytes::from_static(&[1, 2, 3]), Bytes::from_static(&[4, 5])]
+        );
+    }
+
+    #[test]
+    fn depacketize_stops_at_a_truncated_access_unit() {
+        let mut payload = BytesMut::new();
+        payload.put_u16(32);
+        payload.put_u16(2 << 3);
+        payload.put_u16(10 << 3);
+        payload.put_slice(&[1, 2, 3]);
+
+        assert_eq!(
+            depacketize(&payload.freeze()),
+            vec![Bytes::from_static(&[1, 2])]
+        );
+        assert!(depacketize(&Bytes::from_static(&[0])).is_empty());
+    }
+}
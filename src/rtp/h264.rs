@@ -0,0 +1,244 @@
+use super::RtpPacket;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+const STAP_A: u8 = 24;
+const FU_A: u8 = 28;
+
+/// Split the nal units of an access unit into rtp payloads, RFC 6184
+/// packetization mode 1, a nal unit larger than the mtu is sent as FU-A
+/// fragments.
+pub fn packetize(nalus: &[&[u8]], mtu: usize) -> Vec<Bytes> {
+    let mut payloads = Vec::with_capacity(nalus.len());
+    for nalu in nalus.iter().filter(|nalu| !nalu.is_empty()) {
+        if nalu.len() <= mtu {
+            payloads.push(Bytes::copy_from_slice(nalu));
+            continue;
+        }
+
+        // The header of the nal unit is split into the fu indicator and the
+        // fu header.
+        let header = nalu[0];
+        let chunks = nalu[1..].chunks(mtu - 2);
+        let count = chunks.len();
+        for (index, chunk) in chunks.enumerate() {
+            let mut payload = BytesMut::with_capacity(chunk.len() + 2);
+            let mut fu = header & 0x1f;
+            if index == 0 {
+                fu |= 0x80;
+            }
+
+            if index + 1 == count {
+                fu |= 0x40;
+            }
+
+            payload.put_u8((header & 0xe0) | FU_A);
+            payload.put_u8(fu);
+            payload.put_slice(chunk);
+            payloads.push(payload.freeze());
+        }
+    }
+
+    payloads
+}
+
+/// Reassembles the access units of an RFC 6184 stream, an access unit ends
+/// at the marker bit, or when the timestamp changes.
+#[derive(Default)]
+pub struct H264Depacketizer {
+    timestamp: u32,
+    nalus: Vec<Bytes>,
+    fragment: Option<BytesMut>,
+    sequence: Option<u16>,
+}
+
+impl H264Depacketizer {
+    /// Push a packet, returns the timestamp and the nal units of the access
+    /// units completed by it.
+    pub fn push(&mut self, packet: &RtpPacket) -> Vec<(u32, Vec<Bytes>)> {
+        let mut units = Vec::new();
+        if packet.timestamp != self.timestamp && !self.nalus.is_empty() {
+            units.push((self.timestamp, std::mem::take(&mut self.nalus)));
+        }
+
+        // A lost packet breaks the fragmented nal unit.
+        if let Some(sequence) = self.sequence {
+            if packet.sequence != sequence.wrapping_add(1) {
+                self.fragment = None;
+            }
+        }
+
+        self.sequence = Some(packet.sequence);
+        self.timestamp = packet.timestamp;
+
+        let payload = &packet.payload;
+        match payload.first().map(|byte| byte & 0x1f) {
+            Some(1..=23) => self.nalus.push(payload.clone()),
+            Some(STAP_A) => {
+                let mut offset = 1;
+                while offset + 2 <= payload.len() {
+                    let size = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+                    offset += 2;
+                    if offset + size > payload.len() {
+                        break;
+                    }
+
+                    self.nalus.push(payload.slice(offset..offset + size));
+                    offset += size;
+                }
+            }
+            Some(FU_A) if payload.len() > 2 => {
+                let (indicator, header) = (payload[0], payload[1]);
+                if header & 0x80 != 0 {
+                    let mut fragment = BytesMut::with_capacity(payload.len() * 4);
+                    fragment.put_u8((indicator & 0xe0) | (header & 0x1f));
+                    self.fragment = Some(fragment);
+                }
+
+                if let Some(fragment) = &mut self.fragment {
+                    fragment.put_slice(&payload[2..]);
+                    if header & 0x40 != 0 {
+                        self.nalus.push(self.fragment.take().unwrap().freeze());
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        if packet.marker && !self.nalus.is_empty() {
+            units.push((self.timestamp, std::mem::take(&mut self.nalus)));
+        }
+
+        units
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtp::RtpWriter;
+
+    /// A nal unit of the given type and size with a counting body.
+    fn nalu(header: u8, size: usize) -> Vec<u8> {
+        let mut nalu = vec![header];
+        nalu.extend((1..size).map(|i| i as u8));
+        nalu
+    }
+
+    fn packets(
+        nalus: &[&[u8]],
+        mtu: usize,
+        timestamp: u32,
+        writer: &mut RtpWriter,
+    ) -> Vec<RtpPacket> {
+        writer
+            .write(packetize(nalus, mtu), timestamp)
+            .into_iter()
+            .map(|buf| RtpPacket::parse(buf).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn packetize_sends_small_nal_units_whole() {
+        let sps = nalu(0x67, 20);
+        let idr = nalu(0x65, 100);
+        let payloads = packetize(&[&sps, &[], &idr], 100);
+
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(&payloads[0][..], &sps[..]);
+        assert_eq!(&payloads[1][..], &idr[..]);
+    }
+
+    #[test]
+    fn packetize_fragments_large_nal_units() {
+        let idr = nalu(0x65, 1000);
+        let payloads = packetize(&[&idr], 300);
+
+        // 999 bytes after the header in chunks of 298.
+        assert_eq!(payloads.len(), 4);
+        for (index, payload) in payloads.iter().enumerate() {
+            assert!(payload.len() <= 300);
+            assert_eq!(payload[0], 0x60 | FU_A);
+            assert_eq!(payload[1] & 0x1f, 5);
+            assert_eq!(payload[1] & 0x80 != 0, index == 0);
+            assert_eq!(payload[1] & 0x40 != 0, index == 3);
+        }
+
+        let body: Vec<u8> = payloads.iter().flat_map(|p| p[2..].to_vec()).collect();
+        assert_eq!(&body[..], &idr[1..]);
+    }
+
+    #[test]
+    fn depacketizer_rebuilds_access_units() {
+        let mut writer = RtpWriter::new(96, 1);
+        let mut depacketizer = H264Depacketizer::default();
+        let sps = nalu(0x67, 20);
+        let idr = nalu(0x65, 5000);
+        let slice = nalu(0x41, 50);
+
+        let mut units = Vec::new();
+        for packet in packets(&[&sps, &idr], 1400, 3000, &mut writer)
+            .iter()
+            .chain(&packets(&[&slice], 1400, 6000, &mut writer))
+        {
+            units.extend(depacketizer.push(packet));
+        }
+
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[0].0, 3000);
+        assert_eq!(units[0].1, vec![Bytes::from(sps), Bytes::from(idr)]);
+        assert_eq!(units[1], (6000, vec![Bytes::from(slice)]));
+    }
+
+    #[test]
+    fn depacketizer_reads_stap_a() {
+        let mut payload = BytesMut::new();
+        payload.put_u8(STAP_A);
+        for nalu in [&[0x67, 1, 2][..], &[0x68, 3]] {
+            payload.put_u16(nalu.len() as u16);
+            payload.put_slice(nalu);
+        }
+
+        let packet = RtpPacket {
+            payload_type: 96,
+            marker: true,
+            sequence: 0,
+            timestamp: 0,
+            ssrc: 1,
+            payload: payload.freeze(),
+        };
+
+        let units = H264Depacketizer::default().push(&packet);
+        assert_eq!(
+            units,
+            vec![(
+                0,
+                vec![
+                    Bytes::from_static(&[0x67, 1, 2]),
+                    Bytes::from_static(&[0x68, 3])
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn depacketizer_drops_a_nal_unit_with_a_lost_fragment() {
+        let mut writer = RtpWriter::new(96, 1);
+        let mut depacketizer = H264Depacketizer::default();
+        let idr = nalu(0x65, 1000);
+        let slice = nalu(0x41, 50);
+
+        let mut lost = packets(&[&idr], 300, 0, &mut writer);
+        lost.remove(1);
+
+        let mut units = Vec::new();
+        for packet in lost
+            .iter()
+            .chain(&packets(&[&slice], 300, 3000, &mut writer))
+        {
+            units.extend(depacketizer.push(packet));
+        }
+
+        assert_eq!(units, vec![(3000, vec![Bytes::from(slice)])]);
+    }
+}
@@ -0,0 +1,112 @@
+pub mod aac;
+pub mod h264;
+
+use anyhow::{ensure, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// The largest rtp payload, leaves room for the ip, udp and rtp headers.
+pub const MTU: usize = 1400;
+
+#[derive(Clone, Debug)]
+pub struct RtpPacket {
+    pub payload_type: u8,
+    pub marker: bool,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    pub payload: Bytes,
+}
+
+impl RtpPacket {
+    pub fn parse(mut buf: Bytes) -> Result<Self> {
+        ensure!(buf.len() >= 12, "rtp packet is too short");
+        ensure!(buf[0] >> 6 == 2, "unsupported rtp version");
+
+        let first = buf.get_u8();
+        let second = buf.get_u8();
+        let sequence = buf.get_u16();
+        let timestamp = buf.get_u32();
+        let ssrc = buf.get_u32();
+
+        // The csrc list and the header extension are skipped, the padding is
+        // at the end of the payload.
+        let mut skip = (first & 0x0f) as usize * 4;
+        if first & 0x10 != 0 {
+            ensure!(buf.len() >= skip + 4, "rtp header extension is too short");
+            skip += 4 + u16::from_be_bytes([buf[skip + 2], buf[skip + 3]]) as usize * 4;
+        }
+
+        ensure!(buf.len() >= skip, "rtp header is too short");
+        buf.advance(skip);
+
+        if first & 0x20 != 0 {
+            let padding = buf.last().copied().unwrap_or_default() as usize;
+            ensure!(buf.len() >= padding, "rtp padding is too long");
+            buf.truncate(buf.len() - padding);
+        }
+
+        Ok(Self {
+            payload_type: second & 0x7f,
+            marker: second & 0x80 != 0,
+            payload: buf,
+            sequence,
+            timestamp,
+            ssrc,
+        })
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(12 + self.payload.len());
+        buf.put_u8(0x80);
+        buf.put_u8(if self.marker { 0x80 } else { 0 } | self.payload_type);
+        buf.put_u16(self.sequence);
+        buf.put_u32(self.timestamp);
+        buf.put_u32(self.ssrc);
+        buf.put_slice(&self.payload);
+        buf.freeze()
+    }
+}
+
+/// Numbers the packets of an outgoing rtp stream.
+pub struct RtpWriter {
+    payload_type: u8,
+    ssrc: u32,
+    sequence: u16,
+}
+
+impl RtpWriter {
+    pub fn new(payload_type: u8, ssrc: u32) -> Self {
+        Self {
+            sequence: 0,
+            payload_type,
+            ssrc,
+        }
+    }
+
+    /// The sequence number of the next packet.
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    /// Write the packets of a frame, the marker is set on the last one.
+    pub fn write(&mut self, payloads: Vec<Bytes>, timestamp: u32) -> Vec<Bytes> {
+        let count = payloads.len();
+        payloads
+            .into_iter()
+            .enumerate()
+            .map(|(index, payload)| {
+                let packet = RtpPacket {
+                    payload_type: self.payload_type,
+                    marker: index + 1 == count,
+                    sequence: self.sequence,
+                    ssrc: self.ssrc,
+                    timestamp,
+                    payload,
+                };
+
+                self.sequence = self.sequence.wrapping_add(1);
+                packet.encode()
+            })
+            .collect()
+    }
+}
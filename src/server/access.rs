@@ -0,0 +1,343 @@
+use crate::{
+    config::{Access, App, Config, Limits},
+    router::{Router, RouterLimits, StreamId},
+};
+
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::{Duration, SystemTime},
+};
+
+use ahash::AHashMap;
+use serde::Serialize;
+use tokio::time;
+
+/// How often the configuration file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Why a client was rejected, the rejections are counted by reason.
+#[derive(Clone, Copy, Debug)]
+enum Rejection {
+    Connections,
+    Publish,
+    Play,
+    Viewers,
+    Origin,
+}
+
+impl Rejection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Connections => "too many connections",
+            Self::Publish => "publish is not allowed",
+            Self::Play => "play is not allowed",
+            Self::Viewers => "too many viewers",
+            Self::Origin => "origin is not allowed",
+        }
+    }
+}
+
+/// The number of clients rejected since the start, by reason.
+#[derive(Serialize, Debug)]
+pub struct Rejections {
+    pub connections: u64,
+    pub publish: u64,
+    pub play: u64,
+    pub viewers: u64,
+    pub origin: u64,
+}
+
+#[derive(Default)]
+struct Connections {
+    total: usize,
+    ips: AHashMap<IpAddr, usize>,
+}
+
+/// The sections of the configuration that are reloaded, the access rules of
+/// the apps are resolved once.
+struct Policy {
+    access: Arc<Access>,
+    apps: AHashMap<String, Arc<Access>>,
+    vhosts: AHashMap<String, AHashMap<String, Arc<Access>>>,
+    limits: Limits,
+}
+
+impl Policy {
+    fn new(cfg: &Config) -> Self {
+        // An app without rules has the global ones.
+        let access = Arc::new(cfg.access.clone());
+        let apps = |apps: &HashMap<String, App>| {
+            apps.iter()
+                .map(|(name, app)| {
+                    let rules = app.access.clone().map(Arc::new);
+                    (name.clone(), rules.unwrap_or_else(|| access.clone()))
+                })
+                .collect::<AHashMap<_, _>>()
+        };
+
+        Self {
+            apps: apps(&cfg.apps),
+            vhosts: cfg
+                .vhosts
+                .iter()
+                .map(|(name, vhost)| (name.clone(), apps(&vhost.apps)))
+                .collect(),
+            limits: cfg.limits.clone(),
+            access,
+        }
+    }
+}
+
+/// Applies the access rules and the connection limits of the configuration,
+/// both are reloaded when the configuration file changes.
+pub struct AccessControl {
+    cfg: Arc<Config>,
+    policy: RwLock<Arc<Policy>>,
+    connections: Mutex<Connections>,
+    rejections: [AtomicU64; 5],
+}
+
+impl AccessControl {
+    pub fn new(cfg: Arc<Config>) -> Arc<Self> {
+        let access = Arc::new(Self {
+            policy: RwLock::new(Arc::new(Policy::new(&cfg))),
+            cfg,
+            connections: Mutex::default(),
+            rejections: Default::default(),
+        });
+
+        tokio::spawn(reload(Arc::downgrade(&access)));
+        access
+    }
+
+    fn reject(&self, rejection: Rejection, proto: &str, addr: SocketAddr, name: Option<&StreamId>) {
+        let count = self.rejections[rejection as usize].fetch_add(1, Ordering::Relaxed) + 1;
+        log::warn!(
+            "{} client rejected, addr: {}, name: {}, reason: {}, rejected: {}",
+            proto,
+            addr,
+            name.map(|name| name.to_string()).unwrap_or_default(),
+            rejection.as_str(),
+            count
+        );
+    }
+
+    pub fn rejections(&self) -> Rejections {
+        let count =
+            |rejection: Rejection| self.rejections[rejection as usize].load(Ordering::Relaxed);
+        Rejections {
+            connections: count(Rejection::Connections),
+            publish: count(Rejection::Publish),
+            play: count(Rejection::Play),
+            viewers: count(Rejection::Viewers),
+            origin: count(Rejection::Origin),
+        }
+    }
+
+    /// The access rules of the app of a stream, the apps of the virtual host
+    /// come before the global ones.
+    fn access(&self, name: &StreamId) -> Arc<Access> {
+        let policy = self.policy.read().unwrap();
+        policy
+            .vhosts
+            .get(&name.vhost)
+            .and_then(|apps| apps.get(&name.app))
+            .or_else(|| policy.apps.get(&name.app))
+            .unwrap_or(&policy.access)
+            .clone()
+    }
+
+    /// Count a new connection of a listener, returns `None` if it is over the
+    /// limits. The connection is counted until the returned guard is dropped.
+    pub fn connect(self: &Arc<Self>, proto: &str, addr: SocketAddr) -> Option<Connection> {
+        let limits = self.policy.read().unwrap().limits.clone();
+        let ip = addr.ip().to_canonical();
+
+        {
+            let mut connections = self.connections.lock().unwrap();
+            let count = connections.ips.get(&ip).copied().unwrap_or(0);
+            let full = (limits.max_connections > 0 && connections.total >= limits.max_connections)
+                || (limits.max_connections_per_ip > 0 && count >= limits.max_connections_per_ip);
+
+            if !full {
+                connections.total += 1;
+                connections.ips.insert(ip, count + 1);
+                return Some(Connection {
+                    access: self.clone(),
+                    ip,
+                });
+            }
+        }
+
+        self.reject(Rejection::Connections, proto, addr, None);
+        None
+    }
+
+    /// Whether the client can publish the stream.
+    pub fn publish(&self, proto: &str, addr: SocketAddr, name: &StreamId) -> bool {
+        if self.access(name).publish_rules(proto).allows(addr.ip()) {
+            return true;
+        }
+
+        self.reject(Rejection::Publish, proto, addr, Some(name));
+        false
+    }
+
+    /// Whether a web page of the origin, or the url of a referer, can play
+    /// the stream. Clients without either are only rejected if the rules
+    /// require an origin.
+    pub fn origin(
+        &self,
+        proto: &str,
+        addr: SocketAddr,
+        name: &StreamId,
+        origin: Option<&str>,
+    ) -> bool {
+        let access = self.access(name);
+        let allowed = match origin {
+            Some(origin) => access.allows_origin(origin),
+            None => !access.require_origin,
+        };
+
+        if !allowed {
+            self.reject(Rejection::Origin, proto, addr, Some(name));
+        }
+
+        allowed
+    }
+
+    /// Whether a web page of the origin can play the stream, for the cors
+    /// headers of the responses, nothing is counted.
+    pub fn allows_origin(&self, name: &StreamId, origin: &str) -> bool {
+        self.access(name).allows_origin(origin)
+    }
+
+    /// Whether the client can play the stream, and the stream has room for
+    /// another viewer. The viewers are counted again by the router when the
+    /// client subscribes, this check only tells the client why it is
+    /// rejected.
+    pub fn play(&self, router: &Router, proto: &str, addr: SocketAddr, name: &StreamId) -> bool {
+        let access = self.access(name);
+        if !access.play_rules(proto).allows(addr.ip()) {
+            self.reject(Rejection::Play, proto, addr, Some(name));
+            return false;
+        }
+
+        if access.max_viewers > 0 && router.subscribers(name).unwrap_or(0) >= access.max_viewers {
+            self.reject(Rejection::Viewers, proto, addr, Some(name));
+            return false;
+        }
+
+        true
+    }
+}
+
+impl RouterLimits for AccessControl {
+    fn max_subscribers(&self, name: &StreamId) -> usize {
+        self.access(name).max_viewers
+    }
+
+    fn refused(&self, name: &StreamId) {
+        let count =
+            self.rejections[Rejection::Viewers as usize].fetch_add(1, Ordering::Relaxed) + 1;
+        log::warn!(
+            "subscriber rejected, name: {}, reason: {}, rejected: {}",
+            name,
+            Rejection::Viewers.as_str(),
+            count
+        );
+    }
+}
+
+/// A connection counted by the access control.
+pub struct Connection {
+    access: Arc<AccessControl>,
+    ip: IpAddr,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut connections = self.access.connections.lock().unwrap();
+        connections.total -= 1;
+        if let Some(count) = connections.ips.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                connections.ips.remove(&self.ip);
+            }
+        }
+    }
+}
+
+fn modified(cfg: &Config) -> Option<SystemTime> {
+    fs::metadata(cfg.path.as_ref()?)
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+/// Replace the access rules and the limits once the file has changed, the
+/// previous ones are kept if the file can not be read. The other settings
+/// are only read at the start.
+async fn reload(access: Weak<AccessControl>) {
+    let mut last = match access.upgrade() {
+        Some(access) => modified(&access.cfg),
+        None => return,
+    };
+
+    let mut interval = time::interval(RELOAD_INTERVAL);
+    loop {
+        interval.tick().await;
+        let access = match access.upgrade() {
+            Some(access) => access,
+            None => return,
+        };
+
+        let current = modified(&access.cfg);
+        if current.is_none() || current == last {
+            continue;
+        }
+
+        last = current;
+        match access.cfg.reload() {
+            Ok(Some(cfg)) => {
+                *access.policy.write().unwrap() = Arc::new(Policy::new(&cfg));
+                log::info!("access rules and limits reloaded, other changes need a restart");
+            }
+            Ok(None) => (),
+            Err(e) => log::warn!("access rules reload failed, err: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn apps_without_rules_have_the_global_ones() {
+        let cfg = r#"
+            [access]
+            max_viewers = 1
+            [apps.live.access]
+            max_viewers = 2
+            [vhosts."example.com".apps.live]
+            [vhosts."example.com".apps.tv.access]
+            max_viewers = 3
+        "#;
+        let access = AccessControl::new(Arc::new(toml::from_str(cfg).unwrap()));
+        let viewers =
+            |vhost: &str, app: &str| access.max_subscribers(&StreamId::new(vhost, app, "a"));
+
+        assert_eq!(viewers("", "live"), 2);
+        assert_eq!(viewers("", "other"), 1);
+        assert_eq!(viewers("example.com", "live"), 1);
+        assert_eq!(viewers("example.com", "tv"), 3);
+        assert_eq!(viewers("example.com", "other"), 1);
+        assert_eq!(viewers("other.com", "live"), 2);
+    }
+}
@@ -1,35 +1,675 @@
-use crate::{config::HttpFlv, proto::http::*, router};
+use super::{
+    access::{AccessControl, Rejections},
+    tls,
+    vod::{fork_vod, Indexes},
+    websocket_flv,
+};
+use crate::{
+    config::{Connection, HttpFlv, WebSocketFlv},
+    flv::FlvDecoder,
+    mp4::Fragmentation,
+    proto::{http::*, rtmp::PublishType, websocket},
+    router::{self, StreamId},
+};
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
-use axum::extract::{ConnectInfo, Path, State};
-use axum::http::{HeaderValue, Response, StatusCode};
-use axum::{response::IntoResponse, routing::get, Router};
-use tower_http::cors::CorsLayer;
+use axum::body::{boxed, Body, BoxBody};
+use axum::extract::{BodyStream, ConnectInfo, Host, Path, Query, State};
+use axum::http::{header::*, request::Parts, HeaderMap, HeaderValue, Method};
+use axum::http::{Request, Response, StatusCode};
+use axum::{response::IntoResponse, routing::get, Json, Router};
+use futures_util::StreamExt;
+use tokio::net::TcpListener;
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::derive_accept_key,
+        protocol::{Role, WebSocketConfig},
+    },
+    WebSocketStream,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
+#[derive(Clone)]
+pub struct HttpState {
+    pub cfg: Arc<HttpFlv>,
+    pub router: Arc<router::Router>,
+    pub access: Arc<AccessControl>,
+    /// The settings of the websocket connections upgraded from requests.
+    pub websocket: WebSocketConfig,
+    /// The timeouts of the websocket connections upgraded from requests.
+    pub connection: Connection,
+    /// The keyframe indexes of the recordings.
+    pub indexes: Arc<Indexes>,
+}
+
+/// The virtual host of a request.
+pub fn vhost(state: &HttpState, host: &Option<Host>) -> String {
+    state
+        .router
+        .vhost(host.as_ref().map(|Host(host)| host.as_str()))
+}
+
+/// The web page of a request, the `Origin` header, or the `Referer` header
+/// which browsers send for media elements.
+fn origin(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(ORIGIN)
+        .or_else(|| headers.get(REFERER))
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Whether the client can play the stream from the web page of the request.
+pub fn allowed(state: &HttpState, addr: SocketAddr, name: &StreamId, headers: &HeaderMap) -> bool {
+    state.access.origin("http", addr, name, origin(headers))
+        && state.access.play(&state.router, "http", addr, name)
+}
+
+/// The formats of the streams played over http, by the extension of the
+/// file name.
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Flv,
+    Mp4,
+    Ts,
+}
+
+impl Format {
+    fn split(file: &str) -> Option<(&str, Self)> {
+        [(".flv", Self::Flv), (".mp4", Self::Mp4), (".ts", Self::Ts)]
+            .into_iter()
+            .find_map(|(extension, format)| Some((file.strip_suffix(extension)?, format)))
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Flv => "flv",
+            Self::Mp4 => "mp4",
+            Self::Ts => "ts",
+        }
+    }
+}
+
+/// The response headers of a played stream, a live stream is not cached.
+fn response(mime: HeaderValue, body: BoxBody) -> Response<BoxBody> {
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, mime);
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static("no-cache, no-store"),
+    );
+    response
+}
+
+/// Play a stream in the format of the request. A `HEAD` request probes the
+/// stream without subscribing to it, so it is not counted as a viewer, the
+/// content type of mp4 does not carry the codecs then.
+async fn play(
+    state: &HttpState,
+    addr: SocketAddr,
+    method: &Method,
+    name: StreamId,
+    format: Format,
+    headers: &HeaderMap,
+    query: &HashMap<String, String>,
+) -> Response<BoxBody> {
+    log::info!(
+        "http {} connection name: {}, addr: {}",
+        format.as_str(),
+        name,
+        addr
+    );
+
+    if !allowed(state, addr, &name, headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    if method == Method::HEAD {
+        if state.router.subscribers(&name).is_none() {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+
+        let mime = match format {
+            Format::Flv => "video/x-flv",
+            Format::Mp4 => "video/mp4",
+            Format::Ts => "video/mp2t",
+        };
+
+        return response(HeaderValue::from_static(mime), boxed(Body::empty()));
+    }
+
+    let (mime, body) = match format {
+        Format::Flv => match state.router.subscribe_flv(&name).await {
+            Some(reader) => (
+                HeaderValue::from_static("video/x-flv"),
+                boxed(Stream::new(reader)),
+            ),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+        Format::Ts => match state.router.subscribe_ts(&name).await {
+            Some(reader) => (
+                HeaderValue::from_static("video/mp2t"),
+                boxed(TsStream::new(reader)),
+            ),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+        Format::Mp4 => {
+            // One fragment per frame, or per gop with `?fragment=gop`.
+            let fragmentation = Fragmentation::parse(query.get("fragment").map(|f| f.as_str()));
+            let mut reader = match state.router.subscribe_fmp4(&name, fragmentation).await {
+                Some(reader) => reader,
+                None => return StatusCode::NOT_FOUND.into_response(),
+            };
+
+            // The codecs are announced in the content type, they are known
+            // once the init segment is written.
+            let init = match reader.read().await {
+                Some(init) => init,
+                None => return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response(),
+            };
+
+            let mime = HeaderValue::from_str(reader.mime().unwrap_or("video/mp4")).unwrap();
+            (mime, boxed(Fmp4Stream::new(reader, init)))
+        }
+    };
+
+    response(mime, body)
+}
+
+/// Play the stream at `/{name}?key={key}`, in the format of the extension of
+/// the name, `.flv`, `.mp4` or `.ts`, a name without one is played as flv.
+/// The name is the app and the key is the stream, like the publishes at
+/// `/{name}` and the legacy websocket flv urls, a stream published over rtmp
+/// as `live/cam` is played at `/live.flv?key=cam`.
 async fn fork_socket(
+    Path(file): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<HttpState>,
+    host: Option<Host>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response<BoxBody> {
+    let (app, format) = Format::split(&file).unwrap_or((&file, Format::Flv));
+    let key = query.get("key").map(|k| k.as_str()).unwrap_or_default();
+    let name = StreamId::new(vhost(&state, &host), app, key);
+    play(&state, addr, &method, name, format, &headers, &query).await
+}
+
+/// Returns the `Sec-WebSocket-Accept` value if the request is a websocket
+/// handshake.
+fn websocket_accept(headers: &HeaderMap) -> Option<String> {
+    let contains = |name: HeaderName, value: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                v.split(',')
+                    .any(|item| item.trim().eq_ignore_ascii_case(value))
+            })
+            .unwrap_or(false)
+    };
+
+    if !contains(CONNECTION, "upgrade")
+        || !contains(UPGRADE, "websocket")
+        || !contains(SEC_WEBSOCKET_VERSION, "13")
+    {
+        return None;
+    }
+
+    let key = headers.get(SEC_WEBSOCKET_KEY)?;
+    Some(derive_accept_key(key.as_bytes()))
+}
+
+/// Play the stream at `/{app}/{stream}.flv`, as http-flv, or as
+/// websocket-flv if the request is a websocket upgrade, so that both are
+/// served by the same listener. The stream is also played as `.mp4` and
+/// `.ts`, and the query parameters are passed on like those of `/{name}`.
+async fn fork_app(
+    Path((app, file)): Path<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<HttpState>,
+    host: Option<Host>,
+    request: Request<Body>,
+) -> Response<BoxBody> {
+    let (key, format) = match Format::split(&file) {
+        Some((key, format)) => (key.to_string(), format),
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let upgrade = websocket_accept(request.headers()).filter(|_| format == Format::Flv);
+    let accept = match upgrade {
+        Some(accept) => accept,
+        None => {
+            let name = StreamId::new(vhost(&state, &host), &app, &key);
+            let (method, headers) = (request.method(), request.headers());
+            return play(&state, addr, method, name, format, headers, &query).await;
+        }
+    };
+
+    let querys = query.into_iter().collect();
+    let query = websocket::Query {
+        origin: origin(request.headers()).map(|origin| origin.to_string()),
+        host: host.map(|Host(host)| host),
+        ..websocket::Query::new(app, key, &querys)
+    };
+
+    // The page of a player is checked before the upgrade, like the upgrades
+    // of the websocket flv server.
+    let vhost = state.router.vhost(query.host.as_deref());
+    let name = StreamId::new(vhost, &query.name, &query.key);
+    if query.mode == websocket::Mode::Play
+        && !state
+            .access
+            .origin("websocket", addr, &name, query.origin.as_deref())
+    {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    // The upgrade completes once the response is sent, a client that does not
+    // read it is closed after the handshake timeout.
+    let deadline = super::deadline(state.connection.handshake());
+    tokio::spawn(async move {
+        let upgraded = match super::timeout(deadline, hyper::upgrade::on(request)).await {
+            Some(upgraded) => upgraded,
+            None => {
+                log::warn!("websocket upgrade timeout, addr: {}", addr);
+                return;
+            }
+        };
+
+        match upgraded {
+            Ok(upgraded) => {
+                let stream =
+                    WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(state.websocket))
+                        .await;
+                let (router, access) = (&state.router, &state.access);
+                websocket_flv::serve(addr, router, access, &state.connection, query, stream).await;
+            }
+            Err(e) => log::warn!("websocket upgrade failed, addr: {}, err: {}", addr, e),
+        }
+    });
+
+    let headers = [
+        (CONNECTION, "upgrade".to_string()),
+        (UPGRADE, "websocket".to_string()),
+        (SEC_WEBSOCKET_ACCEPT, accept),
+    ];
+
+    (StatusCode::SWITCHING_PROTOCOLS, headers).into_response()
+}
+
+/// Publish the flv stream sent as the request body, usually with chunked
+/// transfer encoding.
+async fn publish(
+    state: &HttpState,
+    addr: SocketAddr,
+    name: StreamId,
+    mut body: BodyStream,
+) -> StatusCode {
+    let source = format!("http://{}", addr);
+    if !state.access.publish("http", addr, &name) {
+        return StatusCode::FORBIDDEN;
+    }
+
+    let mut sender = match super::publish(&state.router, source, &name, PublishType::Live) {
+        Some(sender) => sender,
+        None => return StatusCode::CONFLICT,
+    };
+
+    let mut decoder = FlvDecoder::default();
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => break,
+        };
+
+        decoder.extend(&chunk);
+        loop {
+            match decoder.decode() {
+                Ok(Some(tag)) => {
+                    if sender
+                        .send(tag.frame, tag.timestamp, tag.data)
+                        .await
+                        .is_none()
+                    {
+                        return StatusCode::OK;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    log::warn!("http flv publish failed, addr: {}, err: {}", addr, e);
+                    return StatusCode::BAD_REQUEST;
+                }
+            }
+        }
+    }
+
+    log::info!("http flv publish close, name: {}, addr: {}", name, addr);
+    StatusCode::OK
+}
+
+/// Publish the stream at `/{name}?key={key}`.
+async fn fork_publish(
     Path(name): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<HttpState>,
+    host: Option<Host>,
+    body: BodyStream,
+) -> StatusCode {
+    let key = query.get("key").map(|k| k.as_str()).unwrap_or_default();
+    let name = StreamId::new(vhost(&state, &host), &name, key);
+    publish(&state, addr, name, body).await
+}
+
+/// Publish the stream at `/{app}/{stream}`, the `.flv` extension is
+/// optional.
+async fn fork_app_publish(
+    Path((app, file)): Path<(String, String)>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    State(router): State<Arc<router::Router>>,
-) -> impl IntoResponse {
-    log::info!("http flv connection name: {}, addr: {}", name, addr);
+    State(state): State<HttpState>,
+    host: Option<Host>,
+    body: BodyStream,
+) -> StatusCode {
+    let stream = file.strip_suffix(".flv").unwrap_or(&file);
+    let name = StreamId::new(vhost(&state, &host), &app, stream);
+    publish(&state, addr, name, body).await
+}
 
-    if let Some(reader) = router.get_receiver(&addr, &name).await {
-        Response::new(Stream::new(reader)).into_response()
-    } else {
-        StatusCode::NOT_FOUND.into_response()
+/// The stream a request is checked against for the cors headers, only the
+/// app matters to the access rules, the first directory of a recording.
+fn cors_stream(router: &router::Router, parts: &Parts) -> StreamId {
+    let host = parts.headers.get(HOST).and_then(|host| host.to_str().ok());
+    let path = parts.uri.path().trim_start_matches('/');
+    let path = path.strip_prefix("_/vod/").unwrap_or(path);
+    let app = path.split('/').next().unwrap_or_default();
+    let (app, _) = Format::split(app).unwrap_or((app, Format::Flv));
+    StreamId::new(router.vhost(host), app, "")
+}
+
+/// The number of clients rejected by the access control, by reason.
+async fn access_stats(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<HttpState>,
+) -> Result<Json<Rejections>, StatusCode> {
+    if !state.cfg.stats.allows(addr.ip()) {
+        return Err(StatusCode::FORBIDDEN);
     }
+
+    Ok(Json(state.access.rejections()))
 }
 
-pub async fn run(cfg: HttpFlv, router: Arc<router::Router>) -> anyhow::Result<()> {
+/// Serve the http flv server, the websocket connections upgraded from it use
+/// the settings of the websocket flv server if it is configured.
+pub async fn run(
+    cfg: HttpFlv,
+    websocket: Option<WebSocketFlv>,
+    router: Arc<router::Router>,
+    access: Arc<AccessControl>,
+) -> anyhow::Result<()> {
+    // The origin is mirrored if the access rules of the stream allow it.
+    let (allow_origin, cors_router, cors_access) =
+        (cfg.allow_origin.clone(), router.clone(), access.clone());
+    let cors = CorsLayer::new().allow_origin(AllowOrigin::predicate(move |origin, parts| {
+        let origin = match origin.to_str() {
+            Ok(origin) => origin,
+            Err(_) => return false,
+        };
+
+        let name = cors_stream(&cors_router, parts);
+        (allow_origin == "*" || allow_origin == origin) && cors_access.allows_origin(&name, origin)
+    }));
+    let listen = cfg.listen;
+    let connection = cfg.connection.clone();
+    let header_timeout = connection.handshake();
+    let acceptor = match &cfg.tls {
+        Some(tls) => Some(tls::acceptor(tls, &[b"h2", b"http/1.1"])?),
+        None => None,
+    };
+
+    let state = HttpState {
+        cfg: Arc::new(cfg),
+        websocket: websocket
+            .as_ref()
+            .map(|cfg| cfg.get_config())
+            .unwrap_or_default(),
+        connection: websocket.map(|cfg| cfg.connection).unwrap_or_default(),
+        indexes: Arc::default(),
+        router,
+        access: access.clone(),
+    };
+
     let app = Router::new()
-        .route("/:name", get(fork_socket))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(cfg.allow_origin.as_str().parse::<HeaderValue>().unwrap()),
-        )
-        .with_state(router)
+        .route("/:name", get(fork_socket).post(fork_publish))
+        .route("/_/vod/*path", get(fork_vod))
+        .route("/_/stats/access", get(access_stats))
+        .route("/:app/:file", get(fork_app).post(fork_app_publish))
+        .layer(cors)
+        .with_state(state)
         .into_make_service_with_connect_info::<SocketAddr>();
-    axum::Server::bind(&cfg.listen).serve(app).await?;
+
+    let listener = TcpListener::bind(&listen).await?;
+    let mut server = axum::Server::builder(tls::incoming(listener, acceptor, access, connection));
+    if let Some(timeout) = header_timeout {
+        server = server.http1_header_read_timeout(timeout);
+    }
+
+    server.serve(app).await?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::avc::AvcConfig,
+        config::Timestamp,
+        flv::{FlvEncoer, FlvFrame, FlvHeader},
+        router::Metadata,
+        server,
+    };
+
+    use std::time::Duration;
+
+    use bytes::{BufMut, BytesMut};
+    use hyper::{body::HttpBody, Client};
+    use tokio::time::{sleep, timeout};
+
+    const SPS: [u8; 24] = [
+        0x67, 0x42, 0xc0, 0x1e, 0xda, 0x02, 0x80, 0xbf, 0xe5, 0x84, 0x00, 0x00, 0x03, 0x00, 0x04,
+        0x00, 0x00, 0x03, 0x00, 0xca, 0x3c, 0x58, 0xba, 0x80,
+    ];
+
+    fn header() -> Vec<u8> {
+        let mut header = BytesMut::from(&[0x17, 0, 0, 0, 0][..]);
+        header.put_slice(&AvcConfig::encode(&SPS, &[0x68, 0xce, 0x3c, 0x80]));
+        header.to_vec()
+    }
+
+    fn keyframe(index: u8) -> Vec<u8> {
+        let mut frame = BytesMut::from(&[0x17, 1, 0, 0, 0][..]);
+        frame.put_u32(4);
+        frame.put_slice(&[0x65, 0x88, 0x84, index]);
+        frame.to_vec()
+    }
+
+    /// Start an instance with the configuration and an http flv listener
+    /// with the settings, returns its router and its port.
+    async fn start(cfg: &str, http_flv: &str) -> (Arc<router::Router>, u16) {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let cfg = format!(
+            "{}\n[proto.http_flv]\nlisten = \"127.0.0.1:{}\"\n{}\n",
+            cfg, port, http_flv
+        );
+        let router = Arc::new(router::Router::new(Timestamp::default(), []));
+        server::run(Arc::new(toml::from_str(&cfg).unwrap()), router.clone());
+        sleep(Duration::from_millis(100)).await;
+        (router, port)
+    }
+
+    async fn head(uri: String) -> Response<Body> {
+        head_from(uri, None).await
+    }
+
+    /// Probe a stream from a web page.
+    async fn head_from(uri: String, origin: Option<&str>) -> Response<Body> {
+        let mut request = Request::head(uri);
+        if let Some(origin) = origin {
+            request = request.header(ORIGIN, origin);
+        }
+
+        let request = request.body(Body::empty()).unwrap();
+        timeout(Duration::from_secs(1), Client::new().request(request))
+            .await
+            .expect("the probe hangs")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn probes_a_stream_without_subscribing() {
+        let (router, port) = start("", "").await;
+        let name = StreamId::new("", "live", "cam");
+        let _sender = router.publish(&name, Metadata::default()).unwrap();
+
+        // No frame is published, the mp4 init segment is never written.
+        let response = head(format!("http://127.0.0.1:{}/live/cam.mp4", port)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "video/mp4");
+
+        let response = head(format!("http://127.0.0.1:{}/live.flv?key=cam", port)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "video/x-flv");
+        assert_eq!(router.subscribers(&name), Some(0));
+
+        let response = head(format!("http://127.0.0.1:{}/live/other.flv", port)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn publishes_to_an_app_path_and_plays_it_back() {
+        let (_, port) = start("", "").await;
+        let url = format!("http://127.0.0.1:{}/live/cam.flv", port);
+
+        // The body is kept open, the stream is published until it ends.
+        let (mut sender, body) = Body::channel();
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&url)
+            .body(body)
+            .unwrap();
+        let client = Client::new();
+        let post = tokio::spawn(client.request(request));
+
+        // The header is cached for the players, the keyframes are live.
+        let feed = tokio::spawn(async move {
+            let mut encoder = FlvEncoer::new(FlvHeader::Video);
+            encoder.encode(FlvFrame::Video, 0, &header());
+            for index in 0.. {
+                encoder.encode(FlvFrame::Video, index as u32 * 40, &keyframe(index));
+                if sender.send_data(encoder.flush_to().into()).await.is_err() {
+                    return;
+                }
+
+                sleep(Duration::from_millis(40)).await;
+            }
+        });
+
+        let play = async {
+            sleep(Duration::from_millis(100)).await;
+            let response = client.get(url.parse().unwrap()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let mut body = response.into_body();
+            let mut decoder = FlvDecoder::default();
+            let mut tags = Vec::new();
+            while tags.len() < 2 {
+                let chunk = body.data().await.expect("the stream ended").unwrap();
+                decoder.extend(&chunk);
+                while let Some(tag) = decoder.decode().unwrap() {
+                    tags.push(tag.data);
+                }
+            }
+
+            tags
+        };
+
+        let tags = timeout(Duration::from_secs(5), play)
+            .await
+            .expect("the published stream is not played");
+        assert_eq!(&tags[0][..], &header()[..]);
+        assert_eq!(&tags[1][..5], &keyframe(0)[..5]);
+
+        // A second publisher of the same stream is refused.
+        let response = client
+            .request(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri(&url)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        // The stream ends with the body.
+        feed.abort();
+        let response = post.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn shares_responses_with_the_allowed_pages_only() {
+        let cfg = "[access]\norigins = [\"*.example.com\"]\nrequire_origin = true\n";
+        let (router, port) = start(cfg, "").await;
+        let _sender = router
+            .publish(&StreamId::new("", "live", "cam"), Metadata::default())
+            .unwrap();
+
+        let url = format!("http://127.0.0.1:{}/live/cam.flv", port);
+        let response = head_from(url.clone(), Some("https://www.example.com")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://www.example.com"
+        );
+
+        let response = head_from(url.clone(), Some("https://example.org")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(response
+            .headers()
+            .get(ACCESS_CONTROL_ALLOW_ORIGIN)
+            .is_none());
+
+        let response = head_from(url, None).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn serves_the_stats_under_the_reserved_prefix() {
+        let (router, port) = start("", "").await;
+        let _sender = router
+            .publish(&StreamId::new("", "stats", "access"), Metadata::default())
+            .unwrap();
+
+        let response = head(format!("http://127.0.0.1:{}/stats/access.flv", port)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let uri = format!("http://127.0.0.1:{}/_/stats/access", port);
+        let response = Client::new().get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+
+        let (_, port) = start("", "stats = { allow = [\"10.0.0.0/8\"] }").await;
+        let uri = format!("http://127.0.0.1:{}/_/stats/access", port);
+        let response = Client::new().get(uri.parse().unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -1,25 +1,163 @@
+mod access;
 mod http_flv;
 mod rtmp;
+mod rtsp;
+mod srt;
+mod tls;
+mod vod;
+mod webrtc;
 mod websocket_flv;
 
-use crate::{config::Config, router::Router};
-use std::sync::Arc;
+use self::access::AccessControl;
+use crate::{
+    config::{self, Config},
+    proto::rtmp::PublishType,
+    router::{Metadata, Router, RouterSender, StreamId},
+};
 
-pub fn run(cfg: Arc<Config>) {
-    let router = Arc::new(Router::default());
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
 
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Instant};
+
+/// How long to wait before accepting again after an accept error.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Start publishing a stream for a client of any protocol, returns `None` if
+/// the publish is rejected.
+pub fn publish(
+    router: &Router,
+    source: String,
+    name: &StreamId,
+    kind: PublishType,
+) -> Option<RouterSender> {
+    log::info!(
+        "publish stream source: {}, name: {}, type: {}",
+        source,
+        name,
+        kind.as_str()
+    );
+
+    let mut metadata = Metadata::new(source);
+    metadata
+        .params
+        .insert("type".to_string(), kind.as_str().to_string());
+
+    let sender = router.publish(name, metadata);
+    if sender.is_none() {
+        log::warn!("stream is already published, name: {}", name);
+    }
+
+    sender
+}
+
+/// Accept the next connection of a listener. An accept error is mostly out
+/// of file descriptors, it passes once some connections are closed, so the
+/// listener keeps accepting after a backoff.
+async fn accept(listener: &TcpListener, proto: &str) -> (TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                log::warn!("{} accept failed, err: {}", proto, e);
+                time::sleep(ACCEPT_BACKOFF).await;
+            }
+        }
+    }
+}
+
+/// Apply the socket options of a listener to an accepted connection.
+fn configure(socket: &TcpStream, cfg: &config::Connection) {
+    if cfg.nodelay {
+        if let Err(e) = socket.set_nodelay(true) {
+            log::warn!("set tcp nodelay failed, err: {}", e);
+        }
+    }
+
+    if let Some(secs) = cfg.keepalive {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+        if let Err(e) = SockRef::from(socket).set_tcp_keepalive(&keepalive) {
+            log::warn!("set tcp keepalive failed, err: {}", e);
+        }
+    }
+}
+
+/// The deadline of a timeout that starts now, `None` if it is disabled.
+fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
+
+/// Await a future until the deadline, `None` if the deadline has passed
+/// first.
+async fn timeout<F: Future>(deadline: Option<Instant>, future: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Run a server in the background, it only returns if it can not listen,
+/// which is logged.
+fn spawn<F>(name: &'static str, listen: SocketAddr, server: F)
+where
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    log::info!("{} server listening: {}", name, listen);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("{} server failed, listen: {}, err: {}", name, listen, e);
+        }
+    });
+}
+
+pub fn run(cfg: Arc<Config>, router: Arc<Router>) {
+    let access = AccessControl::new(cfg.clone());
+    router.set_limits(access.clone());
+    let timestamp = &cfg.timestamp;
     if let Some(cfg) = &cfg.proto.rtmp {
-        tokio::spawn(rtmp::run(cfg.clone(), router.clone()));
-        log::info!("rtmp server listening: {}", cfg.listen);
+        let (router, access) = (router.clone(), access.clone());
+        let server = rtmp::run(cfg.clone(), timestamp.clone(), router, access);
+        spawn("rtmp", cfg.listen, server);
+    }
+
+    if let Some(cfg) = &cfg.proto.rtsp {
+        let (router, access) = (router.clone(), access.clone());
+        let server = rtsp::run(cfg.clone(), timestamp.clone(), router, access);
+        spawn("rtsp", cfg.listen, server);
+    }
+
+    if let Some(cfg) = &cfg.proto.srt {
+        let (router, access) = (router.clone(), access.clone());
+        let server = srt::run(cfg.clone(), timestamp.clone(), router, access);
+        spawn("srt", cfg.listen, server);
+    }
+
+    if let Some(cfg) = &cfg.proto.webrtc {
+        let (router, access) = (router.clone(), access.clone());
+        let server = webrtc::run(cfg.clone(), timestamp.clone(), router, access);
+        spawn("webrtc", cfg.listen, server);
     }
 
+    // The http flv server upgrades the websocket requests itself, a separate
+    // websocket flv server on its address could not listen.
+    let http_flv = cfg.proto.http_flv.as_ref().map(|cfg| cfg.listen);
     if let Some(cfg) = &cfg.proto.websocket_flv {
-        tokio::spawn(websocket_flv::run(cfg.clone(), router.clone()));
-        log::info!("websocket flv server listening: {}", cfg.listen);
+        if http_flv == Some(cfg.listen) {
+            log::info!(
+                "websocket flv is served by the http flv server: {}",
+                cfg.listen
+            );
+        } else {
+            let (router, access) = (router.clone(), access.clone());
+            let server = websocket_flv::run(cfg.clone(), router, access);
+            spawn("websocket flv", cfg.listen, server);
+        }
     }
 
+    let websocket = cfg.proto.websocket_flv.clone();
     if let Some(cfg) = &cfg.proto.http_flv {
-        tokio::spawn(http_flv::run(cfg.clone(), router));
-        log::info!("http flv server listening: {}", cfg.listen);
+        let server = http_flv::run(cfg.clone(), websocket, router, access);
+        spawn("http flv", cfg.listen, server);
     }
 }
@@ -1,10 +1,12 @@
 use std::{net::SocketAddr, sync::Arc};
 
+use super::access::AccessControl;
 use crate::{
     config,
     flv::FlvFrame,
-    proto::rtmp::{Rtmp, RtmpObserver},
-    router::{Router, RouterSender},
+    proto::rtmp::{PublishType, Rtmp, RtmpObserver, RtmpStream},
+    router::{Router, RouterEvent, RouterSender, RouterSubscriber, StreamId},
+    timestamp::Timestamper,
 };
 
 use anyhow::Result;
@@ -13,39 +15,48 @@ use bytes::Bytes;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    time::{self, Instant},
 };
 
 pub struct Observer {
     router: Arc<Router>,
+    access: Arc<AccessControl>,
     sender: Option<RouterSender>,
-    app: Option<String>,
     addr: SocketAddr,
 }
 
 impl Observer {
-    fn new(addr: SocketAddr, router: Arc<Router>) -> Self {
+    fn new(addr: SocketAddr, router: Arc<Router>, access: Arc<AccessControl>) -> Self {
         Self {
             sender: None,
-            app: None,
             router,
+            access,
             addr,
         }
     }
 }
 
+/// The stream of a command, the virtual host is the host of the `tcUrl`.
+fn stream_id(router: &Router, stream: &RtmpStream) -> StreamId {
+    let vhost = router.vhost(stream.host.as_deref());
+    StreamId::new(vhost, &stream.app, &stream.name)
+}
+
 #[async_trait]
 impl RtmpObserver for Observer {
-    async fn guard(&mut self, app: &str, key: &str) {
-        log::info!(
-            "rtmp publish stream addr: {}, name: {}, key: {}",
-            self.addr,
-            app,
-            key
-        );
-
-        let sender = self.router.get_sender(&self.addr, app).await;
-        let _ = self.sender.insert(sender);
-        let _ = self.app.insert(app.to_string());
+    async fn guard(&mut self, stream: &RtmpStream, kind: PublishType) -> bool {
+        let source = format!("rtmp://{}", self.addr);
+        let name = stream_id(&self.router, stream);
+        if !self.access.publish("rtmp", self.addr, &name) {
+            return false;
+        }
+
+        if let Some(sender) = super::publish(&self.router, source, &name, kind) {
+            let _ = self.sender.insert(sender);
+            true
+        } else {
+            false
+        }
     }
 
     async fn data_frame(&mut self, buf: Bytes) {
@@ -67,33 +78,123 @@ impl RtmpObserver for Observer {
     }
 }
 
-async fn fork_socket(addr: SocketAddr, mut socket: TcpStream, router: Arc<Router>) {
+async fn fork_socket(
+    addr: SocketAddr,
+    mut socket: TcpStream,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+    cfg: config::Rtmp,
+    timestamp: config::Timestamp,
+) {
     let mut buf = [0u8; 5120];
-    let mut rtmp = Rtmp::new(Observer::new(addr, router.clone()));
-    while let Ok(size) = socket.read(&mut buf).await {
-        if size == 0 {
-            break;
-        }
+    let observer = Observer::new(addr, router.clone(), access.clone());
+    let mut rtmp = Rtmp::new(observer);
+    let mut player: Option<(RouterSubscriber, Timestamper)> = None;
+    let handshake = super::deadline(cfg.connection.handshake());
+
+    loop {
+        // The client has to start publishing or playing before the handshake
+        // deadline, then a publisher has to keep sending while a player may
+        // stay silent.
+        let deadline = if player.is_some() {
+            None
+        } else if rtmp.is_publishing() {
+            super::deadline(cfg.connection.idle())
+        } else {
+            handshake
+        };
 
-        if let Ok(bytes) = rtmp.process(&buf[..size]).await {
-            if !bytes.is_empty() && socket.write_all(&bytes).await.is_err() {
+        let bytes = tokio::select! {
+            size = socket.read(&mut buf) => match size {
+                Ok(size) if size > 0 => rtmp.process(&buf[..size]).await,
+                _ => break,
+            },
+            _ = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                log::warn!("rtmp connection timed out, addr: {}", addr);
+                break;
+            }
+            event = async { player.as_mut().unwrap().0.recv().await }, if player.is_some() => {
+                match event {
+                    Some(RouterEvent::Frame(payload)) => {
+                        let (_, timestamper) = player.as_mut().unwrap();
+                        let timestamp = timestamper.normalize(payload.frame, payload.timestamp);
+                        rtmp.send(payload.frame, timestamp, payload.bytes)
+                    }
+                    Some(RouterEvent::Start(_)) => continue,
+                    Some(RouterEvent::End) | None => break,
+                }
+            }
+        };
+
+        let mut bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(_) => break,
+        };
+
+        // A play request is answered once the stream has been looked up, a
+        // missing stream closes the connection.
+        if let Some(stream) = rtmp.play() {
+            let name = stream_id(&router, &stream);
+            log::info!("rtmp play stream addr: {}, name: {}", addr, name);
+
+            let subscriber = if access.play(&router, "rtmp", addr, &name) {
+                router.subscribe(&name).await
+            } else {
+                None
+            };
+
+            match rtmp.play_status(subscriber.is_some()) {
+                Ok(status) => bytes.extend(status),
+                Err(_) => break,
+            }
+
+            if let Some(subscriber) = subscriber {
+                player = Some((subscriber, Timestamper::new(timestamp.clone())));
+            } else {
+                let write = super::deadline(cfg.connection.write());
+                let _ = super::timeout(write, socket.write_all(&bytes)).await;
+                log::warn!("rtmp play stream not found, name: {}", name);
                 break;
             }
-        } else {
-            break;
+        }
+
+        if !bytes.is_empty() {
+            let write = super::deadline(cfg.connection.write());
+            match super::timeout(write, socket.write_all(&bytes)).await {
+                Some(Ok(())) => (),
+                Some(Err(_)) => break,
+                None => {
+                    log::warn!("rtmp connection write timed out, addr: {}", addr);
+                    break;
+                }
+            }
         }
     }
 
-    log::info!("rtmp connection unpublish : {}", addr);
-    router.remove(&addr).await;
+    log::info!("rtmp connection close: {}", addr);
 }
 
-pub async fn run(cfg: config::Rtmp, router: Arc<Router>) -> Result<()> {
+pub async fn run(
+    cfg: config::Rtmp,
+    timestamp: config::Timestamp,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+) -> Result<()> {
     let listener = TcpListener::bind(cfg.listen).await?;
-    while let Ok((socket, addr)) = listener.accept().await {
-        log::info!("rtmp connection publish: {}", addr);
-        tokio::spawn(fork_socket(addr, socket, router.clone()));
-    }
+    loop {
+        let (socket, addr) = super::accept(&listener, "rtmp").await;
+        let connection = match access.connect("rtmp", addr) {
+            Some(connection) => connection,
+            None => continue,
+        };
 
-    Ok(())
+        log::info!("rtmp connection: {}", addr);
+        super::configure(&socket, &cfg.connection);
+        let (cfg, timestamp) = (cfg.clone(), timestamp.clone());
+        let (router, access) = (router.clone(), access.clone());
+        tokio::spawn(async move {
+            fork_socket(addr, socket, router, access, cfg, timestamp).await;
+            drop(connection);
+        });
+    }
 }
@@ -0,0 +1,796 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use super::access::AccessControl;
+use crate::{
+    codec::{aac::AacConfig, avc::AvcConfig, split_length_prefixed},
+    config,
+    flv::*,
+    proto::{
+        rtmp::PublishType,
+        rtsp::{interleaved, sdp, sdp::Media, Message, Request, Response, RtspDecoder, Transport},
+    },
+    router::{Payload, Router, RouterEvent, RouterSender, RouterSubscriber, StreamId},
+    rtp::{aac, h264, h264::H264Depacketizer, RtpPacket, RtpWriter, MTU},
+    timestamp::Timestamper,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+/// How long to wait for the sequence headers of a described stream.
+const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+const PAYLOAD_TYPE_VIDEO: u8 = 96;
+const PAYLOAD_TYPE_AUDIO: u8 = 97;
+
+const METHODS: &str = "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER, SET_PARAMETER";
+
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[derive(Clone)]
+enum Codec {
+    H264 {
+        sps: Bytes,
+        pps: Bytes,
+        nal_length_size: usize,
+    },
+    Aac(AacConfig),
+    /// A track announced by a publisher that can not be converted, its
+    /// packets are dropped.
+    Unsupported,
+}
+
+struct Udp {
+    rtp: Arc<UdpSocket>,
+    /// Only bound so the port is reserved, rtcp is ignored.
+    rtcp: UdpSocket,
+    /// The rtp address of the client.
+    peer: SocketAddr,
+}
+
+struct Track {
+    control: String,
+    codec: Codec,
+    clock_rate: u32,
+    /// The rtp channel of a track interleaved in the connection.
+    channel: Option<u8>,
+    udp: Option<Udp>,
+    /// The first rtp timestamp received from a publisher.
+    base: Option<u32>,
+    depacketizer: H264Depacketizer,
+}
+
+impl Track {
+    fn new(control: String, codec: Codec, clock_rate: u32) -> Self {
+        Self {
+            depacketizer: H264Depacketizer::default(),
+            channel: None,
+            base: None,
+            udp: None,
+            control,
+            codec,
+            clock_rate,
+        }
+    }
+
+    fn is_setup(&self) -> bool {
+        self.channel.is_some() || self.udp.is_some()
+    }
+
+    fn media(&self, payload_type: u8) -> Option<Media> {
+        let mut media = Media {
+            control: self.control.clone(),
+            clock_rate: self.clock_rate,
+            payload_type,
+            ..Media::default()
+        };
+
+        match &self.codec {
+            Codec::H264 { sps, pps, .. } => {
+                let sets = format!("{},{}", base64::encode(sps), base64::encode(pps));
+
+                media.kind = "video".to_string();
+                media.encoding = "H264".to_string();
+                media.fmtp = vec![("packetization-mode".to_string(), "1".to_string())];
+
+                // The profile is the three bytes after the nal header, a
+                // truncated sps has none.
+                if let Some(profile) = sps.get(1..4) {
+                    let profile = profile.iter().map(|b| format!("{:02x}", b)).collect();
+                    media.fmtp.push(("profile-level-id".to_string(), profile));
+                }
+
+                media.fmtp.push(("sprop-parameter-sets".to_string(), sets));
+            }
+            Codec::Aac(config) => {
+                let specific: String = config
+                    .encode()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+
+                media.kind = "audio".to_string();
+                media.encoding = "MPEG4-GENERIC".to_string();
+                media.channels = Some(config.channels);
+                media.fmtp = aac::FMTP
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect();
+                media.fmtp.push(("config".to_string(), specific));
+            }
+            Codec::Unsupported => return None,
+        }
+
+        Some(media)
+    }
+}
+
+/// Reads the codecs of a track announced by a publisher.
+fn announced(media: &Media) -> Codec {
+    match media.encoding.as_str() {
+        "H264" => {
+            let sets: Vec<Vec<u8>> = media
+                .param("sprop-parameter-sets")
+                .unwrap_or_default()
+                .split(',')
+                .filter_map(|set| base64::decode(set).ok())
+                .collect();
+
+            match &sets[..] {
+                [sps, pps, ..] => Codec::H264 {
+                    sps: Bytes::copy_from_slice(sps),
+                    pps: Bytes::copy_from_slice(pps),
+                    nal_length_size: 4,
+                },
+                // The parameter sets may also be sent in band only.
+                _ => Codec::H264 {
+                    sps: Bytes::new(),
+                    pps: Bytes::new(),
+                    nal_length_size: 4,
+                },
+            }
+        }
+        "MPEG4-GENERIC" => {
+            let config = media.param("config").unwrap_or_default();
+            let specific: Option<Vec<u8>> = (0..config.len())
+                .step_by(2)
+                .map(|i| {
+                    config
+                        .get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                })
+                .collect();
+
+            match specific.map(|specific| AacConfig::parse(&specific)) {
+                Some(Ok(config)) => Codec::Aac(config),
+                _ => Codec::Unsupported,
+            }
+        }
+        _ => Codec::Unsupported,
+    }
+}
+
+/// The stream of a request, the path is `app/stream[/track]` and the
+/// virtual host is the host of the uri.
+fn stream(router: &Router, req: &Request) -> StreamId {
+    let mut parts = req.path().split('/');
+    let vhost = router.vhost(req.host());
+    let app = parts.next().unwrap_or_default();
+    StreamId::new(vhost, app, parts.next().unwrap_or_default())
+}
+
+struct Player {
+    subscriber: RouterSubscriber,
+    timestamper: Timestamper,
+    writers: Vec<RtpWriter>,
+    /// A keyframe has been sent, the frames before it are dropped.
+    started: bool,
+}
+
+struct Recorder {
+    sender: RouterSender,
+    video: FlvVideoBuilder,
+    audio: FlvAudioBuilder,
+    /// The parameter sets of the session description have been passed to
+    /// the video builder.
+    announced: bool,
+}
+
+struct Connection {
+    addr: SocketAddr,
+    local: IpAddr,
+    cfg: config::Rtsp,
+    timestamp: config::Timestamp,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+    session: String,
+    tracks: Vec<Track>,
+    /// The stream announced by a publisher, published before recording.
+    announce: Option<RouterSender>,
+    player: Option<Player>,
+    recorder: Option<Recorder>,
+    /// The packets received on the udp sockets of a recording session.
+    packets_tx: mpsc::Sender<(usize, Bytes)>,
+    packets: mpsc::Receiver<(usize, Bytes)>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Connection {
+    fn session(&self) -> String {
+        format!("{};timeout=60", self.session)
+    }
+
+    async fn handle(&mut self, req: &Request) -> Result<(Response, bool)> {
+        let response = match req.method.as_str() {
+            "OPTIONS" => {
+                let methods = if self.cfg.record {
+                    format!("{}, ANNOUNCE, RECORD", METHODS)
+                } else {
+                    METHODS.to_string()
+                };
+
+                Response::new(200).header("Public", methods)
+            }
+            "DESCRIBE" => self.describe(req).await?,
+            "ANNOUNCE" if self.cfg.record => self.announce(req).await,
+            "SETUP" => self.setup(req).await?,
+            "PLAY" => self.play(req).await,
+            "RECORD" if self.cfg.record => self.record(),
+            "TEARDOWN" => return Ok((Response::new(200), true)),
+            "GET_PARAMETER" | "SET_PARAMETER" => Response::new(200),
+            _ => Response::new(405).header("Allow", METHODS),
+        };
+
+        Ok((response, false))
+    }
+
+    async fn describe(&mut self, req: &Request) -> Result<Response> {
+        let name = stream(&self.router, req);
+        if !self.access.play(&self.router, "rtsp", self.addr, &name) {
+            return Ok(Response::new(403));
+        }
+
+        let mut subscriber = match self.router.subscribe(&name).await {
+            Some(subscriber) => subscriber,
+            None => {
+                log::warn!("rtsp describe stream not found, name: {}", name);
+                return Ok(Response::new(404));
+            }
+        };
+
+        // The tracks are read from the sequence headers, which come before
+        // the first frame, a stream started on demand may take a while to
+        // send them.
+        let deadline = Instant::now() + DESCRIBE_TIMEOUT;
+        let mut tracks = Vec::new();
+        loop {
+            let payload = match time::timeout_at(deadline, subscriber.recv()).await {
+                Ok(Some(RouterEvent::Frame(payload))) => payload,
+                Ok(Some(RouterEvent::Start(_))) => continue,
+                _ => break,
+            };
+
+            let control = format!("trackID={}", tracks.len());
+            match payload.frame {
+                FlvFrame::Video => match FlvVideo::parse(&payload.bytes) {
+                    Some(video) if video.header && video.codec == VIDEO_CODEC_AVC => {
+                        let config = AvcConfig::parse(video.data)?;
+                        if let (Some(sps), Some(pps)) = (config.sps.first(), config.pps.first()) {
+                            let codec = Codec::H264 {
+                                nal_length_size: config.nal_length_size,
+                                sps: sps.clone(),
+                                pps: pps.clone(),
+                            };
+
+                            tracks.push(Track::new(control, codec, 90000));
+                        }
+                    }
+                    Some(video) if video.header => (),
+                    _ => break,
+                },
+                FlvFrame::Audio => match FlvAudio::parse(&payload.bytes) {
+                    Some(audio) if audio.header => {
+                        let config = AacConfig::parse(&audio.data)?;
+                        let rate = config.sample_rate;
+                        tracks.push(Track::new(control, Codec::Aac(config), rate));
+                    }
+                    _ => break,
+                },
+                FlvFrame::Script => (),
+            }
+        }
+
+        let medias: Vec<Media> = tracks
+            .iter()
+            .filter_map(|track| match track.codec {
+                Codec::H264 { .. } => track.media(PAYLOAD_TYPE_VIDEO),
+                _ => track.media(PAYLOAD_TYPE_AUDIO),
+            })
+            .collect();
+
+        if medias.is_empty() {
+            log::warn!("rtsp stream has no supported tracks, name: {}", name);
+            return Ok(Response::new(415));
+        }
+
+        self.tracks = tracks;
+        Ok(Response::new(200)
+            .header(
+                "Content-Base",
+                format!("{}/", req.uri.trim_end_matches('/')),
+            )
+            .body("application/sdp", sdp::encode(&name.to_string(), &medias)))
+    }
+
+    async fn announce(&mut self, req: &Request) -> Response {
+        let name = stream(&self.router, req);
+        let medias = sdp::parse(&String::from_utf8_lossy(&req.body));
+        if medias.is_empty() {
+            return Response::new(400);
+        }
+
+        if !self.access.publish("rtsp", self.addr, &name) {
+            return Response::new(403);
+        }
+
+        let source = format!("rtsp://{}", self.addr);
+        let sender = match super::publish(&self.router, source, &name, PublishType::Live) {
+            Some(sender) => sender,
+            None => return Response::new(409),
+        };
+
+        self.tracks = medias
+            .iter()
+            .enumerate()
+            .map(|(index, media)| {
+                let control = match media.control.as_str() {
+                    "" => format!("trackID={}", index),
+                    control => control.to_string(),
+                };
+
+                Track::new(control, announced(media), media.clock_rate.max(1))
+            })
+            .collect();
+
+        self.announce = Some(sender);
+        Response::new(200).header("Session", self.session())
+    }
+
+    async fn setup(&mut self, req: &Request) -> Result<Response> {
+        // The control of a track may be relative or absolute, its last
+        // segment is compared.
+        let segment = |path: &str| path.rsplit('/').next().unwrap_or_default().to_string();
+        let target = segment(req.uri.trim_end_matches('/'));
+        let index = match self
+            .tracks
+            .iter()
+            .position(|track| segment(&track.control) == target)
+        {
+            Some(index) => index,
+            None if self.tracks.is_empty() => return Ok(Response::new(455)),
+            None => return Ok(Response::new(404)),
+        };
+
+        let transport = match req
+            .header("transport")
+            .and_then(|header| Transport::parse(header, index))
+        {
+            Some(transport) => transport,
+            None => return Ok(Response::new(461)),
+        };
+
+        let track = &mut self.tracks[index];
+        let header = match transport {
+            Transport::Tcp(rtp, rtcp) => {
+                track.channel = Some(rtp);
+                format!("RTP/AVP/TCP;unicast;interleaved={}-{}", rtp, rtcp)
+            }
+            Transport::Udp(rtp, rtcp) => {
+                let udp = Udp {
+                    rtp: Arc::new(UdpSocket::bind((self.local, 0)).await?),
+                    rtcp: UdpSocket::bind((self.local, 0)).await?,
+                    peer: SocketAddr::new(self.addr.ip(), rtp),
+                };
+
+                let header = format!(
+                    "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+                    rtp,
+                    rtcp,
+                    udp.rtp.local_addr()?.port(),
+                    udp.rtcp.local_addr()?.port()
+                );
+
+                track.udp = Some(udp);
+                header
+            }
+        };
+
+        Ok(Response::new(200)
+            .header("Transport", header)
+            .header("Session", self.session()))
+    }
+
+    async fn play(&mut self, req: &Request) -> Response {
+        if self.announce.is_some() || !self.tracks.iter().any(Track::is_setup) {
+            return Response::new(455);
+        }
+
+        let name = stream(&self.router, req);
+        if !self.access.play(&self.router, "rtsp", self.addr, &name) {
+            return Response::new(403);
+        }
+
+        let subscriber = match self.router.subscribe(&name).await {
+            Some(subscriber) => subscriber,
+            None => return Response::new(404),
+        };
+
+        log::info!("rtsp play stream addr: {}, name: {}", self.addr, name);
+
+        let base = req.uri.trim_end_matches('/');
+        let writers: Vec<RtpWriter> = self
+            .tracks
+            .iter()
+            .map(|track| match track.codec {
+                Codec::H264 { .. } => RtpWriter::new(PAYLOAD_TYPE_VIDEO, random() as u32),
+                _ => RtpWriter::new(PAYLOAD_TYPE_AUDIO, random() as u32),
+            })
+            .collect();
+
+        let info: Vec<String> = self
+            .tracks
+            .iter()
+            .zip(&writers)
+            .filter(|(track, _)| track.is_setup())
+            .map(|(track, writer)| {
+                format!("url={}/{};seq={}", base, track.control, writer.sequence())
+            })
+            .collect();
+
+        self.player = Some(Player {
+            timestamper: Timestamper::new(self.timestamp.clone()),
+            started: false,
+            subscriber,
+            writers,
+        });
+
+        Response::new(200)
+            .header("Session", self.session())
+            .header("Range", "npt=0.000-")
+            .header("RTP-Info", info.join(","))
+    }
+
+    fn record(&mut self) -> Response {
+        let sender = match self.announce.take() {
+            Some(sender) => sender,
+            None => return Response::new(455),
+        };
+
+        // The packets received over udp are passed to the connection.
+        for (index, track) in self.tracks.iter().enumerate() {
+            if let Some(udp) = &track.udp {
+                let (socket, packets) = (udp.rtp.clone(), self.packets_tx.clone());
+                self.tasks.push(tokio::spawn(async move {
+                    let mut buf = vec![0u8; 65536];
+                    while let Ok(size) = socket.recv(&mut buf).await {
+                        let packet = Bytes::copy_from_slice(&buf[..size]);
+                        if packets.send((index, packet)).await.is_err() {
+                            break;
+                        }
+                    }
+                }));
+            }
+        }
+
+        self.recorder = Some(Recorder {
+            video: FlvVideoBuilder::new(VIDEO_CODEC_AVC),
+            audio: FlvAudioBuilder::default(),
+            announced: false,
+            sender,
+        });
+
+        Response::new(200).header("Session", self.session())
+    }
+
+    /// Send a frame of the played stream, returns the bytes interleaved in
+    /// the connection.
+    async fn send(&mut self, payload: Payload) -> Result<Vec<u8>> {
+        let player = self.player.as_mut().unwrap();
+        let timestamp = player
+            .timestamper
+            .normalize(payload.frame, payload.timestamp);
+        let has_video = self
+            .tracks
+            .iter()
+            .any(|track| track.is_setup() && matches!(track.codec, Codec::H264 { .. }));
+
+        let mut packets = Vec::new();
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            let writer = &mut player.writers[index];
+            let setup = track.is_setup();
+            match (&mut track.codec, payload.frame) {
+                (
+                    Codec::H264 {
+                        sps,
+                        pps,
+                        nal_length_size,
+                    },
+                    FlvFrame::Video,
+                ) => {
+                    let video = match FlvVideo::parse(&payload.bytes) {
+                        Some(video) if video.codec == VIDEO_CODEC_AVC => video,
+                        _ => continue,
+                    };
+
+                    if video.header {
+                        let config = AvcConfig::parse(video.data)?;
+                        if let (Some(new_sps), Some(new_pps)) =
+                            (config.sps.first(), config.pps.first())
+                        {
+                            (*sps, *pps) = (new_sps.clone(), new_pps.clone());
+                            *nal_length_size = config.nal_length_size;
+                        }
+
+                        continue;
+                    }
+
+                    player.started |= video.keyframe;
+                    if !player.started || !setup {
+                        continue;
+                    }
+
+                    // The parameter sets are repeated before every keyframe,
+                    // so a player can start at any of them.
+                    let mut nalus = split_length_prefixed(&video.data, *nal_length_size);
+                    if video.keyframe {
+                        nalus.splice(0..0, [&sps[..], &pps[..]]);
+                    }
+
+                    let time = (timestamp as i64 + video.cts as i64).max(0) as u64 * 90;
+                    let payloads = h264::packetize(&nalus, MTU);
+                    packets.push((index, writer.write(payloads, time as u32)));
+                }
+                (Codec::Aac(config), FlvFrame::Audio) => {
+                    let audio = match FlvAudio::parse(&payload.bytes) {
+                        Some(audio) => audio,
+                        None => continue,
+                    };
+
+                    if audio.header {
+                        *config = AacConfig::parse(&audio.data)?;
+                        continue;
+                    }
+
+                    if (has_video && !player.started) || !setup {
+                        continue;
+                    }
+
+                    let time = timestamp as u64 * track.clock_rate as u64 / 1000;
+                    let payloads = vec![aac::packetize(&audio.data)];
+                    packets.push((index, writer.write(payloads, time as u32)));
+                }
+                _ => (),
+            }
+        }
+
+        let mut bytes = Vec::new();
+        for (index, packets) in packets {
+            let track = &self.tracks[index];
+            for packet in packets {
+                match (&track.udp, track.channel) {
+                    (Some(udp), _) => {
+                        udp.rtp.send_to(&packet, udp.peer).await?;
+                    }
+                    (None, Some(channel)) => bytes.extend(interleaved(channel, &packet)),
+                    (None, None) => (),
+                }
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    /// Receive an rtp packet of a recorded track, returns `false` if the
+    /// stream has been removed from the router.
+    async fn receive(&mut self, index: usize, packet: Bytes) -> Result<bool> {
+        let (recorder, track) = match (&mut self.recorder, self.tracks.get_mut(index)) {
+            (Some(recorder), Some(track)) => (recorder, track),
+            _ => return Ok(true),
+        };
+
+        let packet = match RtpPacket::parse(packet) {
+            Ok(packet) => packet,
+            Err(_) => return Ok(true),
+        };
+
+        // The timestamps of every track start at zero.
+        let base = *track.base.get_or_insert(packet.timestamp);
+        let clock_rate = track.clock_rate as u64;
+        let time =
+            |timestamp: u32| (timestamp.wrapping_sub(base) as u64 * 1000 / clock_rate) as u32;
+
+        let mut tags = Vec::new();
+        match &track.codec {
+            Codec::H264 { sps, pps, .. } => {
+                for (timestamp, nalus) in track.depacketizer.push(&packet) {
+                    let mut units: Vec<&[u8]> = Vec::with_capacity(nalus.len() + 2);
+                    if !recorder.announced {
+                        recorder.announced = true;
+                        units.extend([&sps[..], &pps[..]]);
+                    }
+
+                    units.extend(nalus.iter().map(|nalu| &nalu[..]));
+                    tags.extend(recorder.video.build(units, time(timestamp), 0)?);
+                }
+            }
+            Codec::Aac(config) => {
+                let duration = 1024 * 1000 / config.sample_rate;
+                for (index, frame) in aac::depacketize(&packet.payload).iter().enumerate() {
+                    let timestamp = time(packet.timestamp) + index as u32 * duration;
+                    tags.extend(recorder.audio.build(config, timestamp, frame));
+                }
+            }
+            Codec::Unsupported => (),
+        }
+
+        for tag in tags {
+            if recorder
+                .sender
+                .send(tag.frame, tag.timestamp, tag.data)
+                .await
+                .is_none()
+            {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn run(&mut self, mut socket: TcpStream) -> Result<()> {
+        let mut decoder = RtspDecoder::default();
+        let mut buf = vec![0u8; 65536];
+
+        loop {
+            let bytes = tokio::select! {
+                size = socket.read(&mut buf) => {
+                    let size = size?;
+                    if size == 0 {
+                        return Ok(());
+                    }
+
+                    decoder.extend(&buf[..size]);
+                    let mut bytes = Vec::new();
+                    while let Some(message) = decoder.decode()? {
+                        match message {
+                            Message::Request(req) => {
+                                let (response, close) = self.handle(&req).await?;
+                                bytes.extend(response.encode(req.header("cseq")));
+                                if close {
+                                    socket.write_all(&bytes).await?;
+                                    return Ok(());
+                                }
+                            }
+                            Message::Interleaved(channel, packet) => {
+                                let track = self
+                                    .tracks
+                                    .iter()
+                                    .position(|track| track.channel == Some(channel));
+                                if let Some(index) = track {
+                                    if !self.receive(index, packet).await? {
+                                        return Ok(());
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    bytes
+                }
+                event = async { self.player.as_mut().unwrap().subscriber.recv().await }, if self.player.is_some() => {
+                    match event {
+                        Some(RouterEvent::Frame(payload)) => self.send(payload).await?,
+                        Some(RouterEvent::Start(_)) => continue,
+                        Some(RouterEvent::End) | None => return Ok(()),
+                    }
+                }
+                Some((index, packet)) = self.packets.recv() => {
+                    if !self.receive(index, packet).await? {
+                        return Ok(());
+                    }
+
+                    continue;
+                }
+            };
+
+            if !bytes.is_empty() {
+                socket.write_all(&bytes).await?;
+            }
+        }
+    }
+}
+
+async fn fork_socket(
+    addr: SocketAddr,
+    socket: TcpStream,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+    cfg: config::Rtsp,
+    timestamp: config::Timestamp,
+) {
+    let local = match socket.local_addr() {
+        Ok(local) => local.ip(),
+        Err(_) => return,
+    };
+
+    let (packets_tx, packets) = mpsc::channel(1024);
+    let mut connection = Connection {
+        session: format!("{:016x}", random()),
+        packets_tx,
+        packets,
+        tracks: Vec::new(),
+        tasks: Vec::new(),
+        announce: None,
+        player: None,
+        recorder: None,
+        timestamp,
+        router,
+        access,
+        local,
+        addr,
+        cfg,
+    };
+
+    if let Err(e) = connection.run(socket).await {
+        log::warn!("rtsp connection failed, addr: {}, err: {}", addr, e);
+    }
+
+    log::info!("rtsp connection close: {}", addr);
+}
+
+pub async fn run(
+    cfg: config::Rtsp,
+    timestamp: config::Timestamp,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+) -> Result<()> {
+    let listener = TcpListener::bind(cfg.listen).await?;
+    loop {
+        let (socket, addr) = super::accept(&listener, "rtsp").await;
+        let connection = match access.connect("rtsp", addr) {
+            Some(connection) => connection,
+            None => continue,
+        };
+
+        log::info!("rtsp connection: {}", addr);
+        let (router, access) = (router.clone(), access.clone());
+        let (cfg, timestamp) = (cfg.clone(), timestamp.clone());
+        tokio::spawn(async move {
+            fork_socket(addr, socket, router, access, cfg, timestamp).await;
+            drop(connection);
+        });
+    }
+}
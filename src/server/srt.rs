@@ -0,0 +1,171 @@
+use std::{sync::Arc, time::Duration};
+
+use super::access::AccessControl;
+use crate::{
+    config,
+    proto::{
+        rtmp::PublishType,
+        srt::{Mode, StreamId},
+    },
+    router::{self, Router, RouterEvent, RouterSender, RouterSubscriber},
+    timestamp::Timestamper,
+    ts::{TsDecoder, TsEncoder},
+};
+
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt, TryStreamExt};
+use srt_tokio::{
+    access::{RejectReason, ServerRejectReason},
+    ConnectionRequest, SrtListener, SrtSocket,
+};
+
+/// The payload of an srt message, 7 ts packets fit in a single udp packet.
+pub const MESSAGE_SIZE: usize = 1316;
+
+/// Publish the mpeg-ts stream sent by the peer.
+async fn publish(mut socket: SrtSocket, mut sender: RouterSender) -> Result<()> {
+    let mut decoder = TsDecoder::default();
+    while let Some((_, buf)) = socket.try_next().await? {
+        decoder.extend(&buf);
+
+        for tag in decoder.decode()? {
+            if sender
+                .send(tag.frame, tag.timestamp, tag.data)
+                .await
+                .is_none()
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Send the subscribed stream to the peer as mpeg-ts, until either side
+/// closes.
+async fn play(
+    mut socket: SrtSocket,
+    mut subscriber: RouterSubscriber,
+    timestamp: config::Timestamp,
+) -> Result<()> {
+    let mut encoder = TsEncoder::default();
+    let mut timestamper = Timestamper::new(timestamp);
+
+    loop {
+        tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(_)) => (),
+                _ => return Ok(()),
+            },
+            event = subscriber.recv() => match event {
+                Some(RouterEvent::Frame(payload)) => {
+                    let timestamp = timestamper.normalize(payload.frame, payload.timestamp);
+                    let mut bytes = encoder.encode(payload.frame, timestamp, &payload.bytes)?;
+
+                    while !bytes.is_empty() {
+                        let message = bytes.split_to(bytes.len().min(MESSAGE_SIZE));
+                        socket.send((std::time::Instant::now(), message)).await?;
+                    }
+                }
+                Some(RouterEvent::Start(_)) => (),
+                Some(RouterEvent::End) | None => return Ok(socket.close().await?),
+            }
+        }
+    }
+}
+
+/// Accept or reject a connection by the stream id, publishing follows the
+/// same rules as rtmp, and playing a missing stream is rejected.
+async fn fork_request(
+    request: ConnectionRequest,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+    timestamp: config::Timestamp,
+) -> Result<()> {
+    let addr = request.remote();
+    let stream_id = request.stream_id().map(|id| StreamId::parse(id.as_str()));
+    let stream_id = match stream_id {
+        Some(Ok(stream_id)) => stream_id,
+        _ => {
+            log::warn!("srt invalid stream id, addr: {}", addr);
+            let reason = RejectReason::Server(ServerRejectReason::BadRequest);
+            return Ok(request.reject(reason).await?);
+        }
+    };
+
+    let vhost = router.vhost(stream_id.host.as_deref());
+    let name = router::StreamId::new(vhost, &stream_id.name, &stream_id.key);
+    let allowed = match stream_id.mode {
+        Mode::Publish => access.publish("srt", addr, &name),
+        Mode::Play => access.play(&router, "srt", addr, &name),
+    };
+
+    if !allowed {
+        let reason = RejectReason::Server(ServerRejectReason::Forbidden);
+        return Ok(request.reject(reason).await?);
+    }
+
+    match stream_id.mode {
+        Mode::Publish => {
+            let source = format!("srt://{}", addr);
+            match super::publish(&router, source, &name, PublishType::Live) {
+                Some(sender) => publish(request.accept(None).await?, sender).await,
+                None => {
+                    let reason = RejectReason::Server(ServerRejectReason::Conflict);
+                    Ok(request.reject(reason).await?)
+                }
+            }
+        }
+        Mode::Play => {
+            log::info!("srt play stream addr: {}, name: {}", addr, name);
+            match router.subscribe(&name).await {
+                Some(subscriber) => play(request.accept(None).await?, subscriber, timestamp).await,
+                None => {
+                    log::warn!("srt play stream not found, name: {}", name);
+                    let reason = RejectReason::Server(ServerRejectReason::Notfound);
+                    Ok(request.reject(reason).await?)
+                }
+            }
+        }
+    }
+}
+
+pub async fn run(
+    cfg: config::Srt,
+    timestamp: config::Timestamp,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+) -> Result<()> {
+    let mut builder = SrtListener::builder().latency(Duration::from_millis(cfg.latency));
+    if let Some(passphrase) = &cfg.passphrase {
+        builder = builder.encryption(cfg.key_size, passphrase.as_str());
+    }
+
+    let (_listener, mut incoming) = builder.bind(cfg.listen).await?;
+    while let Some(request) = incoming.incoming().next().await {
+        let addr = request.remote();
+        log::info!("srt connection: {}", addr);
+        let connection = match access.connect("srt", addr) {
+            Some(connection) => connection,
+            None => {
+                let reason = RejectReason::Server(ServerRejectReason::Overload);
+                let _ = request.reject(reason).await;
+                continue;
+            }
+        };
+
+        let (router, access, timestamp) = (router.clone(), access.clone(), timestamp.clone());
+        tokio::spawn(async move {
+            if let Err(e) = fork_request(request, router, access, timestamp).await {
+                log::warn!("srt connection failed, addr: {}, err: {}", addr, e);
+            }
+
+            drop(connection);
+
+            log::info!("srt connection close: {}", addr);
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use super::access::{self, AccessControl};
+use crate::config::{self, Tls};
+
+use std::{
+    fs::{self, File},
+    future::Future,
+    io::{self, BufReader},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use axum::extract::connect_info::Connected;
+use hyper::server::accept::{self, Accept};
+use rustls_pemfile::Item;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+    sync::mpsc,
+    time::{self, Sleep},
+};
+use tokio_rustls::{
+    rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::{any_supported_type, CertifiedKey},
+        Certificate, PrivateKey, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Resolves every handshake to the certificate loaded last.
+struct Resolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn modified(cfg: &Tls) -> Option<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(&cfg.cert).and_then(|m| m.modified()).ok()?;
+    let key = fs::metadata(&cfg.key).and_then(|m| m.modified()).ok()?;
+    Some((cert, key))
+}
+
+fn load(cfg: &Tls) -> Result<Arc<CertifiedKey>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&cfg.cert)?))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate is found in {}", cfg.cert.display()));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(&cfg.key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(key),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("no private key is found in {}", cfg.key.display()))?;
+
+    let certs = certs.into_iter().map(Certificate).collect();
+    let key = any_supported_type(&PrivateKey(key))?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+/// Replace the certificate once the files have changed, the previous one is
+/// kept if the new files can not be loaded.
+async fn reload(cfg: Tls, resolver: Arc<Resolver>) {
+    let mut last = modified(&cfg);
+    let mut interval = time::interval(RELOAD_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        let current = modified(&cfg);
+        if current.is_none() || current == last {
+            continue;
+        }
+
+        last = current;
+        match load(&cfg) {
+            Ok(key) => {
+                *resolver.current.write().unwrap() = key;
+                log::info!("tls certificate reloaded: {}", cfg.cert.display());
+            }
+            Err(e) => log::warn!("tls certificate reload failed, err: {}", e),
+        }
+    }
+}
+
+/// Create an acceptor of the configured certificate, `alpn` lists the
+/// application protocols in the order of preference.
+pub fn acceptor(cfg: &Tls, alpn: &[&[u8]]) -> Result<TlsAcceptor> {
+    let resolver = Arc::new(Resolver {
+        current: RwLock::new(load(cfg)?),
+    });
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+    tokio::spawn(reload(cfg.clone(), resolver));
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The stream of a connection of the http server, tls is optional.
+enum MaybeTls {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// A connection of the http server, with the address of the client, it is
+/// counted by the access control until it is closed.
+///
+/// A write that has not completed within the write timeout fails, which
+/// closes a viewer that stopped reading the body of a stream.
+pub struct Connection {
+    stream: MaybeTls,
+    addr: SocketAddr,
+    write_timeout: Option<Duration>,
+    /// The deadline of the pending write, if a write is pending.
+    write_deadline: Option<Pin<Box<Sleep>>>,
+    _connection: access::Connection,
+}
+
+impl Connection {
+    /// Fail a pending write once the write timeout has passed since it
+    /// started pending, a completed write clears the deadline.
+    fn deadline<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        let timeout = match (&poll, self.write_timeout) {
+            (Poll::Pending, Some(timeout)) => timeout,
+            _ => {
+                self.write_deadline = None;
+                return poll;
+            }
+        };
+
+        let deadline = self
+            .write_deadline
+            .get_or_insert_with(|| Box::pin(time::sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(io::ErrorKind::TimedOut.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            MaybeTls::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = match &mut self.stream {
+            MaybeTls::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        };
+
+        self.deadline(cx, poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = match &mut self.stream {
+            MaybeTls::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        };
+
+        self.deadline(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            MaybeTls::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+impl Connected<&Connection> for SocketAddr {
+    fn connect_info(target: &Connection) -> Self {
+        target.addr
+    }
+}
+
+/// Accept the connections of the http server, the tls handshakes run
+/// concurrently so that a slow client does not hold up the others, and are
+/// closed after the handshake timeout. The connections over the limits of the
+/// access control are closed.
+pub fn incoming(
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    access: Arc<AccessControl>,
+    cfg: config::Connection,
+) -> impl Accept<Conn = Connection, Error = io::Error> {
+    let (sender, mut receiver) = mpsc::channel(16);
+    tokio::spawn(async move {
+        while !sender.is_closed() {
+            let (socket, addr) = super::accept(&listener, "http").await;
+
+            let connection = match access.connect("http", addr) {
+                Some(connection) => connection,
+                None => continue,
+            };
+
+            super::configure(&socket, &cfg);
+            let acceptor = acceptor.clone();
+            let sender = sender.clone();
+            let (handshake, write_timeout) = (cfg.handshake(), cfg.write());
+            tokio::spawn(async move {
+                let stream = match acceptor {
+                    Some(acceptor) => {
+                        let deadline = super::deadline(handshake);
+                        match super::timeout(deadline, acceptor.accept(socket)).await {
+                            Some(Ok(stream)) => MaybeTls::Tls(Box::new(stream)),
+                            Some(Err(e)) => {
+                                log::warn!("tls handshake failed, addr: {}, err: {}", addr, e);
+                                return;
+                            }
+                            None => {
+                                log::warn!("tls handshake timeout, addr: {}", addr);
+                                return;
+                            }
+                        }
+                    }
+                    None => MaybeTls::Plain(socket),
+                };
+
+                let connection = Connection {
+                    stream,
+                    addr,
+                    write_timeout,
+                    write_deadline: None,
+                    _connection: connection,
+                };
+
+                let _ = sender.send(connection).await;
+            });
+        }
+    });
+
+    accept::from_stream(futures_util::stream::poll_fn(move |cx| {
+        receiver.poll_recv(cx).map(|connection| connection.map(Ok))
+    }))
+}
@@ -0,0 +1,436 @@
+use super::http_flv::{self, HttpState};
+use crate::{
+    flv::{FlvDecoder, FlvEncoer, FlvFrame, FlvHeader, FlvTag},
+    record::parse_metadata,
+    router::StreamId,
+};
+
+use std::{
+    io::SeekFrom,
+    net::SocketAddr,
+    path::{Component, Path as FsPath, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use ahash::AHashMap;
+use anyhow::Result;
+use axum::{
+    body::StreamBody,
+    extract::{ConnectInfo, Host, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use bytes::{Bytes, BytesMut};
+use futures_util::{stream, Stream, StreamExt};
+use rml_rtmp::rml_amf0::Amf0Value;
+use serde::Deserialize;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt},
+    time::Instant,
+};
+
+const CHUNK_SIZE: usize = 65536;
+
+/// The number of file indexes kept, the cache is cleared when it is full.
+const MAX_INDEXES: usize = 256;
+
+#[derive(Deserialize)]
+pub struct VodQuery {
+    /// Start playing at the keyframe before this many seconds.
+    start: Option<f64>,
+    /// Send the tags at the speed of their timestamps, as a live stream would.
+    #[serde(default)]
+    realtime: bool,
+}
+
+/// The head of a flv file and its keyframes.
+struct Index {
+    /// The flv header, the metadata and the sequence headers, which are sent
+    /// before the tags of a seek position.
+    head: Bytes,
+    /// The time in seconds and the file position of every keyframe.
+    keyframes: Vec<(f64, u64)>,
+    /// The position of the first tag that is not part of the head.
+    body: u64,
+}
+
+impl Index {
+    fn keyframes_of_metadata(tag: &FlvTag) -> Option<Vec<(f64, u64)>> {
+        let metadata = parse_metadata(&tag.data)?;
+        let keyframes = match metadata.get("keyframes")? {
+            Amf0Value::Object(keyframes) => keyframes,
+            _ => return None,
+        };
+
+        let numbers = |key: &str| match keyframes.get(key) {
+            Some(Amf0Value::StrictArray(values)) => Some(
+                values
+                    .iter()
+                    .filter_map(|v| v.clone().get_number())
+                    .collect::<Vec<_>>(),
+            ),
+            _ => None,
+        };
+
+        let times = numbers("times")?;
+        let positions = numbers("filepositions")?;
+        Some(
+            times
+                .into_iter()
+                .zip(positions)
+                .map(|(time, position)| (time, position as u64))
+                .collect(),
+        )
+    }
+
+    /// Read the head of the file, the keyframes come from the `onMetaData`
+    /// index written by the recorder, or from scanning the whole file if
+    /// there is no index.
+    async fn load(file: &mut File) -> Result<Self> {
+        let mut decoder = FlvDecoder::default();
+        let mut encoder = FlvEncoer::new(FlvHeader::Full);
+        let mut keyframes = None;
+        let mut scanned = Vec::new();
+        let mut body = None;
+        let mut position = 0;
+
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        'read: loop {
+            let size = file.read(&mut buf).await?;
+            if size == 0 {
+                break;
+            }
+
+            decoder.extend(&buf[..size]);
+            while let Some(tag) = decoder.decode()? {
+                let offset = decoder.consumed() - (tag.data.len() as u64 + 15);
+                if body.is_none() {
+                    if tag.frame == FlvFrame::Script {
+                        keyframes = keyframes.or_else(|| Self::keyframes_of_metadata(&tag));
+                    }
+
+                    if tag.frame == FlvFrame::Script || tag.frame.is_sequence_header(&tag.data) {
+                        encoder.encode(tag.frame, tag.timestamp, &tag.data);
+                        continue;
+                    }
+
+                    body = Some(offset);
+                    position = offset;
+                    if keyframes.is_some() {
+                        break 'read;
+                    }
+                }
+
+                if tag.frame.is_keyframe(&tag.data) {
+                    scanned.push((tag.timestamp as f64 / 1000.0, offset));
+                }
+            }
+        }
+
+        encoder.encode_header();
+        Ok(Self {
+            keyframes: keyframes.unwrap_or(scanned),
+            head: encoder.flush_to().into(),
+            body: body.unwrap_or(position),
+        })
+    }
+
+    /// The position of the last keyframe at or before the time.
+    fn seek(&self, time: f64) -> u64 {
+        self.keyframes
+            .iter()
+            .take_while(|(t, _)| *t <= time)
+            .last()
+            .map(|(_, position)| *position)
+            .unwrap_or(self.body)
+            .max(self.body)
+    }
+}
+
+/// The indexes of the files played with seeking, an index is used again as
+/// long as the file has the same size and modification time, so a file that
+/// is still being recorded is indexed again when it has grown.
+#[derive(Default)]
+pub struct Indexes {
+    files: Mutex<AHashMap<PathBuf, (Version, Arc<Index>)>>,
+}
+
+/// The size and the modification time of a file.
+type Version = (u64, SystemTime);
+
+impl Indexes {
+    async fn get(&self, path: &FsPath, file: &mut File) -> Result<Arc<Index>> {
+        let metadata = file.metadata().await?;
+        let version = (metadata.len(), metadata.modified()?);
+        if let Some((indexed, index)) = self.files.lock().unwrap().get(path) {
+            if *indexed == version {
+                return Ok(index.clone());
+            }
+        }
+
+        let index = Arc::new(Index::load(file).await?);
+        let mut files = self.files.lock().unwrap();
+        if files.len() >= MAX_INDEXES {
+            files.clear();
+        }
+
+        files.insert(path.to_path_buf(), (version, index.clone()));
+        Ok(index)
+    }
+}
+
+/// Read the file in chunks, up to `limit` bytes.
+fn read_stream<R>(reader: R, limit: u64) -> impl Stream<Item = Result<Bytes>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    stream::unfold(
+        (reader.take(limit), vec![0u8; CHUNK_SIZE]),
+        |(mut reader, mut buf)| async move {
+            match reader.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(size) => Some((Ok(Bytes::copy_from_slice(&buf[..size])), (reader, buf))),
+                Err(e) => Some((Err(e.into()), (reader, buf))),
+            }
+        },
+    )
+}
+
+/// Send the tags of the file paced by their timestamps.
+fn realtime_stream(file: File, head: Bytes) -> impl Stream<Item = Result<Bytes>> {
+    let state = (
+        file,
+        FlvDecoder::default(),
+        None::<(Instant, u32)>,
+        Some(head),
+        true,
+    );
+    stream::unfold(
+        state,
+        |(mut file, mut decoder, mut clock, mut head, mut header)| async move {
+            if let Some(head) = head.take() {
+                return Some((Ok(head), (file, decoder, clock, None, header)));
+            }
+
+            // The decoder expects a flv header, the tags continue after the head
+            // that was already sent.
+            if header {
+                header = false;
+                let mut buf = BytesMut::new();
+                FlvHeader::Full.encode(&mut buf);
+                decoder.extend(&buf);
+            }
+
+            let mut buf = vec![0u8; CHUNK_SIZE];
+            let tag = loop {
+                match decoder.decode() {
+                    Ok(Some(tag)) => break tag,
+                    Ok(None) => (),
+                    Err(e) => return Some((Err(e), (file, decoder, clock, None, header))),
+                }
+
+                match file.read(&mut buf).await {
+                    Ok(0) => return None,
+                    Ok(size) => decoder.extend(&buf[..size]),
+                    Err(e) => return Some((Err(e.into()), (file, decoder, clock, None, header))),
+                }
+            };
+
+            let (start, base) = *clock.get_or_insert((Instant::now(), tag.timestamp));
+            let delay = tag.timestamp.saturating_sub(base) as u64;
+            tokio::time::sleep_until(start + Duration::from_millis(delay)).await;
+
+            let mut bytes = BytesMut::with_capacity(tag.data.len() + 15);
+            tag.frame.encode(&tag.data, &mut bytes, tag.timestamp);
+            Some((Ok(bytes.freeze()), (file, decoder, clock, None, header)))
+        },
+    )
+}
+
+/// Parse a single `bytes=` range, the end is inclusive.
+fn parse_range(value: &str, size: u64) -> Option<(u64, u64)> {
+    if size == 0 {
+        return None;
+    }
+
+    let (start, end) = value.strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (size.checked_sub(suffix.parse().ok()?)?, size - 1),
+        (start, "") => (start.parse().ok()?, size - 1),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(size - 1)),
+    };
+
+    (start <= end && end < size).then_some((start, end))
+}
+
+fn resolve(root: &FsPath, path: &str) -> Option<PathBuf> {
+    let path = FsPath::new(path);
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_)))
+        .then(|| root.join(path))
+}
+
+async fn serve(
+    path: String,
+    query: VodQuery,
+    headers: HeaderMap,
+    state: HttpState,
+) -> Result<Response> {
+    let path = match state
+        .cfg
+        .vod_root
+        .as_ref()
+        .and_then(|root| resolve(root, &path))
+    {
+        Some(path) => path,
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+
+    let mut file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+
+    let size = file.metadata().await?.len();
+    let content_type = [(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("video/x-flv"),
+    )];
+
+    if query.start.is_some() || query.realtime {
+        let index = state.indexes.get(&path, &mut file).await?;
+        let position = index.seek(query.start.unwrap_or(0.0));
+        file.seek(SeekFrom::Start(position)).await?;
+
+        log::info!("http flv vod: {:?}, position: {}", path, position);
+
+        return Ok(if query.realtime {
+            let body = StreamBody::new(realtime_stream(file, index.head.clone()));
+            (content_type, body).into_response()
+        } else {
+            let head = stream::once(futures_util::future::ready(Ok(index.head.clone())));
+            let body = StreamBody::new(head.chain(read_stream(file, u64::MAX)));
+            (content_type, body).into_response()
+        });
+    }
+
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    let (start, end) = match range.map(|range| parse_range(range, size)) {
+        Some(Some(range)) => range,
+        Some(None) => {
+            let range = format!("bytes */{}", size);
+            return Ok((
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, range)],
+            )
+                .into_response());
+        }
+        None => {
+            let body = StreamBody::new(read_stream(file, size));
+            return Ok((
+                content_type,
+                [
+                    (header::ACCEPT_RANGES, "bytes".to_string()),
+                    (header::CONTENT_LENGTH, size.to_string()),
+                ],
+                body,
+            )
+                .into_response());
+        }
+    };
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let body = StreamBody::new(read_stream(file, end - start + 1));
+    Ok((
+        StatusCode::PARTIAL_CONTENT,
+        content_type,
+        [
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::CONTENT_LENGTH, (end - start + 1).to_string()),
+            (
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, size),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Play a recording, it is checked like the live stream of its app, the
+/// first directory of the path. The connection is counted by the listener.
+pub async fn fork_vod(
+    Path(path): Path<String>,
+    Query(query): Query<VodQuery>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<HttpState>,
+    host: Option<Host>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let name = StreamId::from_path(http_flv::vhost(&state, &host), &path);
+    if !http_flv::allowed(&state, addr, &name, &headers) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    match serve(path, query, headers, state).await {
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("http flv vod failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::AsyncWriteExt;
+
+    fn keyframes(encoder: &mut FlvEncoer, timestamps: std::ops::Range<u32>) -> Vec<u8> {
+        for timestamp in timestamps {
+            let data = Bytes::from_static(&[0x17, 1, 0, 0, 0, 0, 0, 0, 1, 0x65]);
+            encoder.encode(FlvFrame::Video, timestamp * 1000, &data);
+        }
+
+        encoder.flush_to()
+    }
+
+    #[tokio::test]
+    async fn indexes_are_reused_until_the_file_changes() {
+        let path = std::env::temp_dir().join(format!("vod-index-{}.flv", std::process::id()));
+        let mut encoder = FlvEncoer::new(FlvHeader::Full);
+        tokio::fs::write(&path, keyframes(&mut encoder, 0..3))
+            .await
+            .unwrap();
+
+        let indexes = Indexes::default();
+        let mut file = File::open(&path).await.unwrap();
+        let first = indexes.get(&path, &mut file).await.unwrap();
+        let mut file = File::open(&path).await.unwrap();
+        let second = indexes.get(&path, &mut file).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.keyframes.len(), 3);
+
+        // A recording that has grown is indexed again.
+        let mut writer = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await
+            .unwrap();
+        writer
+            .write_all(&keyframes(&mut encoder, 3..5))
+            .await
+            .unwrap();
+        writer.flush().await.unwrap();
+
+        let mut file = File::open(&path).await.unwrap();
+        let third = indexes.get(&path, &mut file).await.unwrap();
+        assert_eq!(third.keyframes.len(), 5);
+        assert_eq!(third.seek(3.5), third.keyframes[3].1);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+mod whep;
+mod whip;
+
+use super::access::AccessControl;
+use crate::{
+    config,
+    router::{Router, StreamId},
+};
+
+use std::{net::SocketAddr, sync::Arc};
+
+use ahash::AHashMap;
+use anyhow::Result;
+use axum::extract::{Host, Path, State};
+use axum::http::{
+    header::{CONTENT_TYPE, LOCATION},
+    HeaderValue, StatusCode,
+};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, post};
+use axum::Router as HttpRouter;
+use tokio::{net::UdpSocket, sync::Mutex};
+use webrtc::{
+    api::{
+        interceptor_registry::register_default_interceptors, media_engine::MediaEngine,
+        setting_engine::SettingEngine, APIBuilder, API,
+    },
+    ice::{
+        network_type::NetworkType,
+        udp_mux::{UDPMuxDefault, UDPMuxParams},
+        udp_network::UDPNetwork,
+    },
+    ice_transport::ice_candidate_type::RTCIceCandidateType,
+    interceptor::registry::Registry,
+    peer_connection::{
+        configuration::RTCConfiguration, math_rand_alpha,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+};
+
+#[derive(Clone)]
+pub struct WebrtcState {
+    api: Arc<API>,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+    timestamp: config::Timestamp,
+    /// The peer connections of the sessions, by the id of their resource.
+    sessions: Arc<Mutex<AHashMap<String, Arc<RTCPeerConnection>>>>,
+}
+
+impl WebrtcState {
+    async fn peer_connection(&self) -> Result<Arc<RTCPeerConnection>> {
+        let pc = self
+            .api
+            .new_peer_connection(RTCConfiguration::default())
+            .await?;
+
+        Ok(Arc::new(pc))
+    }
+
+    /// Answer the offer of a client, the answer holds every candidate as
+    /// the endpoints do not trickle them.
+    async fn answer(&self, pc: &RTCPeerConnection, offer: String) -> Result<String> {
+        pc.set_remote_description(RTCSessionDescription::offer(offer)?)
+            .await?;
+
+        let answer = pc.create_answer(None).await?;
+        let mut gathered = pc.gathering_complete_promise().await;
+        pc.set_local_description(answer).await?;
+        let _ = gathered.recv().await;
+
+        match pc.local_description().await {
+            Some(answer) => Ok(answer.sdp),
+            None => anyhow::bail!("webrtc local description is missing"),
+        }
+    }
+
+    /// Keep the peer connection of a session until it is deleted.
+    async fn insert(&self, id: String, pc: Arc<RTCPeerConnection>) {
+        self.sessions.lock().await.insert(id, pc);
+    }
+
+    /// Forget and close the peer connection of a session.
+    async fn remove(&self, id: &str) -> bool {
+        let pc = self.sessions.lock().await.remove(id);
+        if let Some(pc) = &pc {
+            let _ = pc.close().await;
+        }
+
+        pc.is_some()
+    }
+}
+
+/// The id of a new session and its resource at `/{kind}/{path}/{id}`, the
+/// segments of the path are encoded again as they were decoded from the
+/// request.
+fn resource(kind: &str, path: &[String]) -> Result<(String, HeaderValue)> {
+    let id = math_rand_alpha(16);
+    let mut location = format!("/{}", kind);
+    for segment in path.iter().chain([&id]) {
+        location.push('/');
+        for byte in segment.bytes() {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                    location.push(byte as char)
+                }
+                _ => location.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+    }
+
+    let location = HeaderValue::from_str(&location)?;
+    Ok((id, location))
+}
+
+/// The response of a created session, the resource at `location` is
+/// deleted to close it.
+fn created(location: HeaderValue, answer: String) -> Response {
+    let headers = [
+        (CONTENT_TYPE, HeaderValue::from_static("application/sdp")),
+        (LOCATION, location),
+    ];
+
+    (StatusCode::CREATED, headers, answer).into_response()
+}
+
+/// The stream of a request path of `app[/stream]`, the virtual host is the
+/// host of the request.
+fn stream_id(state: &WebrtcState, host: &Option<Host>, path: &[String]) -> StreamId {
+    let vhost = state
+        .router
+        .vhost(host.as_ref().map(|Host(host)| host.as_str()));
+    StreamId::from_path(vhost, &path.join("/"))
+}
+
+/// Close a session, the resource of `/{kind}/{app}[/{stream}]/{id}` is
+/// deleted.
+async fn fork_delete(
+    Path(path): Path<Vec<String>>,
+    State(state): State<WebrtcState>,
+) -> impl IntoResponse {
+    match state.remove(path.last().unwrap()).await {
+        true => StatusCode::OK,
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+fn api(cfg: &config::Webrtc, socket: UdpSocket) -> Result<API> {
+    let mut media = MediaEngine::default();
+    media.register_default_codecs()?;
+    let registry = register_default_interceptors(Registry::new(), &mut media)?;
+
+    // Every session shares the udp socket, the server only answers the
+    // connectivity checks of the clients.
+    let mut setting = SettingEngine::default();
+    setting.set_lite(true);
+    setting.set_network_types(vec![NetworkType::Udp4, NetworkType::Udp6]);
+    setting.set_udp_network(UDPNetwork::Muxed(UDPMuxDefault::new(UDPMuxParams::new(
+        socket,
+    ))));
+
+    let mut candidates: Vec<String> = cfg.candidates.iter().map(|ip| ip.to_string()).collect();
+    if candidates.is_empty() && !cfg.udp.ip().is_unspecified() {
+        candidates.push(cfg.udp.ip().to_string());
+    }
+
+    if !candidates.is_empty() {
+        setting.set_nat_1to1_ips(candidates, RTCIceCandidateType::Host);
+    }
+
+    Ok(APIBuilder::new()
+        .with_media_engine(media)
+        .with_interceptor_registry(registry)
+        .with_setting_engine(setting)
+        .build())
+}
+
+pub async fn run(
+    cfg: config::Webrtc,
+    timestamp: config::Timestamp,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+) -> Result<()> {
+    let socket = UdpSocket::bind(cfg.udp).await?;
+    let state = WebrtcState {
+        api: Arc::new(api(&cfg, socket)?),
+        sessions: Default::default(),
+        timestamp,
+        router,
+        access,
+    };
+
+    let app = HttpRouter::new()
+        .route("/whep/:app", post(whep::fork_play))
+        .route(
+            "/whep/:app/:stream",
+            post(whep::fork_play).delete(fork_delete),
+        )
+        .route("/whep/:app/:stream/:id", delete(fork_delete))
+        .route("/whip/:app", post(whip::fork_publish))
+        .route(
+            "/whip/:app/:stream",
+            post(whip::fork_publish).delete(fork_delete),
+        )
+        .route("/whip/:app/:stream/:id", delete(fork_delete))
+        .with_state(state)
+        .into_make_service_with_connect_info::<SocketAddr>();
+    axum::Server::bind(&cfg.listen).serve(app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resource_encodes_the_path() {
+        let path = ["live".to_string(), "a\nb c/d".to_string()];
+        let (id, location) = resource("whep", &path).unwrap();
+        assert_eq!(
+            location.to_str().unwrap(),
+            format!("/whep/live/a%0Ab%20c%2Fd/{}", id)
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use super::{created, resource, stream_id, WebrtcState};
+use crate::{
+    codec::{avc::AvcConfig, split_length_prefixed},
+    flv::*,
+    router::{Payload, RouterEvent, RouterSubscriber},
+    rtp::{h264, RtpWriter, MTU},
+    timestamp::Timestamper,
+};
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use axum::extract::{ConnectInfo, Host, Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use tokio::{
+    sync::watch,
+    time::{self, Instant},
+};
+use webrtc::{
+    api::media_engine::{MIME_TYPE_H264, MIME_TYPE_OPUS},
+    peer_connection::{peer_connection_state::RTCPeerConnectionState, RTCPeerConnection},
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+    track::track_local::{
+        track_local_static_rtp::TrackLocalStaticRTP, TrackLocal, TrackLocalWriter,
+    },
+};
+
+/// How long to wait for the sequence headers of a played stream.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The codecs of a stream, read from the tags before its first frame.
+#[derive(Default)]
+struct Probe {
+    avc: Option<AvcConfig>,
+    aac: bool,
+    opus: bool,
+    /// The first frame, which ended the probe.
+    first: Option<Payload>,
+}
+
+async fn probe(subscriber: &mut RouterSubscriber) -> Result<Probe> {
+    let deadline = Instant::now() + PROBE_TIMEOUT;
+    let mut probe = Probe::default();
+    loop {
+        let payload = match time::timeout_at(deadline, subscriber.recv()).await {
+            Ok(Some(RouterEvent::Frame(payload))) => payload,
+            Ok(Some(RouterEvent::Start(_))) => continue,
+            _ => return Ok(probe),
+        };
+
+        let header = match payload.frame {
+            FlvFrame::Video => match FlvVideo::parse(&payload.bytes) {
+                Some(video) if video.header && video.codec == VIDEO_CODEC_AVC => {
+                    probe.avc = Some(AvcConfig::parse(video.data)?);
+                    true
+                }
+                video => video.map(|video| video.header).unwrap_or(true),
+            },
+            FlvFrame::Audio => {
+                if let Some(audio) = FlvAudio::parse(&payload.bytes) {
+                    probe.aac = true;
+                    audio.header
+                } else if let Some(opus) = FlvOpus::parse(&payload.bytes) {
+                    probe.opus = true;
+                    opus.header
+                } else {
+                    true
+                }
+            }
+            FlvFrame::Script => true,
+        };
+
+        if !header {
+            probe.first = Some(payload);
+            return Ok(probe);
+        }
+    }
+}
+
+fn track(
+    mime_type: &str,
+    clock_rate: u32,
+    channels: u16,
+    fmtp: String,
+) -> Arc<TrackLocalStaticRTP> {
+    let capability = RTCRtpCodecCapability {
+        mime_type: mime_type.to_string(),
+        sdp_fmtp_line: fmtp,
+        clock_rate,
+        channels,
+        ..Default::default()
+    };
+
+    let kind = &mime_type[..5];
+    Arc::new(TrackLocalStaticRTP::new(
+        capability,
+        kind.to_string(),
+        "media-server".to_string(),
+    ))
+}
+
+struct Player {
+    video: Option<(Arc<TrackLocalStaticRTP>, RtpWriter)>,
+    audio: Option<(Arc<TrackLocalStaticRTP>, RtpWriter)>,
+    avc: Option<AvcConfig>,
+    timestamper: Timestamper,
+    /// The peer is connected, the frames before are dropped.
+    connected: bool,
+    /// A keyframe has been sent, the video frames before it are dropped.
+    started: bool,
+}
+
+impl Player {
+    async fn write(
+        (track, writer): &mut (Arc<TrackLocalStaticRTP>, RtpWriter),
+        payloads: Vec<Bytes>,
+        timestamp: u32,
+    ) -> Result<()> {
+        for packet in writer.write(payloads, timestamp) {
+            track.write(&packet).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&mut self, payload: Payload) -> Result<()> {
+        let timestamp = self.timestamper.normalize(payload.frame, payload.timestamp);
+
+        match payload.frame {
+            FlvFrame::Video => {
+                let (video, avc) = match (FlvVideo::parse(&payload.bytes), &self.avc) {
+                    (Some(video), _) if video.header && video.codec == VIDEO_CODEC_AVC => {
+                        self.avc = Some(AvcConfig::parse(video.data)?);
+                        return Ok(());
+                    }
+                    (Some(video), Some(avc)) if video.codec == VIDEO_CODEC_AVC => (video, avc),
+                    _ => return Ok(()),
+                };
+
+                self.started |= self.connected && video.keyframe;
+                let track = match &mut self.video {
+                    Some(track) if self.started => track,
+                    _ => return Ok(()),
+                };
+
+                // The parameter sets are repeated before every keyframe.
+                let mut nalus = split_length_prefixed(&video.data, avc.nal_length_size);
+                if video.keyframe {
+                    let sets = avc.sps.iter().chain(&avc.pps).map(|set| &set[..]);
+                    nalus.splice(0..0, sets);
+                }
+
+                let time = (timestamp as i64 + video.cts as i64).max(0) as u64 * 90;
+                Self::write(track, h264::packetize(&nalus, MTU), time as u32).await
+            }
+            FlvFrame::Audio => {
+                let opus = match FlvOpus::parse(&payload.bytes) {
+                    Some(opus) if !opus.header => opus,
+                    _ => return Ok(()),
+                };
+
+                let waiting = self.video.is_some() && !self.started;
+                let track = match &mut self.audio {
+                    Some(track) if self.connected && !waiting => track,
+                    _ => return Ok(()),
+                };
+
+                let time = timestamp as u64 * 48;
+                Self::write(track, vec![opus.data], time as u32).await
+            }
+            FlvFrame::Script => Ok(()),
+        }
+    }
+}
+
+/// Send the subscribed stream to the peer until either side closes.
+async fn play(
+    mut player: Player,
+    mut subscriber: RouterSubscriber,
+    mut first: Option<Payload>,
+    mut states: watch::Receiver<RTCPeerConnectionState>,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            changed = states.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+
+                match *states.borrow() {
+                    RTCPeerConnectionState::Connected => player.connected = true,
+                    RTCPeerConnectionState::Disconnected
+                    | RTCPeerConnectionState::Failed
+                    | RTCPeerConnectionState::Closed => return Ok(()),
+                    _ => (),
+                }
+            }
+            event = subscriber.recv() => match event {
+                Some(RouterEvent::Frame(payload)) => {
+                    if let Some(first) = first.take() {
+                        player.send(first).await?;
+                    }
+
+                    player.send(payload).await?;
+                }
+                Some(RouterEvent::Start(_)) => (),
+                Some(RouterEvent::End) | None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Add the tracks of the probed codecs and answer the offer.
+async fn connect(
+    state: &WebrtcState,
+    pc: &RTCPeerConnection,
+    probe: &Probe,
+    offer: String,
+) -> Result<(Player, String)> {
+    let mut player = Player {
+        timestamper: Timestamper::new(state.timestamp.clone()),
+        avc: probe.avc.clone(),
+        connected: false,
+        started: false,
+        video: None,
+        audio: None,
+    };
+
+    if let Some(avc) = &probe.avc {
+        let fmtp = format!(
+            "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={:02x}{:02x}{:02x}",
+            avc.profile, avc.compatibility, avc.level
+        );
+
+        player.video = Some((track(MIME_TYPE_H264, 90000, 0, fmtp), RtpWriter::new(0, 0)));
+    }
+
+    if probe.opus {
+        let fmtp = "minptime=10;useinbandfec=1".to_string();
+        player.audio = Some((track(MIME_TYPE_OPUS, 48000, 2, fmtp), RtpWriter::new(0, 0)));
+    }
+
+    for (track, _) in player.video.iter().chain(&player.audio) {
+        let sender = pc
+            .add_track(track.clone() as Arc<dyn TrackLocal + Send + Sync>)
+            .await?;
+
+        // The rtcp packets are read for the interceptors, which handle the
+        // retransmissions.
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1500];
+            while sender.read(&mut buf).await.is_ok() {}
+        });
+    }
+
+    let answer = state.answer(pc, offer).await?;
+    Ok((player, answer))
+}
+
+/// Play a stream with a session negotiated by the sdp offer of the request
+/// body, h.264 video and opus audio are sent, streams with neither of them
+/// are rejected.
+pub async fn fork_play(
+    Path(path): Path<Vec<String>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<WebrtcState>,
+    host: Option<Host>,
+    offer: String,
+) -> Response {
+    let name = stream_id(&state, &host, &path);
+    log::info!("whep play stream addr: {}, name: {}", addr, name);
+    let (id, location) = match resource("whep", &path) {
+        Ok(resource) => resource,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    if !state.access.play(&state.router, "whep", addr, &name) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let mut subscriber = match state.router.subscribe(&name).await {
+        Some(subscriber) => subscriber,
+        None => {
+            log::warn!("whep play stream not found, name: {}", name);
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+
+    let probe = match probe(&mut subscriber).await {
+        Ok(probe) => probe,
+        Err(e) => return (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string()).into_response(),
+    };
+
+    if probe.avc.is_none() && !probe.opus {
+        let reason = match probe.aac {
+            true => "the stream only has aac audio, which webrtc does not support",
+            false => "the stream has neither h.264 video nor opus audio",
+        };
+
+        log::warn!("whep stream is not supported, name: {}, {}", name, reason);
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, reason).into_response();
+    }
+
+    if probe.aac {
+        log::info!("whep aac audio is not sent, name: {}", name);
+    }
+
+    let pc = match state.peer_connection().await {
+        Ok(pc) => pc,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    let (player, answer) = match connect(&state, &pc, &probe, offer).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            log::warn!("whep negotiation failed, addr: {}, err: {}", addr, e);
+            let _ = pc.close().await;
+            return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+        }
+    };
+
+    let (states_tx, states) = watch::channel(pc.connection_state());
+    pc.on_peer_connection_state_change(Box::new(move |connection| {
+        let _ = states_tx.send(connection);
+        Box::pin(async {})
+    }));
+
+    state.insert(id.clone(), pc).await;
+    tokio::spawn(async move {
+        if let Err(e) = play(player, subscriber, probe.first, states).await {
+            log::warn!("whep play failed, addr: {}, err: {}", addr, e);
+        }
+
+        state.remove(&id).await;
+        log::info!("whep play close, name: {}, addr: {}", name, addr);
+    });
+
+    created(location, answer)
+}
@@ -0,0 +1,250 @@
+use super::{created, resource, stream_id, WebrtcState};
+use crate::{
+    flv::*,
+    proto::rtmp::PublishType,
+    router::RouterSender,
+    rtp::{h264::H264Depacketizer, RtpPacket},
+    server::publish,
+};
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use axum::extract::{ConnectInfo, Host, Path, Query, State};
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tokio::sync::{mpsc, watch};
+use webrtc::{
+    api::media_engine::{MIME_TYPE_H264, MIME_TYPE_OPUS},
+    peer_connection::{peer_connection_state::RTCPeerConnectionState, RTCPeerConnection},
+    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
+    rtp_transceiver::rtp_codec::RTPCodecType,
+    track::track_remote::TrackRemote,
+};
+
+/// How often a keyframe is requested from the publisher, so that players
+/// joining the stream can start.
+const KEYFRAME_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Converts the received rtp packets to flv tags, the timestamps of every
+/// track start at zero.
+struct Publisher {
+    sender: RouterSender,
+    depacketizer: H264Depacketizer,
+    video: FlvVideoBuilder,
+    audio: FlvOpusBuilder,
+    video_base: Option<u32>,
+    audio_base: Option<u32>,
+}
+
+impl Publisher {
+    /// Returns `false` if the stream has been removed from the router.
+    async fn receive(&mut self, kind: RTPCodecType, packet: RtpPacket) -> Result<bool> {
+        let tags = match kind {
+            RTPCodecType::Video => {
+                let base = *self.video_base.get_or_insert(packet.timestamp);
+                let mut tags = Vec::new();
+                for (timestamp, nalus) in self.depacketizer.push(&packet) {
+                    let timestamp = timestamp.wrapping_sub(base) / 90;
+                    let nalus = nalus.iter().map(|nalu| &nalu[..]);
+                    tags.extend(self.video.build(nalus, timestamp, 0)?);
+                }
+
+                tags
+            }
+            _ => {
+                let base = *self.audio_base.get_or_insert(packet.timestamp);
+                let timestamp = packet.timestamp.wrapping_sub(base) / 48;
+                self.audio.build(timestamp, &packet.payload)
+            }
+        };
+
+        for tag in tags {
+            if self
+                .sender
+                .send(tag.frame, tag.timestamp, tag.data)
+                .await
+                .is_none()
+            {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Read the rtp packets of a track, and request keyframes of a video track.
+async fn read(
+    track: Arc<TrackRemote>,
+    pc: Arc<RTCPeerConnection>,
+    packets: mpsc::Sender<(RTPCodecType, RtpPacket)>,
+) {
+    let kind = track.kind();
+    let mut interval = tokio::time::interval(KEYFRAME_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick(), if kind == RTPCodecType::Video => {
+                let pli = PictureLossIndication {
+                    sender_ssrc: 0,
+                    media_ssrc: track.ssrc(),
+                };
+
+                if pc.write_rtcp(&[Box::new(pli)]).await.is_err() {
+                    return;
+                }
+            }
+            packet = track.read_rtp() => {
+                let packet = match packet {
+                    Ok((packet, _)) => RtpPacket {
+                        payload_type: packet.header.payload_type,
+                        marker: packet.header.marker,
+                        sequence: packet.header.sequence_number,
+                        timestamp: packet.header.timestamp,
+                        ssrc: packet.header.ssrc,
+                        payload: packet.payload,
+                    },
+                    Err(_) => return,
+                };
+
+                if packets.send((kind, packet)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Publish the received tracks until either side closes.
+async fn forward(
+    mut publisher: Publisher,
+    mut packets: mpsc::Receiver<(RTPCodecType, RtpPacket)>,
+    mut states: watch::Receiver<RTCPeerConnectionState>,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            changed = states.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+
+                match *states.borrow() {
+                    RTCPeerConnectionState::Disconnected
+                    | RTCPeerConnectionState::Failed
+                    | RTCPeerConnectionState::Closed => return Ok(()),
+                    _ => (),
+                }
+            }
+            Some((kind, packet)) = packets.recv() => {
+                if !publisher.receive(kind, packet).await? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Publish a stream with a session negotiated by the sdp offer of the
+/// request body, h.264 video and opus audio are received. The stream key is
+/// the `key` query or the bearer token, it is the stream name if the path
+/// only has the app.
+pub async fn fork_publish(
+    Path(path): Path<Vec<String>>,
+    Query(query): Query<HashMap<String, String>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<WebrtcState>,
+    host: Option<Host>,
+    headers: HeaderMap,
+    offer: String,
+) -> Response {
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let key = query.get("key").map(|k| k.as_str()).or(bearer);
+
+    let mut name = stream_id(&state, &host, &path);
+    if name.stream.is_empty() {
+        name.stream = key.unwrap_or_default().to_string();
+    }
+
+    if name.stream.is_empty() {
+        let reason = "no stream name in the path, the key query or the bearer token";
+        return (StatusCode::BAD_REQUEST, reason).into_response();
+    }
+
+    if !state.access.publish("whip", addr, &name) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let (id, location) = match resource("whip", &path) {
+        Ok(resource) => resource,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let source = format!("whip://{}", addr);
+    let sender = match publish(&state.router, source, &name, PublishType::Live) {
+        Some(sender) => sender,
+        None => return StatusCode::CONFLICT.into_response(),
+    };
+
+    let pc = match state.peer_connection().await {
+        Ok(pc) => pc,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    };
+
+    let (packets_tx, packets) = mpsc::channel(1024);
+    let weak = Arc::downgrade(&pc);
+    pc.on_track(Box::new(move |track, _, _| {
+        let mime_type = track.codec().capability.mime_type.to_lowercase();
+        let supported = [MIME_TYPE_H264, MIME_TYPE_OPUS]
+            .iter()
+            .any(|supported| supported.to_lowercase() == mime_type);
+
+        match weak.upgrade() {
+            Some(pc) if supported => {
+                tokio::spawn(read(track, pc, packets_tx.clone()));
+            }
+            _ => log::warn!("whip track is not supported, codec: {}", mime_type),
+        }
+
+        Box::pin(async {})
+    }));
+
+    let (states_tx, states) = watch::channel(pc.connection_state());
+    pc.on_peer_connection_state_change(Box::new(move |connection| {
+        let _ = states_tx.send(connection);
+        Box::pin(async {})
+    }));
+
+    let answer = match state.answer(&pc, offer).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            log::warn!("whip negotiation failed, addr: {}, err: {}", addr, e);
+            let _ = pc.close().await;
+            return (StatusCode::BAD_REQUEST, e.to_string()).into_response();
+        }
+    };
+
+    let publisher = Publisher {
+        video: FlvVideoBuilder::new(VIDEO_CODEC_AVC),
+        depacketizer: H264Depacketizer::default(),
+        audio: FlvOpusBuilder::default(),
+        video_base: None,
+        audio_base: None,
+        sender,
+    };
+
+    state.insert(id.clone(), pc).await;
+    tokio::spawn(async move {
+        if let Err(e) = forward(publisher, packets, states).await {
+            log::warn!("whip publish failed, addr: {}, err: {}", addr, e);
+        }
+
+        state.remove(&id).await;
+        log::info!("whip publish close, name: {}, addr: {}", name, addr);
+    });
+
+    created(location, answer)
+}
@@ -37,10 +37,9 @@ async fn fork_socket(
 pub async fn run(cfg: config::Ws, router: Arc<Router>) -> Result<()> {
     let cfg = Arc::new(cfg);
     let listener = TcpListener::bind(&cfg.listen).await?;
-    while let Ok((socket, addr)) = listener.accept().await {
+    loop {
+        let (socket, addr) = super::accept(&listener, "websocket").await;
         log::info!("websocket connection pull: {}", addr);
         tokio::spawn(fork_socket(addr, cfg.clone(), router.clone(), socket));
     }
-
-    Ok(())
 }
@@ -1,30 +1,174 @@
 use std::{net::SocketAddr, sync::Arc};
 
-use crate::{config::WebSocketFlv, proto::websocket::*, router::*};
+use super::access::AccessControl;
+use crate::{
+    config::{Connection, WebSocketFlv},
+    flv::FlvDecoder,
+    mp4::Fragmentation,
+    proto::{rtmp::PublishType, websocket::*},
+    router::*,
+};
 
-use anyhow::Result;
-use futures_util::sink::SinkExt;
+use anyhow::{anyhow, Result};
+use futures_util::{sink::SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::tungstenite::Message;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+    WebSocketStream,
+};
 
-async fn fork_socket(
+/// The stream of a connection, the query has the app and the stream name, and
+/// the virtual host is the host of the request.
+fn stream_id(router: &Router, query: &Query) -> StreamId {
+    let vhost = router.vhost(query.host.as_deref());
+    StreamId::new(vhost, &query.name, &query.key)
+}
+
+/// Publish the flv stream sent as binary messages, the publish ends when
+/// nothing is received before the idle timeout.
+async fn publish<S: AsyncRead + AsyncWrite + Unpin>(
     addr: SocketAddr,
-    cfg: Arc<WebSocketFlv>,
-    router: Arc<Router>,
-    socket: TcpStream,
+    router: &Router,
+    connection: &Connection,
+    name: &StreamId,
+    stream: &mut WebSocketStream<S>,
+) -> Result<()> {
+    let source = format!("ws://{}", addr);
+    let mut sender = match super::publish(router, source, name, PublishType::Live) {
+        Some(sender) => sender,
+        None => {
+            let frame = CloseFrame {
+                code: CloseCode::Policy,
+                reason: "stream is already published".into(),
+            };
+
+            return Ok(stream.close(Some(frame)).await?);
+        }
+    };
+
+    let mut decoder = FlvDecoder::default();
+    loop {
+        let idle = super::deadline(connection.idle());
+        let message = match super::timeout(idle, stream.next()).await {
+            Some(Some(message)) => message,
+            Some(None) => break,
+            None => {
+                log::warn!("websocket flv publish timed out, addr: {}", addr);
+                break;
+            }
+        };
+
+        match message? {
+            Message::Binary(buf) => decoder.extend(&buf),
+            Message::Close(_) => break,
+            _ => continue,
+        }
+
+        while let Some(tag) = decoder.decode()? {
+            if sender
+                .send(tag.frame, tag.timestamp, tag.data)
+                .await
+                .is_none()
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Send a message to a viewer, a write that does not complete before the
+/// write timeout fails.
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &Connection,
+    stream: &mut WebSocketStream<S>,
+    message: Message,
+) -> Result<()> {
+    let write = super::deadline(connection.write());
+    super::timeout(write, stream.send(message))
+        .await
+        .ok_or_else(|| anyhow!("write timed out"))??;
+    Ok(())
+}
+
+/// Play the stream as fragmented mp4, the mime type with the codecs is sent
+/// as a text message before the init segment.
+async fn play_fmp4<S: AsyncRead + AsyncWrite + Unpin>(
+    router: &Router,
+    connection: &Connection,
+    name: &StreamId,
+    fragmentation: Fragmentation,
+    stream: &mut WebSocketStream<S>,
+) -> Result<()> {
+    let mut reader = match router.subscribe_fmp4(name, fragmentation).await {
+        Some(reader) => reader,
+        None => return Ok(()),
+    };
+
+    let mut announced = false;
+    while let Some(buf) = reader.read().await {
+        if !announced {
+            announced = true;
+            let mime = reader.mime().unwrap_or_default().to_string();
+            send(connection, stream, Message::Text(mime)).await?;
+        }
+
+        send(connection, stream, Message::Binary(buf.to_vec())).await?;
+    }
+
+    Ok(())
+}
+
+/// Serve an accepted websocket connection, either the dedicated listener or
+/// an upgraded request of the http flv server.
+pub async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    addr: SocketAddr,
+    router: &Router,
+    access: &AccessControl,
+    connection: &Connection,
+    query: Query,
+    mut stream: WebSocketStream<S>,
 ) {
-    if let Ok((mut stream, query)) = accept(socket, Some(cfg.get_config())).await {
-        log::info!(
-            "websocket flv connection name: {}, key: {}",
-            query.name,
-            query.key
-        );
-
-        if let Some(mut reader) = router.get_receiver(&addr, &query.name).await {
-            while let Some(buf) = reader.read().await {
-                if stream.send(Message::Binary(buf)).await.is_err() {
-                    break;
-                }
+    log::info!(
+        "websocket flv connection name: {}, key: {}",
+        query.name,
+        query.key
+    );
+
+    let name = stream_id(router, &query);
+    let allowed = if query.mode == Mode::Publish {
+        access.publish("websocket", addr, &name)
+    } else {
+        access.play(router, "websocket", addr, &name)
+    };
+
+    if !allowed {
+        let frame = CloseFrame {
+            code: CloseCode::Policy,
+            reason: "access denied".into(),
+        };
+
+        let _ = stream.close(Some(frame)).await;
+    } else if query.mode == Mode::Publish {
+        if let Err(e) = publish(addr, router, connection, &name, &mut stream).await {
+            log::warn!("websocket flv publish failed, addr: {}, err: {}", addr, e);
+        }
+    } else if let Some(fragmentation) = query.fragmentation {
+        if let Err(e) = play_fmp4(router, connection, &name, fragmentation, &mut stream).await {
+            log::warn!("websocket mp4 play failed, addr: {}, err: {}", addr, e);
+        }
+    } else if let Some(mut reader) = router.subscribe_flv(&name).await {
+        while let Some(buf) = reader.read().await {
+            if let Err(e) = send(connection, &mut stream, Message::Binary(buf)).await {
+                log::warn!("websocket flv play failed, addr: {}, err: {}", addr, e);
+                break;
             }
         }
     }
@@ -32,13 +176,85 @@ async fn fork_socket(
     log::info!("websocket flv connection close: {}", addr);
 }
 
-pub async fn run(cfg: WebSocketFlv, router: Arc<Router>) -> Result<()> {
+/// Accept a websocket connection, the handshake has to complete before the
+/// deadline.
+async fn fork_socket<S: AsyncRead + AsyncWrite + Unpin>(
+    addr: SocketAddr,
+    cfg: Arc<WebSocketFlv>,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+    handshake: Option<Instant>,
+    socket: S,
+) {
+    // The origin is checked before the handshake is answered, a page that is
+    // not allowed is refused with 403.
+    let check = |query: &Query| {
+        let name = stream_id(&router, query);
+        query.mode == Mode::Publish
+            || access.origin("websocket", addr, &name, query.origin.as_deref())
+    };
+
+    let accept = accept(socket, Some(cfg.get_config()), check);
+    match super::timeout(handshake, accept).await {
+        Some(Ok((stream, query))) => {
+            serve(addr, &router, &access, &cfg.connection, query, stream).await
+        }
+        Some(Err(_)) => log::info!("websocket flv connection close: {}", addr),
+        None => log::warn!("websocket flv handshake timed out, addr: {}", addr),
+    }
+}
+
+/// Accept a wss connection, the websocket handshake follows the tls one and
+/// both have to complete before the deadline.
+async fn fork_tls(
+    addr: SocketAddr,
+    cfg: Arc<WebSocketFlv>,
+    router: Arc<Router>,
+    access: Arc<AccessControl>,
+    acceptor: TlsAcceptor,
+    socket: TcpStream,
+) {
+    let handshake = super::deadline(cfg.connection.handshake());
+    match super::timeout(handshake, acceptor.accept(socket)).await {
+        Some(Ok(socket)) => fork_socket(addr, cfg, router, access, handshake, socket).await,
+        Some(Err(e)) => log::warn!(
+            "websocket flv tls handshake failed, addr: {}, err: {}",
+            addr,
+            e
+        ),
+        None => log::warn!("websocket flv tls handshake timed out, addr: {}", addr),
+    }
+}
+
+pub async fn run(cfg: WebSocketFlv, router: Arc<Router>, access: Arc<AccessControl>) -> Result<()> {
+    let acceptor = match &cfg.tls {
+        Some(tls) => Some(super::tls::acceptor(tls, &[b"http/1.1"])?),
+        None => None,
+    };
+
     let cfg = Arc::new(cfg);
     let listener = TcpListener::bind(&cfg.listen).await?;
-    while let Ok((socket, addr)) = listener.accept().await {
-        log::info!("websocket flv connection pull: {}", addr);
-        tokio::spawn(fork_socket(addr, cfg.clone(), router.clone(), socket));
-    }
+    loop {
+        let (socket, addr) = super::accept(&listener, "websocket").await;
+        let connection = match access.connect("websocket", addr) {
+            Some(connection) => connection,
+            None => continue,
+        };
 
-    Ok(())
+        log::info!("websocket flv connection: {}", addr);
+        super::configure(&socket, &cfg.connection);
+        let (cfg, router, access) = (cfg.clone(), router.clone(), access.clone());
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            match acceptor {
+                Some(acceptor) => fork_tls(addr, cfg, router, access, acceptor, socket).await,
+                None => {
+                    let handshake = super::deadline(cfg.connection.handshake());
+                    fork_socket(addr, cfg, router, access, handshake, socket).await
+                }
+            }
+
+            drop(connection);
+        });
+    }
 }
@@ -0,0 +1,111 @@
+use crate::{
+    config::FileSource,
+    flv::FlvDecoder,
+    router::{Metadata, Router, RouterSender, StreamId},
+};
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Result};
+use tokio::{fs::File, io::AsyncReadExt, time::Instant};
+
+/// The gap between two files when the last frame duration is unknown.
+const FRAME_DURATION: u64 = 40;
+
+/// Plays flv files one after another as a single stream, the timestamps
+/// continue across files, so the subscribers see no discontinuity.
+struct Player {
+    sender: RouterSender,
+    start: Instant,
+    /// The stream time in milliseconds at which the next file starts.
+    base: u64,
+}
+
+impl Player {
+    /// Send the tags of the file at the speed of their timestamps, returns
+    /// `false` if the stream has been removed from the router.
+    async fn play(&mut self, path: &Path) -> Result<bool> {
+        let mut file = File::open(path).await?;
+        let mut decoder = FlvDecoder::default();
+        let mut buf = vec![0u8; 65536];
+        let mut first = None;
+        let mut last = self.base;
+        let mut delta = FRAME_DURATION;
+
+        loop {
+            let tag = match decoder.decode()? {
+                Some(tag) => tag,
+                None => match file.read(&mut buf).await? {
+                    0 => break,
+                    size => {
+                        decoder.extend(&buf[..size]);
+                        continue;
+                    }
+                },
+            };
+
+            // The stream time of a tag is its offset from the first tag of the
+            // file, a backward jump is played immediately.
+            let first = *first.get_or_insert(tag.timestamp);
+            let position = self.base + tag.timestamp.saturating_sub(first) as u64;
+            if position > last {
+                delta = position - last;
+                last = position;
+            }
+
+            tokio::time::sleep_until(self.start + Duration::from_millis(position)).await;
+            if self
+                .sender
+                .send(tag.frame, position as u32, tag.data)
+                .await
+                .is_none()
+            {
+                return Ok(false);
+            }
+        }
+
+        self.base = last + delta;
+        Ok(true)
+    }
+}
+
+/// Publish the files of the source, and play them in a loop if enabled.
+pub async fn fork_file(source: FileSource, router: Arc<Router>) -> Result<()> {
+    let path = source
+        .files
+        .first()
+        .ok_or_else(|| anyhow!("file source has no files"))?;
+
+    let mut metadata = Metadata::new(format!("file://{}", path.display()));
+    metadata
+        .params
+        .insert("type".to_string(), "live".to_string());
+
+    let sender = router
+        .publish(&StreamId::from_path("", &source.name), metadata)
+        .ok_or_else(|| anyhow!("stream is already published"))?;
+
+    let mut player = Player {
+        start: Instant::now(),
+        base: 0,
+        sender,
+    };
+
+    loop {
+        let mut played = false;
+        for path in &source.files {
+            log::info!("file source play, name: {}, file: {:?}", source.name, path);
+
+            match player.play(path).await {
+                Ok(true) => played = true,
+                Ok(false) => return Ok(()),
+                Err(e) => log::error!("file source play failed, file: {:?}, err: {}", path, e),
+            }
+        }
+
+        // Stop if none of the files can be played, instead of spinning.
+        if !source.looped || !played {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,59 @@
+mod file;
+mod udp;
+
+use crate::{
+    config::Config,
+    relay,
+    router::{Router, StreamId},
+};
+
+use std::sync::Arc;
+
+/// Start publishing the static sources of the configuration.
+pub fn run(cfg: Arc<Config>, router: Arc<Router>) {
+    for source in cfg.sources.file.iter().cloned() {
+        let router = router.clone();
+        tokio::spawn(async move {
+            log::info!("file source start, name: {}", source.name);
+
+            let name = source.name.clone();
+            if let Err(e) = file::fork_file(source, router).await {
+                log::error!("file source failed, name: {}, err: {}", name, e);
+            } else {
+                log::info!("file source stop, name: {}", name);
+            }
+        });
+    }
+
+    for source in cfg.sources.pull.iter().cloned() {
+        let router = router.clone();
+        tokio::spawn(async move {
+            log::info!(
+                "pull source start, name: {}, urls: {:?}",
+                source.name,
+                source.urls
+            );
+
+            let name = StreamId::from_path("", &source.name);
+            if let Err(e) = relay::fork_pull(&source.urls, &name, router).await {
+                log::error!("pull source failed, name: {}, err: {}", source.name, e);
+            }
+        });
+    }
+
+    for source in cfg.sources.udp.iter().cloned() {
+        let router = router.clone();
+        tokio::spawn(async move {
+            log::info!(
+                "udp source start, name: {}, listen: {}",
+                source.name,
+                source.listen
+            );
+
+            let name = source.name.clone();
+            if let Err(e) = udp::fork_udp(source, router).await {
+                log::error!("udp source failed, name: {}, err: {}", name, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,288 @@
+use crate::{
+    config::UdpSource,
+    router::{Metadata, Router, RouterSender, StreamId},
+    ts::{TsDecoder, SYNC_BYTE},
+};
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::{
+    net::UdpSocket,
+    time::{sleep, timeout},
+};
+
+/// The stream is unpublished when no packets have been received for this
+/// long, and published again when they resume.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait after a failed receive or publish before trying again,
+/// the packets received in the meantime are dropped.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The mpeg-ts packets of a datagram, which may be wrapped in rtp.
+fn payload(buf: &[u8]) -> Option<&[u8]> {
+    if buf.first() == Some(&SYNC_BYTE) {
+        return Some(buf);
+    }
+
+    // An rtp header of version 2, followed by the csrc list and the
+    // extension, the padding is at the end.
+    if buf.len() < 12 || buf[0] >> 6 != 2 {
+        return None;
+    }
+
+    let mut offset = 12 + (buf[0] & 0x0f) as usize * 4;
+    if buf[0] & 0x10 != 0 {
+        let size = buf.get(offset + 2..offset + 4)?;
+        offset += 4 + u16::from_be_bytes([size[0], size[1]]) as usize * 4;
+    }
+
+    let mut end = buf.len();
+    if buf[0] & 0x20 != 0 {
+        end = end.checked_sub(*buf.last()? as usize)?;
+    }
+
+    buf.get(offset..end)
+}
+
+async fn bind(source: &UdpSource) -> Result<UdpSocket> {
+    let ip = source.listen.ip();
+    if !ip.is_multicast() {
+        return Ok(UdpSocket::bind(source.listen).await?);
+    }
+
+    // Bind to the group port on every address, and join the group.
+    let port = source.listen.port();
+    let socket = match ip {
+        IpAddr::V4(group) => {
+            let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).await?;
+            socket.join_multicast_v4(group, source.interface)?;
+            socket
+        }
+        IpAddr::V6(group) => {
+            let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, port)).await?;
+            socket.join_multicast_v6(&group, 0)?;
+            socket
+        }
+    };
+
+    Ok(socket)
+}
+
+fn publish(source: &UdpSource, router: &Router, addr: SocketAddr) -> Result<RouterSender> {
+    let mut metadata = Metadata::new(format!("udp://{}", addr));
+    metadata
+        .params
+        .insert("type".to_string(), "live".to_string());
+
+    router
+        .publish(&StreamId::from_path("", &source.name), metadata)
+        .ok_or_else(|| anyhow!("stream is already published"))
+}
+
+/// Receive the stream for as long as the process runs, it is published
+/// while packets are arriving.
+///
+/// Only binding the socket fails the source, a failed receive or publish is
+/// retried, and the packets that can not be decoded are skipped.
+pub async fn fork_udp(source: UdpSource, router: Arc<Router>) -> Result<()> {
+    let socket = bind(&source).await?;
+    let mut buf = vec![0u8; 65536];
+
+    loop {
+        let (size, addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                log::warn!(
+                    "udp source receive failed, name: {}, err: {}",
+                    source.name,
+                    e
+                );
+                sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+
+        log::info!(
+            "udp source receiving, name: {}, addr: {}",
+            source.name,
+            addr
+        );
+
+        let mut sender = match publish(&source, &router, addr) {
+            Ok(sender) => sender,
+            Err(e) => {
+                log::warn!(
+                    "udp source publish failed, name: {}, err: {}",
+                    source.name,
+                    e
+                );
+                sleep(RETRY_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let mut decoder = TsDecoder::default();
+        let mut size = size;
+
+        loop {
+            if let Some(payload) = payload(&buf[..size]) {
+                decoder.extend(payload);
+            }
+
+            let tags = decoder.decode().unwrap_or_else(|e| {
+                log::warn!(
+                    "udp source decode failed, name: {}, err: {}",
+                    source.name,
+                    e
+                );
+                Vec::new()
+            });
+
+            for tag in tags {
+                if sender
+                    .send(tag.frame, tag.timestamp, tag.data)
+                    .await
+                    .is_none()
+                {
+                    return Ok(());
+                }
+            }
+
+            size = match timeout(RECEIVE_TIMEOUT, socket.recv(&mut buf)).await {
+                Ok(Ok(size)) => size,
+                Ok(Err(e)) => {
+                    log::warn!(
+                        "udp source receive failed, name: {}, err: {}",
+                        source.name,
+                        e
+                    );
+                    sleep(RETRY_INTERVAL).await;
+                    0
+                }
+                Err(_) => break,
+            };
+        }
+
+        log::info!("udp source timeout, name: {}", source.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec::avc::AvcConfig,
+        config,
+        flv::FlvFrame,
+        router::{RouterEvent, RouterSubscriber},
+        ts::TsEncoder,
+    };
+
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    const SPS: [u8; 24] = [
+        0x67, 0x42, 0xc0, 0x1e, 0xda, 0x02, 0x80, 0xbf, 0xe5, 0x84, 0x00, 0x00, 0x03, 0x00, 0x04,
+        0x00, 0x00, 0x03, 0x00, 0xca, 0x3c, 0x58, 0xba, 0x80,
+    ];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+
+    /// The datagrams of a stream of keyframes, seven ts packets each.
+    fn datagrams(frames: u32) -> Vec<Bytes> {
+        let mut encoder = TsEncoder::default();
+        let mut header = BytesMut::from(&[0x17, 0, 0, 0, 0][..]);
+        header.put_slice(&AvcConfig::encode(&SPS, &PPS));
+
+        let mut ts = encoder
+            .encode(FlvFrame::Video, 0, &header.freeze())
+            .unwrap()
+            .to_vec();
+        for i in 0..frames {
+            let mut frame = BytesMut::from(&[0x17, 1, 0, 0, 0][..]);
+            frame.put_u32(4);
+            frame.put_slice(&[0x65, 0x88, 0x84, i as u8]);
+            ts.extend(
+                encoder
+                    .encode(FlvFrame::Video, i * 40, &frame.freeze())
+                    .unwrap(),
+            );
+        }
+
+        ts.chunks(7 * 188).map(Bytes::copy_from_slice).collect()
+    }
+
+    async fn send(socket: &UdpSocket, datagrams: &[Bytes], to: SocketAddr) {
+        for datagram in datagrams {
+            socket.send_to(datagram, to).await.unwrap();
+        }
+    }
+
+    async fn subscribe(router: &Router, name: &StreamId) -> RouterSubscriber {
+        loop {
+            if let Some(subscriber) = router.subscribe(name).await {
+                return subscriber;
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Receive frames until a keyframe of the stream arrives.
+    async fn keyframe(subscriber: &mut RouterSubscriber) {
+        while let Some(event) = subscriber.recv().await {
+            if let RouterEvent::Frame(payload) = event {
+                if payload.frame.is_keyframe(&payload.bytes) && payload.bytes[1] == 1 {
+                    return;
+                }
+            }
+        }
+
+        panic!("the stream ended without a keyframe");
+    }
+
+    #[tokio::test]
+    async fn fork_udp_keeps_receiving_after_errors() {
+        let listen = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let source = UdpSource {
+            name: "live/udp".to_string(),
+            listen,
+            interface: Ipv4Addr::UNSPECIFIED,
+        };
+
+        let router = Arc::new(Router::new(config::Timestamp::default(), []));
+        let name = StreamId::from_path("", &source.name);
+        let task = tokio::spawn(fork_udp(source, router.clone()));
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+
+        // The name is taken, the source retries the publish.
+        let publisher = router.publish(&name, Metadata::default()).unwrap();
+        send(&socket, &datagrams(2), listen).await;
+        sleep(Duration::from_millis(100)).await;
+        drop(publisher);
+
+        // Packets that are not mpeg-ts are skipped.
+        socket.send_to(&[0u8; 100], listen).await.unwrap();
+        sleep(RETRY_INTERVAL).await;
+
+        let datagrams = datagrams(50);
+        let receive = async {
+            send(&socket, &datagrams[..1], listen).await;
+            let mut subscriber = subscribe(&router, &name).await;
+            send(&socket, &datagrams[1..], listen).await;
+            keyframe(&mut subscriber).await;
+        };
+
+        let wait = timeout(Duration::from_secs(5), receive).await;
+        assert!(wait.is_ok(), "no keyframe is received");
+        assert!(!task.is_finished());
+    }
+}
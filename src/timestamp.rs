@@ -0,0 +1,196 @@
+use crate::{
+    config::{Discontinuity, Timestamp},
+    flv::FlvFrame,
+};
+
+#[derive(Clone, Copy)]
+struct Track {
+    /// The last timestamp received from the publisher.
+    raw: u32,
+    /// The last timestamp with rollovers unwrapped.
+    position: i64,
+    /// Subtracted from the position to get the output timestamp.
+    offset: i64,
+    /// The last positive interval between two frames.
+    delta: i64,
+}
+
+/// Normalizes the timestamps of a stream for a single subscriber.
+///
+/// The output starts at zero and never goes backwards. Publisher timestamps
+/// are allowed to wrap around at 2^32 milliseconds, a small negative delta
+/// caused by audio and video being interleaved slightly out of order is
+/// clamped to the previous output, and a jump larger than `max_jump` is
+/// handled as a discontinuity. Each of audio, video and script data is
+/// tracked separately, so a discontinuity in one of them does not disturb the
+/// others.
+///
+/// The output itself is a 32 bit flv timestamp, it wraps around after about
+/// 49 days.
+pub struct Timestamper {
+    cfg: Timestamp,
+    tracks: [Option<Track>; 3],
+    last: Option<Track>,
+    output: i64,
+}
+
+impl Timestamper {
+    pub fn new(cfg: Timestamp) -> Self {
+        Self {
+            tracks: [None; 3],
+            last: None,
+            output: 0,
+            cfg,
+        }
+    }
+
+    pub fn normalize(&mut self, frame: FlvFrame, timestamp: u32) -> u32 {
+        let index = match frame {
+            FlvFrame::Audio => 0,
+            FlvFrame::Video => 1,
+            FlvFrame::Script => 2,
+        };
+
+        // A track seen for the first time is compared against the last frame
+        // of any track, so it joins the timeline of the others.
+        let reference = match self.tracks[index].or(self.last) {
+            Some(reference) => reference,
+            None => Track {
+                raw: timestamp,
+                position: timestamp as i64,
+                offset: timestamp as i64,
+                delta: 0,
+            },
+        };
+
+        let delta = timestamp.wrapping_sub(reference.raw) as i32 as i64;
+        let mut track = Track {
+            position: reference.position + delta,
+            raw: timestamp,
+            ..reference
+        };
+
+        let max_jump = self.cfg.max_jump as i64;
+        let preserve = delta > 0 && self.cfg.discontinuity == Discontinuity::Preserve;
+        if delta.abs() > max_jump && !preserve {
+            log::debug!(
+                "timestamp discontinuity, frame: {:?}, from: {}, to: {}",
+                frame,
+                reference.raw,
+                timestamp
+            );
+
+            // Continue from the last output as if a normal frame interval
+            // has passed.
+            track.offset = track.position - (self.output + reference.delta);
+        } else if delta > 0 && delta <= max_jump {
+            track.delta = delta;
+        }
+
+        self.output = self.output.max(track.position - track.offset);
+        self.tracks[index] = Some(track);
+        self.last = Some(track);
+        self.output as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timestamper(discontinuity: Discontinuity) -> Timestamper {
+        Timestamper::new(Timestamp {
+            max_jump: 3000,
+            discontinuity,
+        })
+    }
+
+    fn normalize(timestamper: &mut Timestamper, frames: &[(FlvFrame, u32)]) -> Vec<u32> {
+        frames
+            .iter()
+            .map(|&(frame, timestamp)| timestamper.normalize(frame, timestamp))
+            .collect()
+    }
+
+    fn video(timestamps: &[u32]) -> Vec<(FlvFrame, u32)> {
+        timestamps.iter().map(|&t| (FlvFrame::Video, t)).collect()
+    }
+
+    #[test]
+    fn normalize_unwraps_the_32_bit_rollover() {
+        let timestamps: Vec<u32> = (0..10)
+            .map(|i| (u32::MAX - 100).wrapping_add(i * 40))
+            .collect();
+
+        let mut timestamper = timestamper(Discontinuity::Rebase);
+        let output = normalize(&mut timestamper, &video(&timestamps));
+        assert_eq!(output, (0..10).map(|i| i * 40).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn normalize_clamps_a_small_backward_jump() {
+        let mut timestamper = timestamper(Discontinuity::Rebase);
+        let output = normalize(&mut timestamper, &video(&[0, 40, 80, 10, 50, 120]));
+        assert_eq!(output, [0, 40, 80, 80, 80, 120]);
+    }
+
+    #[test]
+    fn normalize_rebases_a_large_backward_jump() {
+        let mut timestamper = timestamper(Discontinuity::Rebase);
+        let frames = video(&[100000, 100040, 100080, 0, 40]);
+        assert_eq!(normalize(&mut timestamper, &frames), [0, 40, 80, 120, 160]);
+
+        // A backward jump is rebased even when gaps are preserved.
+        let mut timestamper = self::timestamper(Discontinuity::Preserve);
+        assert_eq!(normalize(&mut timestamper, &frames), [0, 40, 80, 120, 160]);
+    }
+
+    #[test]
+    fn normalize_handles_jumps_over_the_threshold() {
+        // A jump of `max_jump` is still continuous.
+        let mut timestamper = timestamper(Discontinuity::Rebase);
+        let output = normalize(&mut timestamper, &video(&[0, 40, 3040, 3080]));
+        assert_eq!(output, [0, 40, 3040, 3080]);
+
+        let frames = video(&[0, 40, 3041, 3081]);
+        let mut timestamper = self::timestamper(Discontinuity::Rebase);
+        assert_eq!(normalize(&mut timestamper, &frames), [0, 40, 80, 120]);
+
+        let mut timestamper = self::timestamper(Discontinuity::Preserve);
+        assert_eq!(normalize(&mut timestamper, &frames), [0, 40, 3041, 3081]);
+    }
+
+    #[test]
+    fn normalize_interleaves_audio_and_video() {
+        let mut timestamper = timestamper(Discontinuity::Rebase);
+        let frames = [
+            (FlvFrame::Script, 5000),
+            (FlvFrame::Video, 5000),
+            (FlvFrame::Audio, 5000),
+            (FlvFrame::Video, 5040),
+            (FlvFrame::Audio, 5023),
+            (FlvFrame::Audio, 5046),
+            (FlvFrame::Video, 5080),
+        ];
+
+        let output = normalize(&mut timestamper, &frames);
+        assert_eq!(output, [0, 0, 0, 40, 40, 46, 80]);
+    }
+
+    #[test]
+    fn normalize_keeps_a_discontinuity_to_its_track() {
+        let mut timestamper = timestamper(Discontinuity::Rebase);
+        let frames = [
+            (FlvFrame::Video, 0),
+            (FlvFrame::Video, 40),
+            (FlvFrame::Audio, 20),
+            (FlvFrame::Audio, 900000),
+            (FlvFrame::Video, 80),
+            (FlvFrame::Audio, 900023),
+            (FlvFrame::Video, 120),
+        ];
+
+        let output = normalize(&mut timestamper, &frames);
+        assert_eq!(output, [0, 40, 40, 80, 80, 103, 120]);
+    }
+}
@@ -0,0 +1,296 @@
+use super::{PACKET_SIZE, PID_PAT, SYNC_BYTE};
+
+use ahash::AHashMap;
+use bytes::{Bytes, BytesMut};
+
+/// The largest pes packet assembled, a pes packet of a video frame without a
+/// length could otherwise grow without bound if its end is lost.
+const MAX_PES_SIZE: usize = 16 * 1024 * 1024;
+
+/// A complete pes packet of an elementary stream.
+#[derive(Clone, Debug)]
+pub struct Pes {
+    pub stream_type: u8,
+    /// Presentation time in 90 kHz units.
+    pub pts: u64,
+    /// Decoding time in 90 kHz units, the same as the pts if not present.
+    pub dts: u64,
+    pub data: Bytes,
+}
+
+/// An incremental mpeg-ts demuxer, the input can be split at any position.
+///
+/// Only the first program of the pat is followed. The demuxer resyncs on
+/// the next sync byte when the input is corrupted, and drops the pes
+/// packets that can not be parsed, packets lost by udp transports are
+/// expected. A gap in the continuity counter of a pid drops the pes packet
+/// being assembled, it is missing a part.
+#[derive(Default)]
+pub struct TsDemuxer {
+    bytes: BytesMut,
+    pmt_pid: Option<u16>,
+    /// The stream type of every elementary stream pid of the pmt.
+    streams: AHashMap<u16, u8>,
+    /// The pes packets being assembled.
+    pending: AHashMap<u16, BytesMut>,
+    /// The last continuity counter of every elementary stream pid.
+    counters: AHashMap<u16, u8>,
+    output: Vec<Pes>,
+}
+
+impl TsDemuxer {
+    pub fn extend(&mut self, chunk: &[u8]) {
+        self.bytes.extend_from_slice(chunk);
+    }
+
+    /// Demux the buffered packets, returns the pes packets completed by
+    /// them.
+    pub fn demux(&mut self) -> Vec<Pes> {
+        loop {
+            match self.bytes.iter().position(|byte| *byte == SYNC_BYTE) {
+                Some(0) => (),
+                Some(offset) => {
+                    let _ = self.bytes.split_to(offset);
+                }
+                None => self.bytes.clear(),
+            }
+
+            if self.bytes.len() < PACKET_SIZE {
+                break;
+            }
+
+            let packet = self.bytes.split_to(PACKET_SIZE);
+            self.packet(&packet);
+        }
+
+        std::mem::take(&mut self.output)
+    }
+
+    fn packet(&mut self, packet: &[u8]) {
+        let start = packet[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        let control = (packet[3] >> 4) & 0x03;
+
+        // The payload follows the adaptation field, if any.
+        let mut offset = 4;
+        if control & 0x02 != 0 {
+            offset += 1 + packet[4] as usize;
+        }
+
+        if control & 0x01 == 0 || offset >= PACKET_SIZE {
+            return;
+        }
+
+        let payload = &packet[offset..];
+        if pid == PID_PAT {
+            self.pat(start, payload);
+        } else if Some(pid) == self.pmt_pid {
+            self.pmt(start, payload);
+        } else if let Some(stream_type) = self.streams.get(&pid).copied() {
+            // The counter is reset by the discontinuity indicator, and a
+            // packet may be sent twice in a row.
+            let counter = packet[3] & 0x0f;
+            let discontinuity = control & 0x02 != 0 && packet[4] > 0 && packet[5] & 0x80 != 0;
+            match self.counters.insert(pid, counter) {
+                _ if discontinuity => (),
+                Some(last) if last == counter => return,
+                Some(last) if (last + 1) & 0x0f != counter => {
+                    self.pending.remove(&pid);
+                }
+                _ => (),
+            }
+
+            if start {
+                if let Some(pending) = self.pending.remove(&pid) {
+                    self.pes(stream_type, pending.freeze());
+                }
+
+                self.pending.insert(pid, BytesMut::from(payload));
+            } else if let Some(pending) = self.pending.get_mut(&pid) {
+                if pending.len() + payload.len() > MAX_PES_SIZE {
+                    self.pending.remove(&pid);
+                    return;
+                }
+
+                pending.extend_from_slice(payload);
+            } else {
+                return;
+            }
+
+            // A pes packet with a length is complete without waiting for the
+            // start of the next one.
+            let pending = &self.pending[&pid];
+            let size = match pending.get(4..6) {
+                Some(size) => u16::from_be_bytes([size[0], size[1]]) as usize,
+                None => 0,
+            };
+
+            if size > 0 && pending.len() >= size + 6 {
+                let mut pending = self.pending.remove(&pid).unwrap();
+                pending.truncate(size + 6);
+                self.pes(stream_type, pending.freeze());
+            }
+        }
+    }
+
+    /// The psi section of a payload, the sections are expected to fit in a
+    /// single packet.
+    fn section(start: bool, payload: &[u8]) -> Option<&[u8]> {
+        if !start {
+            return None;
+        }
+
+        let payload = payload.get(1 + *payload.first()? as usize..)?;
+        let size = u16::from_be_bytes([*payload.get(1)? & 0x0f, *payload.get(2)?]) as usize;
+
+        // The header and the crc are not part of the entries.
+        payload
+            .get(..3 + size)
+            .filter(|section| section.len() >= 12)
+    }
+
+    fn pat(&mut self, start: bool, payload: &[u8]) {
+        if let Some(section) = Self::section(start, payload) {
+            for entry in section[8..section.len() - 4].chunks_exact(4) {
+                let program = u16::from_be_bytes([entry[0], entry[1]]);
+                if program != 0 {
+                    self.pmt_pid = Some(u16::from_be_bytes([entry[2] & 0x1f, entry[3]]));
+                    break;
+                }
+            }
+        }
+    }
+
+    fn pmt(&mut self, start: bool, payload: &[u8]) {
+        let section = match Self::section(start, payload) {
+            Some(section) => section,
+            None => return,
+        };
+
+        let info = u16::from_be_bytes([section[10] & 0x0f, section[11]]) as usize;
+        let end = section.len() - 4;
+        let mut offset = 12 + info;
+
+        self.streams.clear();
+        while offset + 5 <= end {
+            let stream_type = section[offset];
+            let pid = u16::from_be_bytes([section[offset + 1] & 0x1f, section[offset + 2]]);
+            let info = u16::from_be_bytes([section[offset + 3] & 0x0f, section[offset + 4]]);
+
+            self.streams.insert(pid, stream_type);
+            offset += 5 + info as usize;
+        }
+    }
+
+    fn pes(&mut self, stream_type: u8, buf: Bytes) {
+        if buf.len() < 9 || buf[..3] != [0, 0, 1] {
+            return;
+        }
+
+        let flags = buf[7];
+        let header = 9 + buf[8] as usize;
+        if buf.len() < header {
+            return;
+        }
+
+        let pts = match flags & 0x80 {
+            0 => return,
+            _ => match buf.get(9..14) {
+                Some(bytes) => timestamp(bytes),
+                None => return,
+            },
+        };
+
+        let dts = match flags & 0x40 {
+            0 => pts,
+            _ => match buf.get(14..19) {
+                Some(bytes) => timestamp(bytes),
+                None => return,
+            },
+        };
+
+        self.output.push(Pes {
+            data: buf.slice(header..),
+            stream_type,
+            pts,
+            dts,
+        });
+    }
+}
+
+/// Read a 33 bits pts or dts.
+fn timestamp(buf: &[u8]) -> u64 {
+    ((buf[0] as u64 >> 1) & 0x07) << 30
+        | (buf[1] as u64) << 22
+        | (buf[2] as u64 >> 1) << 15
+        | (buf[3] as u64) << 7
+        | buf[4] as u64 >> 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::{TsMuxer, PID_VIDEO, STREAM_TYPE_H264};
+
+    /// The packets of the tables followed by a pes packet of every frame.
+    fn packets(frames: &[Vec<u8>]) -> Vec<Vec<Vec<u8>>> {
+        let mut muxer = TsMuxer::default();
+        muxer.write_tables(&[(PID_VIDEO, STREAM_TYPE_H264)], PID_VIDEO);
+        let mut packets = vec![split(&muxer.take())];
+        for (i, frame) in frames.iter().enumerate() {
+            let pts = i as u64 * 3600;
+            muxer.write_pes(PID_VIDEO, 0xe0, pts, Some(pts), frame, None, false);
+            packets.push(split(&muxer.take()));
+        }
+
+        packets
+    }
+
+    fn split(buf: &[u8]) -> Vec<Vec<u8>> {
+        buf.chunks(PACKET_SIZE)
+            .map(|packet| packet.to_vec())
+            .collect()
+    }
+
+    fn demux(packets: &[Vec<Vec<u8>>]) -> Vec<Bytes> {
+        let mut demuxer = TsDemuxer::default();
+        for packet in packets.iter().flatten() {
+            demuxer.extend(packet);
+        }
+
+        demuxer.demux().into_iter().map(|pes| pes.data).collect()
+    }
+
+    fn frames() -> Vec<Vec<u8>> {
+        (0..3u8).map(|i| vec![i; 1000]).collect()
+    }
+
+    #[test]
+    fn demux_returns_the_pes_packets() {
+        let frames = frames();
+        assert_eq!(demux(&packets(&frames)), frames);
+    }
+
+    #[test]
+    fn demux_drops_a_pes_packet_with_a_lost_packet() {
+        let frames = frames();
+        let mut packets = packets(&frames);
+        packets[2].remove(2);
+        assert_eq!(demux(&packets), [&frames[0], &frames[2]]);
+    }
+
+    #[test]
+    fn demux_skips_a_repeated_packet() {
+        let frames = frames();
+        let mut packets = packets(&frames);
+        let packet = packets[2][2].clone();
+        packets[2].insert(2, packet);
+        assert_eq!(demux(&packets), frames);
+    }
+
+    #[test]
+    fn demux_drops_a_pes_packet_over_the_maximum_size() {
+        let frames = vec![vec![1; MAX_PES_SIZE], vec![2; 1000], vec![3; 1000]];
+        assert_eq!(demux(&packets(&frames)), [&frames[1], &frames[2]]);
+    }
+}
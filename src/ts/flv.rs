@@ -0,0 +1,246 @@
+use super::{
+    Pes, TsDemuxer, TsMuxer, CLOCK_RATE, PID_AUDIO, PID_VIDEO, STREAM_TYPE_AAC, STREAM_TYPE_H264,
+    STREAM_TYPE_HEVC,
+};
+use crate::{
+    codec::{aac::AacConfig, avc::AvcConfig, hevc::HevcConfig, *},
+    flv::*,
+};
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// Converts an mpeg-ts stream carrying h.264, h.265 and aac into flv tags.
+#[derive(Default)]
+pub struct TsDecoder {
+    demuxer: TsDemuxer,
+    video: Option<FlvVideoBuilder>,
+    audio: FlvAudioBuilder,
+    tags: Vec<FlvTag>,
+}
+
+impl TsDecoder {
+    pub fn extend(&mut self, chunk: &[u8]) {
+        self.demuxer.extend(chunk);
+    }
+
+    /// Decode the buffered packets, returns the tags completed by them.
+    pub fn decode(&mut self) -> Result<Vec<FlvTag>> {
+        for pes in self.demuxer.demux() {
+            match pes.stream_type {
+                STREAM_TYPE_H264 => self.video(VIDEO_CODEC_AVC, &pes)?,
+                STREAM_TYPE_HEVC => self.video(VIDEO_CODEC_HEVC, &pes)?,
+                STREAM_TYPE_AAC => self.audio(&pes),
+                _ => (),
+            }
+        }
+
+        Ok(std::mem::take(&mut self.tags))
+    }
+
+    fn video(&mut self, codec: u8, pes: &Pes) -> Result<()> {
+        let builder = match &mut self.video {
+            Some(builder) if builder.codec() == codec => builder,
+            video => video.insert(FlvVideoBuilder::new(codec)),
+        };
+
+        let timestamp = (pes.dts / CLOCK_RATE) as u32;
+        let cts = (pes.pts.wrapping_sub(pes.dts) as i64 / CLOCK_RATE as i64) as i32;
+        let tags = builder.build(split_annexb(&pes.data), timestamp, cts)?;
+        self.tags.extend(tags);
+        Ok(())
+    }
+
+    fn audio(&mut self, pes: &Pes) {
+        let mut buf = &pes.data[..];
+        let mut index = 0;
+
+        // A pes packet may hold several adts frames of 1024 samples each, the
+        // rest of a packet damaged by a lost ts packet is dropped.
+        while !buf.is_empty() {
+            let (config, header, size) = match AacConfig::parse_adts(buf) {
+                Ok(frame) if frame.2 <= buf.len() => frame,
+                _ => break,
+            };
+
+            let timestamp =
+                (pes.pts / CLOCK_RATE + index * 1024 * 1000 / config.sample_rate as u64) as u32;
+            let tags = self.audio.build(&config, timestamp, &buf[header..size]);
+            self.tags.extend(tags);
+
+            buf = &buf[size..];
+            index += 1;
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Video {
+    stream_type: u8,
+    nal_length_size: usize,
+    /// The annex b aud that starts every access unit.
+    aud: &'static [u8],
+    /// The parameter sets in annex b, repeated before every keyframe.
+    parameters: Bytes,
+}
+
+/// Converts flv tags carrying avc, hevc and aac into an mpeg-ts stream.
+///
+/// The pat and the pmt are repeated before every keyframe, and at least
+/// once a second for streams without video. The output of a stream with
+/// video starts at the first keyframe, the frames before it are dropped.
+#[derive(Default)]
+pub struct TsEncoder {
+    muxer: TsMuxer,
+    video: Option<Video>,
+    audio: Option<AacConfig>,
+    /// A keyframe has been written.
+    started: bool,
+    /// The streams changed since the tables were last written.
+    changed: bool,
+    tables_at: Option<u32>,
+}
+
+impl TsEncoder {
+    /// Encode a tag, returns the packets written for it, which may be empty.
+    pub fn encode(&mut self, frame: FlvFrame, timestamp: u32, data: &Bytes) -> Result<Bytes> {
+        match frame {
+            FlvFrame::Video => match FlvVideo::parse(data) {
+                Some(video) if video.header => self.video_header(&video)?,
+                Some(video) => self.video_frame(&video, timestamp),
+                None => (),
+            },
+            FlvFrame::Audio => match FlvAudio::parse(data) {
+                Some(audio) if audio.header => {
+                    self.audio = Some(AacConfig::parse(&audio.data)?);
+                    self.changed = true;
+                }
+                Some(audio) => self.audio_frame(&audio, timestamp)?,
+                None => (),
+            },
+            FlvFrame::Script => (),
+        }
+
+        Ok(self.muxer.take())
+    }
+
+    fn video_header(&mut self, video: &FlvVideo) -> Result<()> {
+        let (stream_type, aud, nal_length_size, sets) = if video.codec == VIDEO_CODEC_AVC {
+            let config = AvcConfig::parse(video.data.clone())?;
+            let sets = [config.sps, config.pps].concat();
+            let aud: &[u8] = &[0, 0, 0, 1, 0x09, 0xf0];
+            (STREAM_TYPE_H264, aud, config.nal_length_size, sets)
+        } else {
+            let config = HevcConfig::parse(video.data.clone())?;
+            let sets = [config.vps, config.sps, config.pps].concat();
+            let aud: &[u8] = &[0, 0, 0, 1, 0x46, 0x01, 0x50];
+            (STREAM_TYPE_HEVC, aud, config.nal_length_size, sets)
+        };
+
+        let mut parameters = BytesMut::new();
+        for set in sets {
+            parameters.put_slice(&START_CODE);
+            parameters.put_slice(&set);
+        }
+
+        self.video = Some(Video {
+            parameters: parameters.freeze(),
+            nal_length_size,
+            stream_type,
+            aud,
+        });
+
+        self.changed = true;
+        Ok(())
+    }
+
+    fn write_tables(&mut self, timestamp: u32, keyframe: bool) {
+        let due = match self.tables_at {
+            Some(at) => keyframe || timestamp.wrapping_sub(at) >= 1000,
+            None => true,
+        };
+
+        if !self.changed && !due {
+            return;
+        }
+
+        let mut streams = Vec::with_capacity(2);
+        if let Some(video) = &self.video {
+            streams.push((PID_VIDEO, video.stream_type));
+        }
+
+        if self.audio.is_some() {
+            streams.push((PID_AUDIO, STREAM_TYPE_AAC));
+        }
+
+        let pcr_pid = if self.video.is_some() {
+            PID_VIDEO
+        } else {
+            PID_AUDIO
+        };
+
+        self.muxer.write_tables(&streams, pcr_pid);
+        self.tables_at = Some(timestamp);
+        self.changed = false;
+    }
+
+    fn video_frame(&mut self, video: &FlvVideo, timestamp: u32) {
+        let config = match &self.video {
+            Some(config) => config.clone(),
+            None => return,
+        };
+
+        self.started |= video.keyframe;
+        if !self.started {
+            return;
+        }
+
+        let mut data = BytesMut::with_capacity(video.data.len() + config.parameters.len() + 16);
+        data.put_slice(config.aud);
+        if video.keyframe {
+            data.put_slice(&config.parameters);
+        }
+
+        for nalu in split_length_prefixed(&video.data, config.nal_length_size) {
+            data.put_slice(&START_CODE);
+            data.put_slice(nalu);
+        }
+
+        let dts = timestamp as u64 * CLOCK_RATE;
+        let pts = (timestamp as i64 + video.cts as i64).max(0) as u64 * CLOCK_RATE;
+
+        self.write_tables(timestamp, video.keyframe);
+        self.muxer.write_pes(
+            PID_VIDEO,
+            0xe0,
+            pts,
+            Some(dts),
+            &data,
+            Some(dts),
+            video.keyframe,
+        );
+    }
+
+    fn audio_frame(&mut self, audio: &FlvAudio, timestamp: u32) -> Result<()> {
+        let config = match &self.audio {
+            Some(config) if self.started || self.video.is_none() => config,
+            _ => return Ok(()),
+        };
+
+        let data = [&config.adts_header(audio.data.len())?[..], &audio.data].concat();
+        let pts = timestamp as u64 * CLOCK_RATE;
+        let pcr = if self.video.is_none() {
+            Some(pts)
+        } else {
+            None
+        };
+
+        self.write_tables(timestamp, false);
+        self.muxer
+            .write_pes(PID_AUDIO, 0xc0, pts, None, &data, pcr, pcr.is_some());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+mod demux;
+mod flv;
+mod mux;
+
+pub use self::{
+    demux::{Pes, TsDemuxer},
+    flv::{TsDecoder, TsEncoder},
+    mux::TsMuxer,
+};
+
+pub const PACKET_SIZE: usize = 188;
+pub const SYNC_BYTE: u8 = 0x47;
+
+pub const PID_PAT: u16 = 0;
+pub const PID_PMT: u16 = 0x1000;
+pub const PID_VIDEO: u16 = 0x100;
+pub const PID_AUDIO: u16 = 0x101;
+
+pub const STREAM_TYPE_AAC: u8 = 0x0f;
+pub const STREAM_TYPE_H264: u8 = 0x1b;
+pub const STREAM_TYPE_HEVC: u8 = 0x24;
+
+/// The 90 kHz clock of pts and dts.
+pub const CLOCK_RATE: u64 = 90;
+
+/// The crc of the psi sections, crc32 mpeg-2 without the final xor.
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in buf {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 {
+                (crc << 1) ^ 0x04c11db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
@@ -0,0 +1,170 @@
+use super::{crc32, PACKET_SIZE, PID_PAT, PID_PMT, SYNC_BYTE};
+
+use ahash::AHashMap;
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// Writes psi tables and pes packets as mpeg-ts packets, with a single
+/// program.
+#[derive(Default)]
+pub struct TsMuxer {
+    bytes: BytesMut,
+    /// The continuity counter of every pid.
+    counters: AHashMap<u16, u8>,
+}
+
+impl TsMuxer {
+    /// Take the packets written since the last call.
+    pub fn take(&mut self) -> Bytes {
+        self.bytes.split().freeze()
+    }
+
+    fn counter(&mut self, pid: u16) -> u8 {
+        let counter = self.counters.entry(pid).or_insert(0x0f);
+        *counter = (*counter + 1) & 0x0f;
+        *counter
+    }
+
+    fn section(&mut self, pid: u16, section: &[u8]) {
+        let counter = self.counter(pid);
+        let start = self.bytes.len();
+
+        self.bytes.put_u8(SYNC_BYTE);
+        self.bytes.put_u16(0x4000 | pid);
+        self.bytes.put_u8(0x10 | counter);
+        self.bytes.put_u8(0);
+        self.bytes.put_slice(section);
+        self.bytes.put_u32(crc32(section));
+        self.bytes.resize(start + PACKET_SIZE, 0xff);
+    }
+
+    /// Write the pat and the pmt, `streams` holds the pid and the stream type
+    /// of every elementary stream.
+    pub fn write_tables(&mut self, streams: &[(u16, u8)], pcr_pid: u16) {
+        let mut pat = BytesMut::with_capacity(12);
+        pat.put_u8(0x00);
+        pat.put_u16(0xb000 | 13);
+        pat.put_u16(1);
+        pat.put_u8(0xc1);
+        pat.put_u16(0);
+        pat.put_u16(1);
+        pat.put_u16(0xe000 | PID_PMT);
+        self.section(PID_PAT, &pat);
+
+        let mut pmt = BytesMut::with_capacity(12 + streams.len() * 5);
+        pmt.put_u8(0x02);
+        pmt.put_u16(0xb000 | (13 + streams.len() as u16 * 5));
+        pmt.put_u16(1);
+        pmt.put_u8(0xc1);
+        pmt.put_u16(0);
+        pmt.put_u16(0xe000 | pcr_pid);
+        pmt.put_u16(0xf000);
+        for (pid, stream_type) in streams {
+            pmt.put_u8(*stream_type);
+            pmt.put_u16(0xe000 | pid);
+            pmt.put_u16(0xf000);
+        }
+
+        self.section(PID_PMT, &pmt);
+    }
+
+    /// Write a pes packet, the pcr is written in the adaptation field of the
+    /// first packet if given, and `random_access` marks a keyframe.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_pes(
+        &mut self,
+        pid: u16,
+        stream_id: u8,
+        pts: u64,
+        dts: Option<u64>,
+        data: &[u8],
+        pcr: Option<u64>,
+        random_access: bool,
+    ) {
+        let mut header = BytesMut::with_capacity(19);
+        let header_size = if dts.is_some() { 10 } else { 5 };
+        let size = 3 + header_size + data.len();
+
+        header.put_slice(&[0, 0, 1, stream_id]);
+        header.put_u16(if size > 0xffff { 0 } else { size as u16 });
+        header.put_u8(0x80);
+        header.put_u8(if dts.is_some() { 0xc0 } else { 0x80 });
+        header.put_u8(header_size as u8);
+        match dts {
+            Some(dts) => {
+                put_timestamp(&mut header, 0x03, pts);
+                put_timestamp(&mut header, 0x01, dts);
+            }
+            None => put_timestamp(&mut header, 0x02, pts),
+        }
+
+        let payload = [&header[..], data].concat();
+        let mut offset = 0;
+        let mut first = true;
+
+        while offset < payload.len() {
+            let remaining = payload.len() - offset;
+            let mut adaptation = Vec::new();
+            if first && (pcr.is_some() || random_access) {
+                adaptation.push(if random_access { 0x40 } else { 0 });
+                if let Some(pcr) = pcr {
+                    adaptation[0] |= 0x10;
+                    adaptation.extend_from_slice(&[
+                        (pcr >> 25) as u8,
+                        (pcr >> 17) as u8,
+                        (pcr >> 9) as u8,
+                        (pcr >> 1) as u8,
+                        ((pcr & 1) << 7) as u8 | 0x7e,
+                        0,
+                    ]);
+                }
+            }
+
+            // The last packet is filled up with stuffing bytes in the
+            // adaptation field.
+            let mut has_adaptation = !adaptation.is_empty();
+            let mut space = PACKET_SIZE
+                - 4
+                - if has_adaptation {
+                    1 + adaptation.len()
+                } else {
+                    0
+                };
+            if remaining < space {
+                if !has_adaptation {
+                    has_adaptation = true;
+                    space -= 1;
+                }
+
+                if remaining < space && adaptation.is_empty() {
+                    adaptation.push(0);
+                    space -= 1;
+                }
+
+                adaptation.resize(adaptation.len() + space - remaining, 0xff);
+                space = remaining;
+            }
+
+            let counter = self.counter(pid);
+            self.bytes.put_u8(SYNC_BYTE);
+            self.bytes.put_u16(if first { 0x4000 } else { 0 } | pid);
+            if has_adaptation {
+                self.bytes.put_u8(0x30 | counter);
+                self.bytes.put_u8(adaptation.len() as u8);
+                self.bytes.put_slice(&adaptation);
+            } else {
+                self.bytes.put_u8(0x10 | counter);
+            }
+
+            self.bytes.put_slice(&payload[offset..offset + space]);
+            offset += space;
+            first = false;
+        }
+    }
+}
+
+/// Write a 33 bits pts or dts with its 4 bits prefix.
+fn put_timestamp(buf: &mut BytesMut, prefix: u8, timestamp: u64) {
+    buf.put_u8((prefix << 4) | (((timestamp >> 30) & 0x07) << 1) as u8 | 1);
+    buf.put_u16(((((timestamp >> 15) & 0x7fff) << 1) | 1) as u16);
+    buf.put_u16((((timestamp & 0x7fff) << 1) | 1) as u16);
+}